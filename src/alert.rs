@@ -0,0 +1,394 @@
+use std::collections::{HashMap, VecDeque};
+
+use chrono::{DateTime, Duration, Utc};
+use coingecko_tokio::Market;
+use serde::{Deserialize, Serialize};
+
+use crate::gecko::find_market;
+
+pub const STATE_PATH: &str = "alerts.json";
+const MAX_ALERTS_PER_USER: usize = 25;
+/// Price history is kept at this resolution, which bounds how precisely change windows line up
+const HISTORY_RESOLUTION_MINS: i64 = 5;
+const MAX_WINDOW_HOURS: i64 = 24;
+
+/// When an alert fires, either on the price crossing a level or on its change over a window
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub enum AlertCondition {
+    Above(f64),
+    Below(f64),
+    /// A percent change at or above the first value over the window in hours
+    ChangeAbove(f32, i64),
+    /// A percent change at or below the first value over the window in hours
+    ChangeBelow(f32, i64),
+}
+
+impl AlertCondition {
+    /// Parses a condition as written in a chat command, e.g. `above 70000` or `below -5% 24h`
+    pub fn parse(words: &[&str]) -> Result<AlertCondition, anyhow::Error> {
+        let (direction, value) = match words {
+            [direction, value] | [direction, value, _] => (*direction, *value),
+            _ => {
+                return Err(anyhow::anyhow!(
+                    "Expected `above <price>` or `below <percent>% <hours>h`"
+                ))
+            }
+        };
+
+        if let Some(percent) = value.strip_suffix('%') {
+            let percent: f32 = percent.parse()?;
+            if !percent.is_finite() {
+                return Err(anyhow::anyhow!("Expected a number for the percent"));
+            }
+            let window = match words.get(2) {
+                Some(window) => parse_window(window)?,
+                None => MAX_WINDOW_HOURS,
+            };
+            match direction {
+                "above" => Ok(AlertCondition::ChangeAbove(percent, window)),
+                "below" => Ok(AlertCondition::ChangeBelow(percent, window)),
+                _ => Err(anyhow::anyhow!("Expected above or below")),
+            }
+        } else {
+            let price: f64 = value.parse()?;
+            if !price.is_finite() {
+                return Err(anyhow::anyhow!("Expected a number for the price"));
+            }
+            match direction {
+                "above" => Ok(AlertCondition::Above(price)),
+                "below" => Ok(AlertCondition::Below(price)),
+                _ => Err(anyhow::anyhow!("Expected above or below")),
+            }
+        }
+    }
+}
+
+fn parse_window(window: &str) -> Result<i64, anyhow::Error> {
+    let hours = match (window.strip_suffix('h'), window.strip_suffix('d')) {
+        (Some(hours), _) => hours.parse::<i64>()?,
+        (_, Some(days)) => days.parse::<i64>()? * 24,
+        _ => return Err(anyhow::anyhow!("Expected a window like 1h or 24h")),
+    };
+    if !(1..=MAX_WINDOW_HOURS).contains(&hours) {
+        return Err(anyhow::anyhow!(
+            "Windows can be between 1h and {}h",
+            MAX_WINDOW_HOURS
+        ));
+    }
+    Ok(hours)
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct PriceAlert {
+    pub id: u64,
+    /// The discord user the alert is sent to
+    pub user_id: u64,
+    /// The coingecko id of the coin
+    pub coin: String,
+    pub condition: AlertCondition,
+}
+
+impl PriceAlert {
+    pub fn description(&self) -> String {
+        match self.condition {
+            AlertCondition::Above(price) => format!("{} above ${}", self.coin, price),
+            AlertCondition::Below(price) => format!("{} below ${}", self.coin, price),
+            AlertCondition::ChangeAbove(percent, hours) => {
+                format!(
+                    "{} changes by {}% or more in {}h",
+                    self.coin, percent, hours
+                )
+            }
+            AlertCondition::ChangeBelow(percent, hours) => {
+                format!(
+                    "{} changes by {}% or less in {}h",
+                    self.coin, percent, hours
+                )
+            }
+        }
+    }
+}
+
+/// Prices of every coin over the longest alert window, sampled every few minutes
+#[derive(Default)]
+pub struct PriceHistory {
+    samples: VecDeque<(DateTime<Utc>, HashMap<String, f64>)>,
+}
+
+impl PriceHistory {
+    pub fn record(&mut self, now: DateTime<Utc>, markets: &[Market]) {
+        let due = self.samples.back().map_or(true, |(at, _)| {
+            now - *at >= Duration::minutes(HISTORY_RESOLUTION_MINS)
+        });
+        if due {
+            let prices = markets
+                .iter()
+                .map(|m| (m.id.clone(), m.current_price))
+                .collect();
+            self.samples.push_back((now, prices));
+        }
+        // Keep one sample older than the longest window so it can always be measured against
+        let max_age =
+            Duration::hours(MAX_WINDOW_HOURS) + Duration::minutes(HISTORY_RESOLUTION_MINS);
+        while self
+            .samples
+            .front()
+            .map_or(false, |(at, _)| now - *at > max_age)
+        {
+            self.samples.pop_front();
+        }
+    }
+
+    /// The price of a coin at the latest sample taken at or before the time, which is none
+    /// until the bot has been running for long enough
+    fn price_at(&self, coin: &str, at: DateTime<Utc>) -> Option<f64> {
+        self.samples
+            .iter()
+            .rev()
+            .find(|(sampled, _)| *sampled <= at)
+            .and_then(|(_, prices)| prices.get(coin).copied())
+    }
+}
+
+/// Every alert waiting to fire, which survive restarts
+#[derive(Deserialize, Serialize, Debug, Default)]
+pub struct AlertState {
+    next_id: u64,
+    alerts: Vec<PriceAlert>,
+}
+
+impl AlertState {
+    /// Adds an alert for a coin in the current state, returning its id
+    pub fn add(
+        &mut self,
+        user_id: u64,
+        coin: &str,
+        condition: AlertCondition,
+        state: &[Market],
+    ) -> Result<u64, anyhow::Error> {
+        let market = find_market(state, coin)
+            .ok_or_else(|| anyhow::anyhow!("Couldn't find a coin called {}", coin))?;
+        if self.alerts.iter().filter(|a| a.user_id == user_id).count() >= MAX_ALERTS_PER_USER {
+            return Err(anyhow::anyhow!(
+                "You can't have more than {} alerts",
+                MAX_ALERTS_PER_USER
+            ));
+        }
+        let id = self.next_id;
+        self.next_id += 1;
+        self.alerts.push(PriceAlert {
+            id,
+            user_id,
+            coin: market.id.clone(),
+            condition,
+        });
+        Ok(id)
+    }
+
+    pub fn for_user(&self, user_id: u64) -> Vec<PriceAlert> {
+        self.alerts
+            .iter()
+            .filter(|a| a.user_id == user_id)
+            .cloned()
+            .collect()
+    }
+
+    /// Deletes one of the user's alerts, returning whether they had one with the id
+    pub fn delete(&mut self, user_id: u64, id: u64) -> bool {
+        let before = self.alerts.len();
+        self.alerts.retain(|a| a.user_id != user_id || a.id != id);
+        self.alerts.len() != before
+    }
+
+    /// Removes and returns the alerts the latest state triggers, since each only fires once
+    pub fn take_triggered(
+        &mut self,
+        state: &[Market],
+        history: &PriceHistory,
+        now: DateTime<Utc>,
+    ) -> Vec<(PriceAlert, Market)> {
+        let mut triggered = vec![];
+        self.alerts.retain(|alert| {
+            let market = match state.iter().find(|m| m.id == alert.coin) {
+                Some(market) => market,
+                None => return true,
+            };
+            let change = |hours: i64| {
+                history
+                    .price_at(&alert.coin, now - Duration::hours(hours))
+                    .map(|initial| (((market.current_price / initial) * 100_f64) - 100_f64) as f32)
+            };
+            let fired = match alert.condition {
+                AlertCondition::Above(price) => market.current_price >= price,
+                AlertCondition::Below(price) => market.current_price <= price,
+                AlertCondition::ChangeAbove(percent, hours) => {
+                    change(hours).map_or(false, |change| change >= percent)
+                }
+                AlertCondition::ChangeBelow(percent, hours) => {
+                    change(hours).map_or(false, |change| change <= percent)
+                }
+            };
+            if fired {
+                triggered.push((alert.clone(), market.clone()));
+            }
+            !fired
+        });
+        triggered
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::harness;
+
+    fn markets(coins: &[(&str, f64, i64)]) -> Vec<Market> {
+        serde_json::from_str(&harness::markets(coins)).unwrap()
+    }
+
+    #[test]
+    fn parses_conditions() {
+        assert_eq!(
+            AlertCondition::parse(&["above", "70000"]).unwrap(),
+            AlertCondition::Above(70000.0)
+        );
+        assert_eq!(
+            AlertCondition::parse(&["below", "0.5"]).unwrap(),
+            AlertCondition::Below(0.5)
+        );
+        assert_eq!(
+            AlertCondition::parse(&["below", "-5%", "4h"]).unwrap(),
+            AlertCondition::ChangeBelow(-5.0, 4)
+        );
+        assert_eq!(
+            AlertCondition::parse(&["above", "10%"]).unwrap(),
+            AlertCondition::ChangeAbove(10.0, MAX_WINDOW_HOURS)
+        );
+    }
+
+    #[test]
+    fn rejects_bad_conditions() {
+        assert!(AlertCondition::parse(&["above"]).is_err());
+        assert!(AlertCondition::parse(&["sideways", "100"]).is_err());
+        assert!(AlertCondition::parse(&["above", "lots"]).is_err());
+        assert!(AlertCondition::parse(&["below", "-5%", "soon"]).is_err());
+        assert!(AlertCondition::parse(&["above", "1", "2", "3"]).is_err());
+        assert!(AlertCondition::parse(&["above", "NaN"]).is_err());
+        assert!(AlertCondition::parse(&["below", "inf"]).is_err());
+        assert!(AlertCondition::parse(&["above", "nan%", "1h"]).is_err());
+    }
+
+    #[test]
+    fn parses_windows() {
+        assert_eq!(parse_window("1h").unwrap(), 1);
+        assert_eq!(parse_window("24h").unwrap(), 24);
+        assert_eq!(parse_window("1d").unwrap(), 24);
+        assert!(parse_window("0h").is_err());
+        assert!(parse_window("25h").is_err());
+        assert!(parse_window("2d").is_err());
+        assert!(parse_window("90m").is_err());
+    }
+
+    #[test]
+    fn price_at_uses_the_latest_sample_at_or_before() {
+        let start = Utc::now();
+        let mut history = PriceHistory::default();
+        history.record(start, &markets(&[("bitcoin", 100.0, 1)]));
+        // Too soon after the last sample to be kept
+        history.record(
+            start + Duration::minutes(1),
+            &markets(&[("bitcoin", 999.0, 1)]),
+        );
+        history.record(
+            start + Duration::minutes(10),
+            &markets(&[("bitcoin", 110.0, 1)]),
+        );
+
+        assert_eq!(
+            history.price_at("bitcoin", start - Duration::minutes(1)),
+            None
+        );
+        assert_eq!(history.price_at("bitcoin", start), Some(100.0));
+        assert_eq!(
+            history.price_at("bitcoin", start + Duration::minutes(9)),
+            Some(100.0)
+        );
+        assert_eq!(
+            history.price_at("bitcoin", start + Duration::minutes(10)),
+            Some(110.0)
+        );
+        assert_eq!(
+            history.price_at("ethereum", start + Duration::minutes(10)),
+            None
+        );
+    }
+
+    #[test]
+    fn history_drops_samples_past_the_longest_window() {
+        let start = Utc::now();
+        let mut history = PriceHistory::default();
+        history.record(start, &markets(&[("bitcoin", 100.0, 1)]));
+        history.record(
+            start + Duration::hours(1),
+            &markets(&[("bitcoin", 110.0, 1)]),
+        );
+        let later = start + Duration::hours(MAX_WINDOW_HOURS + 2);
+        history.record(later, &markets(&[("bitcoin", 120.0, 1)]));
+
+        assert_eq!(
+            history.price_at("bitcoin", start + Duration::minutes(30)),
+            None
+        );
+        assert_eq!(history.price_at("bitcoin", later), Some(120.0));
+    }
+
+    #[test]
+    fn takes_triggered_alerts_once() {
+        let start = Utc::now();
+        let mut history = PriceHistory::default();
+        history.record(
+            start,
+            &markets(&[("bitcoin", 100.0, 1), ("ethereum", 50.0, 2)]),
+        );
+        let mut alerts = AlertState::default();
+        let state = markets(&[("bitcoin", 100.0, 1), ("ethereum", 50.0, 2)]);
+        let above = alerts
+            .add(1, "bitcoin", AlertCondition::Above(105.0), &state)
+            .unwrap();
+        alerts
+            .add(1, "bitcoin", AlertCondition::Below(80.0), &state)
+            .unwrap();
+        let change = alerts
+            .add(2, "ethereum", AlertCondition::ChangeBelow(-10.0, 1), &state)
+            .unwrap();
+
+        let now = start + Duration::hours(1);
+        let state = markets(&[("bitcoin", 106.0, 1), ("ethereum", 40.0, 2)]);
+        history.record(now, &state);
+        let triggered = alerts.take_triggered(&state, &history, now);
+
+        let ids: Vec<_> = triggered.iter().map(|(a, _)| a.id).collect();
+        assert_eq!(ids, vec![above, change]);
+        assert_eq!(triggered[0].1.current_price, 106.0);
+        assert!(alerts.take_triggered(&state, &history, now).is_empty());
+        assert_eq!(alerts.for_user(1).len(), 1);
+    }
+
+    #[test]
+    fn change_alerts_wait_for_enough_history() {
+        let start = Utc::now();
+        let mut history = PriceHistory::default();
+        history.record(start, &markets(&[("bitcoin", 100.0, 1)]));
+        let mut alerts = AlertState::default();
+        let state = markets(&[("bitcoin", 150.0, 1)]);
+        alerts
+            .add(1, "bitcoin", AlertCondition::ChangeAbove(10.0, 4), &state)
+            .unwrap();
+
+        let now = start + Duration::hours(1);
+        history.record(now, &state);
+
+        assert!(alerts.take_triggered(&state, &history, now).is_empty());
+        assert_eq!(alerts.for_user(1).len(), 1);
+    }
+}
@@ -0,0 +1,563 @@
+use std::convert::Infallible;
+
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use tokio::sync::mpsc::Sender;
+#[cfg(any(feature = "twitter", feature = "coingecko"))]
+use tokio::sync::oneshot;
+use warp::{
+    filters::BoxedFilter,
+    http::StatusCode,
+    reply::{Json, WithStatus},
+    Filter, Rejection, Reply,
+};
+
+#[cfg(any(feature = "twitter", feature = "coingecko"))]
+use crate::audit::{Actor, Source};
+#[cfg(feature = "twitter")]
+use crate::command::TwitterCommand;
+#[cfg(feature = "coingecko")]
+use crate::{alert::AlertCondition, command::CoingeckoCommand, gecko::Rule};
+use crate::{command::Command, secret::Secret};
+
+type Response = WithStatus<Json>;
+
+#[derive(Debug)]
+struct Unauthorized;
+
+impl warp::reject::Reject for Unauthorized {}
+
+#[cfg(feature = "twitter")]
+#[derive(Deserialize)]
+struct NewSubscription {
+    handle: String,
+}
+
+#[cfg(feature = "coingecko")]
+#[derive(Deserialize)]
+struct NewAlert {
+    user_id: u64,
+    coin: String,
+    condition: AlertCondition,
+}
+
+#[cfg(feature = "coingecko")]
+#[derive(Deserialize)]
+struct UserQuery {
+    user_id: u64,
+}
+
+#[cfg(feature = "coingecko")]
+#[derive(Serialize)]
+struct Created {
+    id: u64,
+}
+
+/// The JSON control api, mirroring the discord commands onto the command bus. Every route needs
+/// `Authorization: Bearer <http.api_token>`, so nothing is reachable until a token is set.
+pub fn routes(
+    token: Secret,
+    tx: Sender<Command>,
+) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    // Each manager's routes are added on when this build has it
+    let routes = warp::any()
+        .and_then(|| async { Err::<Response, _>(warp::reject::not_found()) })
+        .boxed();
+    #[cfg(feature = "twitter")]
+    let routes = routes.or(subscription_routes(tx.clone())).unify().boxed();
+    #[cfg(feature = "coingecko")]
+    let routes = routes.or(coingecko_routes(tx.clone())).unify().boxed();
+    // Only taken by the groups above, so unused when built without any of them
+    let _ = tx;
+
+    authorized(token).and(routes)
+}
+
+#[cfg(feature = "twitter")]
+fn subscription_routes(tx: Sender<Command>) -> BoxedFilter<(Response,)> {
+    let tx = warp::any().map(move || tx.clone());
+
+    let list_subscriptions = warp::path!("subscriptions")
+        .and(warp::get())
+        .and(tx.clone())
+        .and_then(list_subscriptions);
+    let add_subscription = warp::path!("subscriptions")
+        .and(warp::post())
+        .and(warp::body::json())
+        .and(tx.clone())
+        .and_then(add_subscription);
+    let remove_subscription = warp::path!("subscriptions" / String)
+        .and(warp::delete())
+        .and(tx)
+        .and_then(remove_subscription);
+
+    list_subscriptions
+        .or(add_subscription)
+        .unify()
+        .or(remove_subscription)
+        .unify()
+        .boxed()
+}
+
+#[cfg(feature = "coingecko")]
+fn coingecko_routes(tx: Sender<Command>) -> BoxedFilter<(Response,)> {
+    let tx = warp::any().map(move || tx.clone());
+
+    let list_rules = warp::path!("rules")
+        .and(warp::get())
+        .and(tx.clone())
+        .and_then(list_rules);
+    let add_rule = warp::path!("rules")
+        .and(warp::post())
+        .and(warp::body::json())
+        .and(tx.clone())
+        .and_then(add_rule);
+    // Rules are numbered from 1 in the order they're listed, the same as in the cli
+    let remove_rule = warp::path!("rules" / usize)
+        .and(warp::delete())
+        .and(tx.clone())
+        .and_then(remove_rule);
+
+    let latest_markets = warp::path!("markets" / "latest")
+        .and(warp::get())
+        .and(tx.clone())
+        .and_then(latest_markets);
+
+    let list_alerts = warp::path!("alerts")
+        .and(warp::get())
+        .and(warp::query())
+        .and(tx.clone())
+        .and_then(list_alerts);
+    let add_alert = warp::path!("alerts")
+        .and(warp::post())
+        .and(warp::body::json())
+        .and(tx.clone())
+        .and_then(add_alert);
+    let delete_alert = warp::path!("alerts" / u64)
+        .and(warp::delete())
+        .and(warp::query())
+        .and(tx)
+        .and_then(delete_alert);
+
+    list_rules
+        .or(add_rule)
+        .unify()
+        .or(remove_rule)
+        .unify()
+        .or(latest_markets)
+        .unify()
+        .or(list_alerts)
+        .unify()
+        .or(add_alert)
+        .unify()
+        .or(delete_alert)
+        .unify()
+        .boxed()
+}
+
+fn authorized(token: Secret) -> impl Filter<Extract = (), Error = Rejection> + Clone {
+    warp::header::optional::<String>("authorization")
+        .and_then(move |header: Option<String>| {
+            let token = token.clone();
+            async move {
+                let given = header
+                    .as_deref()
+                    .and_then(|header| header.strip_prefix("Bearer "));
+                match given {
+                    Some(given) if !token.is_empty() && given == token.expose() => Ok(()),
+                    _ => Err(warp::reject::custom(Unauthorized)),
+                }
+            }
+        })
+        .untuple_one()
+}
+
+/// Turns rejections into JSON errors, so clients get the same shape of body from every route
+pub async fn handle_rejection(rejection: Rejection) -> Result<Response, Infallible> {
+    let (status, message) = if rejection.is_not_found() {
+        (StatusCode::NOT_FOUND, String::from("Not found"))
+    } else if rejection.find::<Unauthorized>().is_some() {
+        (
+            StatusCode::UNAUTHORIZED,
+            String::from("Missing or invalid bearer token"),
+        )
+    } else if let Some(e) = rejection.find::<warp::filters::body::BodyDeserializeError>() {
+        (StatusCode::BAD_REQUEST, e.to_string())
+    } else if let Some(e) = rejection.find::<warp::reject::InvalidQuery>() {
+        (StatusCode::BAD_REQUEST, e.to_string())
+    } else if rejection.find::<warp::reject::MethodNotAllowed>().is_some() {
+        (
+            StatusCode::METHOD_NOT_ALLOWED,
+            String::from("Method not allowed"),
+        )
+    } else {
+        tracing::error!("Failed to handle api request {:?}", rejection);
+        (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            String::from("Internal error"),
+        )
+    };
+    Ok(error(status, message))
+}
+
+#[cfg(any(feature = "twitter", feature = "coingecko"))]
+fn actor() -> Actor {
+    Actor {
+        source: Source::Api,
+        id: String::from("api"),
+        name: String::from("api"),
+    }
+}
+
+fn json<T: Serialize>(status: StatusCode, body: &T) -> Response {
+    warp::reply::with_status(warp::reply::json(body), status)
+}
+
+fn error(status: StatusCode, message: impl ToString) -> Response {
+    json(status, &json!({ "error": message.to_string() }))
+}
+
+#[cfg(any(feature = "twitter", feature = "coingecko"))]
+/// Sends a command and waits for its reply. The router drops commands for managers that
+/// aren't running, which drops the reply sender too.
+async fn ask<T>(
+    tx: &Sender<Command>,
+    cmd: Command,
+    rx: oneshot::Receiver<T>,
+) -> Result<T, Response> {
+    if let Err(e) = tx.send(cmd).await {
+        tracing::error!("Failed to send api command {}", e);
+        return Err(error(
+            StatusCode::SERVICE_UNAVAILABLE,
+            "The bot is shutting down",
+        ));
+    }
+    rx.await.map_err(|_| {
+        error(
+            StatusCode::SERVICE_UNAVAILABLE,
+            "The manager handling this isn't running",
+        )
+    })
+}
+
+#[cfg(any(feature = "twitter", feature = "coingecko"))]
+/// Answers with the body on success, or the error as a bad request
+fn done<T: Serialize>(res: Result<T, anyhow::Error>) -> Response {
+    match res {
+        Ok(body) => json(StatusCode::OK, &body),
+        Err(e) => error(StatusCode::BAD_REQUEST, e),
+    }
+}
+
+#[cfg(feature = "twitter")]
+async fn list_subscriptions(tx: Sender<Command>) -> Result<Response, Infallible> {
+    let (reply_tx, reply_rx) = oneshot::channel();
+    let cmd = Command::Twitter(TwitterCommand::ListTwitterSubscriptions(reply_tx));
+    Ok(match ask(&tx, cmd, reply_rx).await {
+        Ok(subscriptions) => json(StatusCode::OK, &subscriptions),
+        Err(response) => response,
+    })
+}
+
+#[cfg(feature = "twitter")]
+async fn add_subscription(
+    body: NewSubscription,
+    tx: Sender<Command>,
+) -> Result<Response, Infallible> {
+    let handle = body.handle.trim_start_matches('@').to_string();
+    let (reply_tx, reply_rx) = oneshot::channel();
+    let cmd = Command::Twitter(TwitterCommand::AddTwitterSubscription(
+        handle,
+        actor(),
+        reply_tx,
+    ));
+    Ok(match ask(&tx, cmd, reply_rx).await {
+        Ok(res) => done(res.map(|()| json!({}))),
+        Err(response) => response,
+    })
+}
+
+#[cfg(feature = "twitter")]
+async fn remove_subscription(handle: String, tx: Sender<Command>) -> Result<Response, Infallible> {
+    let handle = handle.trim_start_matches('@').to_string();
+    let (reply_tx, reply_rx) = oneshot::channel();
+    let cmd = Command::Twitter(TwitterCommand::RemoveTwitterSubscription(
+        handle,
+        actor(),
+        reply_tx,
+    ));
+    Ok(match ask(&tx, cmd, reply_rx).await {
+        Ok(res) => done(res.map(|()| json!({}))),
+        Err(response) => response,
+    })
+}
+
+#[cfg(feature = "coingecko")]
+async fn list_rules(tx: Sender<Command>) -> Result<Response, Infallible> {
+    let (reply_tx, reply_rx) = oneshot::channel();
+    let cmd = Command::Coingecko(CoingeckoCommand::ListRules(reply_tx));
+    Ok(match ask(&tx, cmd, reply_rx).await {
+        Ok(rules) => json(StatusCode::OK, &rules),
+        Err(response) => response,
+    })
+}
+
+#[cfg(feature = "coingecko")]
+async fn add_rule(rule: Rule, tx: Sender<Command>) -> Result<Response, Infallible> {
+    let (reply_tx, reply_rx) = oneshot::channel();
+    let cmd = Command::Coingecko(CoingeckoCommand::AddRule(rule, actor(), reply_tx));
+    Ok(match ask(&tx, cmd, reply_rx).await {
+        Ok(res) => done(res.map(|()| json!({}))),
+        Err(response) => response,
+    })
+}
+
+#[cfg(feature = "coingecko")]
+async fn remove_rule(number: usize, tx: Sender<Command>) -> Result<Response, Infallible> {
+    let (reply_tx, reply_rx) = oneshot::channel();
+    let cmd = Command::Coingecko(CoingeckoCommand::RemoveRule(number, actor(), reply_tx));
+    Ok(match ask(&tx, cmd, reply_rx).await {
+        Ok(res) => done(res),
+        Err(response) => response,
+    })
+}
+
+#[cfg(feature = "coingecko")]
+async fn latest_markets(tx: Sender<Command>) -> Result<Response, Infallible> {
+    let (reply_tx, reply_rx) = oneshot::channel();
+    let cmd = Command::Coingecko(CoingeckoCommand::Markets(reply_tx));
+    Ok(match ask(&tx, cmd, reply_rx).await {
+        Ok(markets) => {
+            let markets: Vec<_> = markets
+                .iter()
+                .map(|m| {
+                    json!({
+                        "id": m.id,
+                        "symbol": m.symbol,
+                        "current_price": m.current_price,
+                        "market_cap": m.market_cap,
+                        "market_cap_rank": m.market_cap_rank,
+                    })
+                })
+                .collect();
+            json(StatusCode::OK, &markets)
+        }
+        Err(response) => response,
+    })
+}
+
+#[cfg(feature = "coingecko")]
+async fn list_alerts(query: UserQuery, tx: Sender<Command>) -> Result<Response, Infallible> {
+    let (reply_tx, reply_rx) = oneshot::channel();
+    let cmd = Command::Coingecko(CoingeckoCommand::ListAlerts(query.user_id, reply_tx));
+    Ok(match ask(&tx, cmd, reply_rx).await {
+        Ok(alerts) => json(StatusCode::OK, &alerts),
+        Err(response) => response,
+    })
+}
+
+#[cfg(feature = "coingecko")]
+async fn add_alert(body: NewAlert, tx: Sender<Command>) -> Result<Response, Infallible> {
+    let (reply_tx, reply_rx) = oneshot::channel();
+    let cmd = Command::Coingecko(CoingeckoCommand::AddAlert(
+        body.user_id,
+        body.coin,
+        body.condition,
+        reply_tx,
+    ));
+    Ok(match ask(&tx, cmd, reply_rx).await {
+        Ok(Ok(id)) => json(StatusCode::CREATED, &Created { id }),
+        Ok(Err(e)) => error(StatusCode::BAD_REQUEST, e),
+        Err(response) => response,
+    })
+}
+
+#[cfg(feature = "coingecko")]
+async fn delete_alert(
+    id: u64,
+    query: UserQuery,
+    tx: Sender<Command>,
+) -> Result<Response, Infallible> {
+    let (reply_tx, reply_rx) = oneshot::channel();
+    let cmd = Command::Coingecko(CoingeckoCommand::DeleteAlert(query.user_id, id, reply_tx));
+    Ok(match ask(&tx, cmd, reply_rx).await {
+        Ok(true) => json(StatusCode::OK, &json!({})),
+        Ok(false) => error(
+            StatusCode::NOT_FOUND,
+            format!("User {} has no alert {}", query.user_id, id),
+        ),
+        Err(response) => response,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::Value;
+    use tokio::sync::mpsc;
+    #[cfg(feature = "coingecko")]
+    use tokio::sync::mpsc::Receiver;
+    use warp::{
+        hyper::body::Bytes,
+        test::{request, RequestBuilder},
+    };
+
+    use super::*;
+
+    const TOKEN: &str = "letmein";
+
+    fn api(
+        token: &str,
+        tx: Sender<Command>,
+    ) -> impl Filter<Extract = (impl Reply,), Error = Infallible> + Clone + 'static {
+        let token = serde_json::from_value(json!(token)).unwrap();
+        routes(token, tx).recover(handle_rejection)
+    }
+
+    fn authorized_request() -> RequestBuilder {
+        request().header("authorization", format!("Bearer {}", TOKEN))
+    }
+
+    fn body(res: &warp::http::Response<Bytes>) -> Value {
+        serde_json::from_slice(res.body()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn requests_need_the_bearer_token() {
+        let (tx, _rx) = mpsc::channel(1);
+        let api = api(TOKEN, tx);
+
+        let res = request().path("/rules").reply(&api).await;
+        assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+        assert_eq!(
+            body(&res),
+            json!({ "error": "Missing or invalid bearer token" })
+        );
+
+        let res = request()
+            .path("/rules")
+            .header("authorization", "Bearer wrong")
+            .reply(&api)
+            .await;
+        assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+
+        // The token without its scheme isn't accepted either
+        let res = request()
+            .path("/rules")
+            .header("authorization", TOKEN)
+            .reply(&api)
+            .await;
+        assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+    }
+
+    #[tokio::test]
+    async fn nothing_is_reachable_without_a_token_set() {
+        let (tx, _rx) = mpsc::channel(1);
+        let api = api("", tx);
+
+        let res = request()
+            .path("/rules")
+            .header("authorization", "Bearer ")
+            .reply(&api)
+            .await;
+        assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+    }
+
+    #[tokio::test]
+    async fn unknown_routes_are_not_found() {
+        let (tx, _rx) = mpsc::channel(1);
+        let api = api(TOKEN, tx);
+
+        let res = authorized_request().path("/nothing").reply(&api).await;
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+        assert_eq!(body(&res), json!({ "error": "Not found" }));
+    }
+
+    /// Answers alert commands as the coingecko manager would for user 1, whose next alert is 7
+    /// and who has no others, and drops the rest as the router does for a stopped manager
+    #[cfg(feature = "coingecko")]
+    fn answer_alerts(mut rx: Receiver<Command>) {
+        tokio::spawn(async move {
+            while let Some(cmd) = rx.recv().await {
+                match cmd {
+                    Command::Coingecko(CoingeckoCommand::AddAlert(_, _, _, reply)) => {
+                        let _ = reply.send(Ok(7));
+                    }
+                    Command::Coingecko(CoingeckoCommand::DeleteAlert(user_id, id, reply)) => {
+                        let _ = reply.send(user_id == 1 && id == 7);
+                    }
+                    _ => {}
+                }
+            }
+        });
+    }
+
+    #[cfg(feature = "coingecko")]
+    #[tokio::test]
+    async fn alerts_are_created_and_deleted() {
+        let (tx, rx) = mpsc::channel(1);
+        answer_alerts(rx);
+        let api = api(TOKEN, tx);
+
+        let res = authorized_request()
+            .method("POST")
+            .path("/alerts")
+            .json(&json!({ "user_id": 1, "coin": "bitcoin", "condition": { "Above": 70000.0 } }))
+            .reply(&api)
+            .await;
+        assert_eq!(res.status(), StatusCode::CREATED);
+        assert_eq!(body(&res), json!({ "id": 7 }));
+
+        let res = authorized_request()
+            .method("DELETE")
+            .path("/alerts/7?user_id=1")
+            .reply(&api)
+            .await;
+        assert_eq!(res.status(), StatusCode::OK);
+
+        let res = authorized_request()
+            .method("DELETE")
+            .path("/alerts/8?user_id=1")
+            .reply(&api)
+            .await;
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+        assert_eq!(body(&res), json!({ "error": "User 1 has no alert 8" }));
+    }
+
+    #[cfg(feature = "coingecko")]
+    #[tokio::test]
+    async fn malformed_requests_are_bad_requests() {
+        let (tx, rx) = mpsc::channel(1);
+        answer_alerts(rx);
+        let api = api(TOKEN, tx);
+
+        let res = authorized_request()
+            .method("POST")
+            .path("/alerts")
+            .json(&json!({ "user_id": 1, "coin": "bitcoin" }))
+            .reply(&api)
+            .await;
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+
+        let res = authorized_request()
+            .method("DELETE")
+            .path("/alerts/7")
+            .reply(&api)
+            .await;
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[cfg(feature = "coingecko")]
+    #[tokio::test]
+    async fn commands_for_a_stopped_manager_are_unavailable() {
+        let (tx, rx) = mpsc::channel(1);
+        answer_alerts(rx);
+        let api = api(TOKEN, tx);
+
+        let res = authorized_request().path("/rules").reply(&api).await;
+        assert_eq!(res.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(
+            body(&res),
+            json!({ "error": "The manager handling this isn't running" })
+        );
+    }
+}
@@ -0,0 +1,236 @@
+use std::{
+    collections::VecDeque,
+    fs::File,
+    io::{BufRead, BufReader},
+    path::Path,
+};
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::storage;
+
+const LOG_PATH: &str = "audit.jsonl";
+
+/// Where a change was made from
+#[derive(Deserialize, Serialize, Debug, Clone, Copy)]
+pub enum Source {
+    Discord,
+    Telegram,
+    Cli,
+    Api,
+}
+
+/// Who made a change
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct Actor {
+    pub source: Source,
+    pub id: String,
+    pub name: String,
+}
+
+/// A single value in the config that changed, at a dotted path such as `twitter.subscriptions`
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct Change {
+    pub path: String,
+    pub before: Value,
+    pub after: Value,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct AuditRecord {
+    pub timestamp: DateTime<Utc>,
+    pub actor: Actor,
+    pub action: String,
+    pub changes: Vec<Change>,
+}
+
+/// Appends a record of the differences between two versions of the config, unless there are none
+pub fn record<T: Serialize>(
+    actor: &Actor,
+    action: &str,
+    before: &T,
+    after: &T,
+) -> Result<(), anyhow::Error> {
+    let changes = changes(before, after)?;
+    if changes.is_empty() {
+        return Ok(());
+    }
+
+    let record = AuditRecord {
+        timestamp: Utc::now(),
+        actor: actor.clone(),
+        action: action.to_string(),
+        changes,
+    };
+    storage::append(LOG_PATH, &record)
+}
+
+/// Appends a record of a permissioned command being run or denied, with no changes of its own
+/// since any change it makes to the config is recorded separately
+pub fn record_command(actor: &Actor, command: &str, allowed: bool) -> Result<(), anyhow::Error> {
+    let verb = if allowed { "ran" } else { "was denied" };
+    let record = AuditRecord {
+        timestamp: Utc::now(),
+        actor: actor.clone(),
+        action: format!("{} {}", verb, command),
+        changes: vec![],
+    };
+    storage::append(LOG_PATH, &record)
+}
+
+/// Every value that differs between two versions of the config
+fn changes<T: Serialize>(before: &T, after: &T) -> Result<Vec<Change>, anyhow::Error> {
+    let mut changes = vec![];
+    diff(
+        "",
+        &serde_json::to_value(before)?,
+        &serde_json::to_value(after)?,
+        &mut changes,
+    );
+    Ok(changes)
+}
+
+fn diff(path: &str, before: &Value, after: &Value, changes: &mut Vec<Change>) {
+    match (before, after) {
+        (Value::Object(before), Value::Object(after)) => {
+            let mut keys: Vec<&String> = before.keys().chain(after.keys()).collect();
+            keys.sort();
+            keys.dedup();
+            for key in keys {
+                let path = if path.is_empty() {
+                    key.to_string()
+                } else {
+                    format!("{}.{}", path, key)
+                };
+                diff(
+                    &path,
+                    before.get(key).unwrap_or(&Value::Null),
+                    after.get(key).unwrap_or(&Value::Null),
+                    changes,
+                );
+            }
+        }
+        _ if before != after => changes.push(Change {
+            path: path.to_string(),
+            before: before.clone(),
+            after: after.clone(),
+        }),
+        _ => {}
+    }
+}
+
+/// The most recent records, oldest first
+pub fn recent(count: usize) -> Result<Vec<AuditRecord>, anyhow::Error> {
+    read_recent(LOG_PATH, count)
+}
+
+fn read_recent(path: impl AsRef<Path>, count: usize) -> Result<Vec<AuditRecord>, anyhow::Error> {
+    if !path.as_ref().exists() {
+        return Ok(vec![]);
+    }
+    let mut records = VecDeque::new();
+    for line in BufReader::new(File::open(path)?).lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        records.push_back(serde_json::from_str(&line)?);
+        if records.len() > count {
+            records.pop_front();
+        }
+    }
+    Ok(records.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use serde_json::json;
+
+    use super::*;
+
+    fn log_path(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!(
+            "honorable_audit_{}_{}.jsonl",
+            name,
+            std::process::id()
+        ));
+        let _ = std::fs::remove_file(&path);
+        path
+    }
+
+    fn command_record(action: &str) -> AuditRecord {
+        AuditRecord {
+            timestamp: Utc::now(),
+            actor: Actor {
+                source: Source::Cli,
+                id: String::from("cli"),
+                name: String::from("cli"),
+            },
+            action: action.to_string(),
+            changes: vec![],
+        }
+    }
+
+    #[test]
+    fn changes_are_listed_by_dotted_path() {
+        let before = json!({
+            "twitter": { "subscriptions": [1], "keywords": [] },
+            "rss": { "feeds": [] }
+        });
+        let after = json!({
+            "twitter": { "subscriptions": [1, 2], "keywords": [] },
+            "telegram": { "chat_ids": [5] }
+        });
+
+        let changes: Vec<_> = changes(&before, &after)
+            .unwrap()
+            .into_iter()
+            .map(|c| (c.path, c.before, c.after))
+            .collect();
+
+        assert_eq!(
+            changes,
+            vec![
+                (String::from("rss"), json!({ "feeds": [] }), Value::Null),
+                (
+                    String::from("telegram"),
+                    Value::Null,
+                    json!({ "chat_ids": [5] })
+                ),
+                (
+                    String::from("twitter.subscriptions"),
+                    json!([1]),
+                    json!([1, 2])
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn identical_configs_have_no_changes() {
+        let config = json!({ "twitter": { "subscriptions": [1] } });
+        assert!(changes(&config, &config).unwrap().is_empty());
+    }
+
+    #[test]
+    fn recent_keeps_the_newest_records_in_order() {
+        let path = log_path("recent");
+        assert!(read_recent(&path, 5).unwrap().is_empty());
+        for i in 0..5 {
+            storage::append(&path, &command_record(&format!("action {}", i))).unwrap();
+        }
+
+        let actions: Vec<_> = read_recent(&path, 2)
+            .unwrap()
+            .into_iter()
+            .map(|r| r.action)
+            .collect();
+
+        assert_eq!(actions, vec!["action 3", "action 4"]);
+        let _ = std::fs::remove_file(&path);
+    }
+}
@@ -0,0 +1,378 @@
+use std::path::PathBuf;
+#[cfg(feature = "coingecko")]
+use std::{fs::File, io::BufReader, path::Path};
+
+#[cfg(feature = "coingecko")]
+use coingecko_tokio::Market;
+use structopt::StructOpt;
+
+#[cfg(any(feature = "twitter", feature = "coingecko"))]
+use crate::audit::{Actor, Source};
+#[cfg(feature = "coingecko")]
+use crate::gecko::{self, Rule};
+use crate::{config_file, Config};
+
+#[derive(StructOpt, Debug)]
+#[structopt(
+    name = "honorable-bot",
+    about = "Posts crypto and social updates to chat"
+)]
+pub struct Opt {
+    /// The config file to use, read as json, toml or yaml by its extension
+    #[structopt(long, parse(from_os_str), global = true)]
+    pub config: Option<PathBuf>,
+    #[structopt(subcommand)]
+    pub command: Option<Subcommand>,
+}
+
+#[derive(StructOpt, Debug)]
+pub enum Subcommand {
+    /// Runs the bot, which is also what happens without a subcommand
+    Run,
+    /// Checks the config
+    Config(ConfigCommand),
+    /// Manages the twitter accounts the bot follows
+    #[cfg(feature = "twitter")]
+    Subscriptions(SubscriptionsCommand),
+    /// Manages the coingecko rules
+    #[cfg(feature = "coingecko")]
+    Rules(RulesCommand),
+    /// Prints what the rules would post between two saved market snapshots
+    #[cfg(feature = "coingecko")]
+    TestRule {
+        /// A json array of coingecko markets to measure from
+        #[structopt(long, parse(from_os_str))]
+        from: PathBuf,
+        /// A json array of coingecko markets to measure to
+        #[structopt(long, parse(from_os_str))]
+        to: PathBuf,
+        /// A rule to try instead of the configured ones, e.g. `--rule "positive_percent 5"`
+        #[structopt(long)]
+        rule: Vec<String>,
+    },
+}
+
+#[derive(StructOpt, Debug)]
+pub enum ConfigCommand {
+    /// Checks that the config can be read and has nothing that can't work, migrating it if needed
+    Validate,
+}
+
+#[cfg(feature = "twitter")]
+#[derive(StructOpt, Debug)]
+pub enum SubscriptionsCommand {
+    Add { handle: String },
+    Remove { handle: String },
+    List,
+}
+
+#[cfg(feature = "coingecko")]
+#[derive(StructOpt, Debug)]
+pub enum RulesCommand {
+    /// Adds a rule, e.g. `rules add positive_percent 5`
+    Add { kind: String, value: String },
+    /// Lists the rules numbered from 1, the numbers `rules remove` and the api's
+    /// `DELETE /rules/{number}` take
+    List,
+    /// Removes a rule by its number in `rules list`
+    Remove { number: usize },
+}
+
+/// Runs a subcommand other than `run`, changing the config the same way the bot does
+pub fn execute(command: Subcommand) -> Result<(), anyhow::Error> {
+    match command {
+        Subcommand::Run => Err(anyhow::anyhow!("The bot isn't run as a cli command")),
+        Subcommand::Config(ConfigCommand::Validate) => {
+            Config::read()?;
+            println!("{} is valid", config_file::path().display());
+            Ok(())
+        }
+        #[cfg(feature = "twitter")]
+        Subcommand::Subscriptions(command) => subscriptions(command),
+        #[cfg(feature = "coingecko")]
+        Subcommand::Rules(command) => rules(command),
+        #[cfg(feature = "coingecko")]
+        Subcommand::TestRule { from, to, rule } => test_rule(from, to, rule),
+    }
+}
+
+#[cfg(feature = "twitter")]
+fn subscriptions(command: SubscriptionsCommand) -> Result<(), anyhow::Error> {
+    match command {
+        SubscriptionsCommand::Add { handle } => {
+            let handle = handle.trim_start_matches('@').to_string();
+            let action = format!("add_subscription {}", handle);
+            Config::modify(&actor(), &action, |config| {
+                let twitter = config.twitter.get_or_insert_with(Default::default);
+                if !twitter.subscriptions.contains(&handle) {
+                    twitter.subscriptions.push(handle);
+                }
+            })?;
+        }
+        SubscriptionsCommand::Remove { handle } => {
+            let handle = handle.trim_start_matches('@').to_string();
+            let action = format!("remove_subscription {}", handle);
+            Config::modify(&actor(), &action, |config| {
+                if let Some(twitter) = &mut config.twitter {
+                    twitter.subscriptions.retain(|h| *h != handle)
+                }
+            })?;
+        }
+        SubscriptionsCommand::List => {
+            if let Some(twitter) = &Config::load()?.twitter {
+                for handle in &twitter.subscriptions {
+                    println!("{}", handle);
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(feature = "coingecko")]
+fn rules(command: RulesCommand) -> Result<(), anyhow::Error> {
+    match command {
+        RulesCommand::Add { kind, value } => {
+            let rule = Rule::parse(&kind, &value)?;
+            let action = format!("add_rule {}", rule);
+            Config::modify(&actor(), &action, |config| add_rule(config, rule))?;
+        }
+        RulesCommand::List => {
+            for line in numbered(&configured_rules()?) {
+                println!("{}", line);
+            }
+        }
+        RulesCommand::Remove { number } => {
+            let mut removed = None;
+            let action = format!("remove_rule {}", number);
+            Config::modify(&actor(), &action, |config| {
+                removed = gecko::remove_rule(config, number)
+            })?;
+            let rule = removed.ok_or_else(|| anyhow::anyhow!("There is no rule {}", number))?;
+            println!("Removed {}", rule);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(feature = "coingecko")]
+fn add_rule(config: &mut Config, rule: Rule) {
+    let coingecko = config.coingecko.get_or_insert_with(Default::default);
+    coingecko.rules.push(rule)
+}
+
+/// The rules as `rules list` prints them, numbered from 1
+#[cfg(feature = "coingecko")]
+fn numbered(rules: &[Rule]) -> Vec<String> {
+    rules
+        .iter()
+        .enumerate()
+        .map(|(i, rule)| format!("{}. {}", i + 1, rule))
+        .collect()
+}
+
+#[cfg(feature = "coingecko")]
+fn configured_rules() -> Result<Vec<Rule>, anyhow::Error> {
+    Ok(Config::load()?.coingecko.map_or_else(Vec::new, |c| c.rules))
+}
+
+#[cfg(feature = "coingecko")]
+fn test_rule(from: PathBuf, to: PathBuf, rules: Vec<String>) -> Result<(), anyhow::Error> {
+    let rules = if rules.is_empty() {
+        configured_rules()?
+    } else {
+        rules
+            .iter()
+            .map(|rule| parse_rule(rule))
+            .collect::<Result<Vec<_>, _>>()?
+    };
+    let initial = read_snapshot(&from)?;
+    let current = read_snapshot(&to)?;
+
+    let results = gecko::evaluate_rules(&rules, &initial, &current);
+    if results.is_empty() {
+        println!("No rules were triggered");
+    }
+    for res in results {
+        println!("{}: {}", res.market().id, res.description());
+    }
+    Ok(())
+}
+
+/// Parses a rule given as one argument, e.g. `--rule "positive_percent 5"`
+#[cfg(feature = "coingecko")]
+fn parse_rule(rule: &str) -> Result<Rule, anyhow::Error> {
+    match rule.split_whitespace().collect::<Vec<_>>()[..] {
+        [kind, value] => Rule::parse(kind, value),
+        _ => Err(anyhow::anyhow!("Expected a rule like `positive_percent 5`")),
+    }
+}
+
+#[cfg(feature = "coingecko")]
+fn read_snapshot(path: &Path) -> Result<Vec<Market>, anyhow::Error> {
+    let file =
+        File::open(path).map_err(|e| anyhow::anyhow!("Failed to open {} {}", path.display(), e))?;
+    Ok(serde_json::from_reader(BufReader::new(file))?)
+}
+
+/// Whoever is running the cli, for the audit log
+#[cfg(any(feature = "twitter", feature = "coingecko"))]
+fn actor() -> Actor {
+    let user = std::env::var("USER").unwrap_or_else(|_| String::from("unknown"));
+    Actor {
+        source: Source::Cli,
+        id: user.clone(),
+        name: user,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(any(feature = "twitter", feature = "coingecko"))]
+    fn parse(args: &[&str]) -> Subcommand {
+        Opt::from_iter_safe(std::iter::once("honorable-bot").chain(args.iter().copied()))
+            .unwrap()
+            .command
+            .unwrap()
+    }
+
+    #[cfg(feature = "coingecko")]
+    fn config(rules: &[&str]) -> Config {
+        let rules: Vec<Rule> = rules.iter().map(|rule| parse_rule(rule).unwrap()).collect();
+        serde_json::from_value(serde_json::json!({ "coingecko": { "rules": rules } })).unwrap()
+    }
+
+    #[cfg(feature = "coingecko")]
+    fn rules_of(config: &Config) -> Vec<String> {
+        config
+            .coingecko
+            .iter()
+            .flat_map(|c| c.rules.iter().map(Rule::to_string))
+            .collect()
+    }
+
+    #[test]
+    fn no_subcommand_runs_the_bot() {
+        let opt = Opt::from_iter_safe(&["honorable-bot", "--config", "bot.toml"]).unwrap();
+        assert_eq!(opt.config, Some(PathBuf::from("bot.toml")));
+        assert!(opt.command.is_none());
+    }
+
+    #[cfg(feature = "twitter")]
+    #[test]
+    fn subscription_commands_parse() {
+        match parse(&["subscriptions", "remove", "@Polkadot"]) {
+            Subcommand::Subscriptions(SubscriptionsCommand::Remove { handle }) => {
+                assert_eq!(handle, "@Polkadot")
+            }
+            command => panic!("Expected subscriptions remove, got {:?}", command),
+        }
+        assert!(matches!(
+            parse(&["subscriptions", "list"]),
+            Subcommand::Subscriptions(SubscriptionsCommand::List)
+        ));
+    }
+
+    #[cfg(feature = "coingecko")]
+    #[test]
+    fn rules_commands_parse() {
+        match parse(&["rules", "add", "positive_percent", "5"]) {
+            Subcommand::Rules(RulesCommand::Add { kind, value }) => {
+                assert_eq!((kind.as_str(), value.as_str()), ("positive_percent", "5"))
+            }
+            command => panic!("Expected rules add, got {:?}", command),
+        }
+        assert!(matches!(
+            parse(&["rules", "remove", "2"]),
+            Subcommand::Rules(RulesCommand::Remove { number: 2 })
+        ));
+        assert!(matches!(
+            parse(&["rules", "list"]),
+            Subcommand::Rules(RulesCommand::List)
+        ));
+        assert!(Opt::from_iter_safe(&["honorable-bot", "rules", "remove", "first"]).is_err());
+    }
+
+    #[cfg(feature = "coingecko")]
+    #[test]
+    fn rules_are_added_to_a_missing_section() {
+        let mut config: Config = serde_json::from_str("{}").unwrap();
+
+        add_rule(&mut config, Rule::parse("negative_rank", "10").unwrap());
+        add_rule(&mut config, Rule::parse("positive_percent", "5").unwrap());
+
+        assert_eq!(
+            rules_of(&config),
+            vec!["negative_rank 10", "positive_percent 5"]
+        );
+    }
+
+    #[cfg(feature = "coingecko")]
+    #[test]
+    fn rules_are_listed_and_removed_by_their_number_from_1() {
+        let mut config = config(&["positive_percent 5", "negative_rank 10", "positive_rank 3"]);
+        assert_eq!(
+            numbered(&config.coingecko.as_ref().unwrap().rules),
+            vec![
+                "1. positive_percent 5",
+                "2. negative_rank 10",
+                "3. positive_rank 3"
+            ]
+        );
+
+        let removed = gecko::remove_rule(&mut config, 2).map(|rule| rule.to_string());
+
+        assert_eq!(removed.as_deref(), Some("negative_rank 10"));
+        assert_eq!(
+            rules_of(&config),
+            vec!["positive_percent 5", "positive_rank 3"]
+        );
+    }
+
+    #[cfg(feature = "coingecko")]
+    #[test]
+    fn removing_a_rule_that_isnt_there_changes_nothing() {
+        let mut config = config(&["positive_percent 5"]);
+
+        assert!(gecko::remove_rule(&mut config, 0).is_none());
+        assert!(gecko::remove_rule(&mut config, 2).is_none());
+        assert_eq!(rules_of(&config), vec!["positive_percent 5"]);
+
+        let mut empty: Config = serde_json::from_str("{}").unwrap();
+        assert!(gecko::remove_rule(&mut empty, 1).is_none());
+    }
+
+    #[cfg(feature = "coingecko")]
+    #[test]
+    fn test_rule_takes_each_rule_as_one_argument() {
+        match parse(&[
+            "test-rule",
+            "--from",
+            "before.json",
+            "--to",
+            "after.json",
+            "--rule",
+            "positive_percent 5",
+            "--rule",
+            "negative_rank 10",
+        ]) {
+            Subcommand::TestRule { from, to, rule } => {
+                assert_eq!(from, PathBuf::from("before.json"));
+                assert_eq!(to, PathBuf::from("after.json"));
+                let rules: Vec<String> = rule
+                    .iter()
+                    .map(|rule| parse_rule(rule).unwrap().to_string())
+                    .collect();
+                assert_eq!(rules, vec!["positive_percent 5", "negative_rank 10"]);
+            }
+            command => panic!("Expected test-rule, got {:?}", command),
+        }
+
+        assert!(parse_rule("positive_percent").is_err());
+        assert!(parse_rule("positive_percent 5 10").is_err());
+        assert!(parse_rule("positive_percent lots").is_err());
+        assert!(parse_rule("sideways 5").is_err());
+    }
+}
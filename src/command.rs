@@ -1,33 +1,145 @@
 use std::sync::Arc;
 
+#[cfg(feature = "coingecko")]
 use coingecko_tokio::Market;
-use egg_mode::tweet::Tweet;
+#[cfg(feature = "discord")]
 use serenity::prelude::TypeMapKey;
 
-use tokio::sync::mpsc::{Receiver, Sender};
+use tokio::sync::{
+    mpsc::{Receiver, Sender},
+    oneshot,
+};
 
-use crate::gecko::RuleResult;
+#[cfg(feature = "coingecko")]
+use crate::alert::{AlertCondition, PriceAlert};
+use crate::audit::Actor;
+use crate::events::EventBus;
+#[cfg(feature = "coingecko")]
+use crate::gecko::Rule;
+use crate::lifecycle::{ManagerHandle, Shutdown};
+#[cfg(feature = "coingecko")]
+use crate::portfolio::PortfolioSummary;
+use crate::scheduler::{Job, JobTask};
 use crate::Config;
 
+/// Control messages routed to a single manager. Anything that happened, as opposed to
+/// something a manager is being asked to do, is published on the event bus instead.
 pub enum Command {
+    #[cfg(feature = "twitter")]
     Twitter(TwitterCommand),
-    Discord(DiscordCommand),
+    #[cfg(feature = "coingecko")]
     Coingecko(CoingeckoCommand),
+    Rss(RssCommand),
+    Reddit(RedditCommand),
+    Scheduler(SchedulerCommand),
+    #[cfg(feature = "coingecko")]
+    Portfolio(PortfolioCommand),
 }
+#[cfg(feature = "twitter")]
 pub enum TwitterCommand {
-    AddTwitterSubscription(String),
+    AddTwitterSubscription(String, Actor, oneshot::Sender<Result<(), anyhow::Error>>),
+    /// Stops following a handle, replying with an error if it wasn't followed
+    RemoveTwitterSubscription(String, Actor, oneshot::Sender<Result<(), anyhow::Error>>),
+    ListTwitterSubscriptions(oneshot::Sender<Vec<String>>),
 }
-pub enum DiscordCommand {
-    SendTweet(Tweet),
-    SendCoingeckoRuleResult(RuleResult),
-    SendCoingeckoBase(Vec<Market>),
+#[cfg(feature = "coingecko")]
+pub enum CoingeckoCommand {
+    /// Looks a coin up in the latest market state by id or symbol
+    Price(String, oneshot::Sender<Option<Market>>),
+    /// Publishes the top coins by market cap
+    Top(usize),
+    /// Publishes the named digest of how the market moved since it last ran
+    Digest(String),
+    /// Adds an alert on a coin for a user, replying with its id
+    AddAlert(
+        u64,
+        String,
+        AlertCondition,
+        oneshot::Sender<Result<u64, anyhow::Error>>,
+    ),
+    ListAlerts(u64, oneshot::Sender<Vec<PriceAlert>>),
+    /// Deletes one of a user's alerts, replying whether it existed
+    DeleteAlert(u64, u64, oneshot::Sender<bool>),
+    /// Replies with the whole latest market state
+    Markets(oneshot::Sender<Vec<Market>>),
+    ListRules(oneshot::Sender<Vec<Rule>>),
+    AddRule(Rule, Actor, oneshot::Sender<Result<(), anyhow::Error>>),
+    /// Removes a rule by its number, counting from 1 as the rules are listed, replying with it
+    RemoveRule(usize, Actor, oneshot::Sender<Result<Rule, anyhow::Error>>),
+}
+pub enum RssCommand {
+    AddFeed(String, Actor),
+    RemoveFeed(String, Actor),
+}
+pub enum RedditCommand {
+    /// Watches a subreddit with the default filters, replying with an error if it already was
+    AddSubreddit(String, Actor, oneshot::Sender<Result<(), anyhow::Error>>),
+    /// Stops watching a subreddit, replying with an error if it wasn't watched
+    RemoveSubreddit(String, Actor, oneshot::Sender<Result<(), anyhow::Error>>),
+    ListSubreddits(oneshot::Sender<Vec<String>>),
+}
+#[cfg(feature = "coingecko")]
+pub enum PortfolioCommand {
+    /// Adds an amount of a coin bought at a price to a user's portfolio
+    Add(
+        u64,
+        String,
+        f64,
+        f64,
+        oneshot::Sender<Result<(), anyhow::Error>>,
+    ),
+    /// Removes an amount of a coin from a user's portfolio, or all of it without an amount
+    Remove(
+        u64,
+        String,
+        Option<f64>,
+        oneshot::Sender<Result<(), anyhow::Error>>,
+    ),
+    Summary(
+        u64,
+        oneshot::Sender<Result<PortfolioSummary, anyhow::Error>>,
+    ),
+}
+pub enum SchedulerCommand {
+    /// Registers a manager's own job, replacing the one it registered for the same task before
+    Register(String, String, JobTask),
+    /// Adds a persisted job with an optional timezone, replying with its id
+    Add(
+        String,
+        Option<String>,
+        JobTask,
+        oneshot::Sender<Result<u64, anyhow::Error>>,
+    ),
+    List(oneshot::Sender<Vec<Job>>),
+    /// Removes a job, replying whether it existed
+    Remove(u64, oneshot::Sender<bool>),
 }
-pub enum CoingeckoCommand {}
 pub struct CommandSender(pub Sender<Command>);
+#[cfg(feature = "discord")]
 impl TypeMapKey for CommandSender {
     type Value = Arc<CommandSender>;
 }
 
 pub trait Manager<T> {
-    fn start_manager(&self, config_cloned: Arc<Config>, rx: Receiver<T>, tx: Sender<Command>);
+    /// Starts the manager's task, which should return once `shutdown` is triggered
+    fn start_manager(
+        &self,
+        config_cloned: Arc<Config>,
+        rx: Receiver<T>,
+        tx: Sender<Command>,
+        events: EventBus,
+        shutdown: Shutdown,
+    ) -> ManagerHandle;
+}
+
+/// A manager that takes no commands, only following its own sources or the event bus
+pub trait Worker {
+    /// Starts the worker's task, which should return once `shutdown` is triggered
+    fn start_worker(
+        &self,
+        config_cloned: Arc<Config>,
+        tx: Sender<Command>,
+        events: EventBus,
+        shutdown: Shutdown,
+    ) -> ManagerHandle;
 }
@@ -0,0 +1,454 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use once_cell::sync::OnceCell;
+use serde_json::{Map, Value};
+
+use crate::migration;
+
+/// Where the base config is looked for, in order, when no `--config` is given
+const DEFAULT_PATHS: [&str; 4] = ["config.toml", "config.yaml", "config.yml", "config.json"];
+/// Names the environment whose file is layered over the base, e.g. `production` layers
+/// `config.production.toml` over `config.toml`
+const ENVIRONMENT_VAR: &str = "HONORABLE_ENV";
+/// Variables overriding single values, e.g. `HONORABLE__DISCORD__CHANNEL_ID=123`
+const OVERRIDE_PREFIX: &str = "HONORABLE__";
+
+static PATH: OnceCell<PathBuf> = OnceCell::new();
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Format {
+    Json,
+    Toml,
+    Yaml,
+}
+
+impl Format {
+    fn of(path: &Path) -> Result<Format, anyhow::Error> {
+        match path.extension().and_then(|e| e.to_str()) {
+            Some("json") => Ok(Format::Json),
+            Some("toml") => Ok(Format::Toml),
+            Some("yaml") | Some("yml") => Ok(Format::Yaml),
+            _ => Err(anyhow::anyhow!(
+                "Can't tell the format of {}, expected a .json, .toml or .yaml file",
+                path.display()
+            )),
+        }
+    }
+
+    fn parse(self, contents: &str) -> Result<Value, anyhow::Error> {
+        Ok(match self {
+            Format::Json => serde_json::from_str(contents)?,
+            Format::Toml => toml::from_str(contents)?,
+            Format::Yaml => serde_yaml::from_str(contents)?,
+        })
+    }
+}
+
+/// Uses the given file as the base config instead of looking for one
+pub fn set_path(path: PathBuf) {
+    let _ = PATH.set(path);
+}
+
+/// The base config file, which is the one changes are written back to
+pub fn path() -> &'static Path {
+    PATH.get_or_init(|| {
+        DEFAULT_PATHS
+            .iter()
+            .map(PathBuf::from)
+            .find(|path| path.exists())
+            .unwrap_or_else(|| PathBuf::from("config.json"))
+    })
+}
+
+fn read_file(path: &Path) -> Result<Value, anyhow::Error> {
+    let contents = fs::read_to_string(path)
+        .map_err(|e| anyhow::anyhow!("Failed to read {} {}", path.display(), e))?;
+    Format::of(path)?
+        .parse(&contents)
+        .map_err(|e| anyhow::anyhow!("Failed to parse {} {}", path.display(), e))
+}
+
+/// Reads the base config, migrating it in place if an older build wrote it, with the
+/// environment's file and then the override variables layered over it
+pub fn load() -> Result<Value, anyhow::Error> {
+    let path = path();
+    let mut config = load_base()?;
+
+    if let Ok(environment) = std::env::var(ENVIRONMENT_VAR) {
+        merge(
+            &mut config,
+            read_file(&environment_path(path, &environment))?,
+        );
+    }
+    for (name, value) in std::env::vars() {
+        if let Some(name) = name.strip_prefix(OVERRIDE_PREFIX) {
+            let keys: Vec<String> = name.split("__").map(|k| k.to_lowercase()).collect();
+            // Values are taken as json where they parse, so numbers and lists can be given
+            let value = serde_json::from_str(&value).unwrap_or(Value::String(value));
+            set(&mut config, &keys, value);
+        }
+    }
+    Ok(config)
+}
+
+/// Reads only the base config, migrating it in place if an older build wrote it. Changes are
+/// worked out against this, so what the other layers set is never written into the file.
+pub fn load_base() -> Result<Value, anyhow::Error> {
+    read_base(path())
+}
+
+fn read_base(path: &Path) -> Result<Value, anyhow::Error> {
+    let mut config = match read_file(path) {
+        Ok(config) => config,
+        Err(e) => {
+            let repaired = match Format::of(path)? {
+                Format::Json => fs::read_to_string(path)
+                    .ok()
+                    .and_then(|contents| migration::strip_stale_tail(&contents)),
+                _ => None,
+            };
+            let repaired = repaired.ok_or(e)?;
+            tracing::warn!(
+                "Removing what an older build left after the config in {}",
+                path.display()
+            );
+            fs::write(path, serde_json::to_string_pretty(&repaired)?)?;
+            repaired
+        }
+    };
+    let original = config.clone();
+    if migration::migrate(&mut config)? {
+        write_changes_to(path, &original, &config)?;
+    }
+    Ok(config)
+}
+
+fn environment_path(path: &Path, environment: &str) -> PathBuf {
+    let stem = path
+        .file_stem()
+        .and_then(|s| s.to_str())
+        .unwrap_or("config");
+    let extension = path.extension().and_then(|e| e.to_str()).unwrap_or("json");
+    path.with_file_name(format!("{}.{}.{}", stem, environment, extension))
+}
+
+/// Layers one config over another, replacing everything but sections, which are merged
+fn merge(config: &mut Value, layer: Value) {
+    match (config, layer) {
+        (Value::Object(config), Value::Object(layer)) => {
+            for (key, value) in layer {
+                merge(config.entry(key).or_insert(Value::Null), value);
+            }
+        }
+        (config, layer) => *config = layer,
+    }
+}
+
+fn set(config: &mut Value, keys: &[String], value: Value) {
+    match keys.split_first() {
+        Some((key, rest)) => {
+            if !config.is_object() {
+                *config = Value::Object(Map::new());
+            }
+            if let Value::Object(config) = config {
+                set(
+                    config.entry(key.clone()).or_insert(Value::Null),
+                    rest,
+                    value,
+                );
+            }
+        }
+        None => *config = value,
+    }
+}
+
+/// Writes what changed between two versions of the config back to the base file, in its
+/// format. Anything else in the file is left as it was, including values the overrides
+/// replaced and secrets, which are never serialized. Comments are kept in TOML files.
+pub fn write_changes(before: &Value, after: &Value) -> Result<(), anyhow::Error> {
+    write_changes_to(path(), before, after)
+}
+
+fn write_changes_to(path: &Path, before: &Value, after: &Value) -> Result<(), anyhow::Error> {
+    let format = Format::of(path)?;
+    let contents = if path.exists() {
+        fs::read_to_string(path)?
+    } else {
+        String::new()
+    };
+
+    let contents = match format {
+        Format::Toml => {
+            let mut document: toml_edit::Document = contents.parse()?;
+            if let (Value::Object(before), Value::Object(after)) = (before, after) {
+                apply_toml(document.as_table_mut(), before, after)?;
+            }
+            document.to_string()
+        }
+        Format::Json | Format::Yaml => {
+            let mut config = if contents.trim().is_empty() {
+                Value::Object(Map::new())
+            } else {
+                format.parse(&contents)?
+            };
+            apply(&mut config, before, after);
+            if format == Format::Json {
+                serde_json::to_string_pretty(&config)?
+            } else {
+                serde_yaml::to_string(&config)?
+            }
+        }
+    };
+    fs::write(path, contents)?;
+
+    Ok(())
+}
+
+fn apply(config: &mut Value, before: &Value, after: &Value) {
+    match (before, after) {
+        (Value::Object(before), Value::Object(after)) => {
+            if !config.is_object() {
+                *config = Value::Object(Map::new());
+            }
+            if let Value::Object(config) = config {
+                for (key, value) in after {
+                    let previous = before.get(key).unwrap_or(&Value::Null);
+                    if previous != value {
+                        apply(
+                            config.entry(key.clone()).or_insert(Value::Null),
+                            previous,
+                            value,
+                        );
+                    }
+                }
+                for key in before.keys().filter(|key| !after.contains_key(*key)) {
+                    config.remove(key);
+                }
+            }
+        }
+        _ if before != after => *config = after.clone(),
+        _ => {}
+    }
+}
+
+/// The same as `apply` but editing the TOML document in place, so comments and formatting
+/// around the values that didn't change survive
+fn apply_toml(
+    table: &mut toml_edit::Table,
+    before: &Map<String, Value>,
+    after: &Map<String, Value>,
+) -> Result<(), anyhow::Error> {
+    for (key, value) in after {
+        let previous = before.get(key).unwrap_or(&Value::Null);
+        if previous == value {
+            continue;
+        }
+        if let (Value::Object(previous), Value::Object(value)) = (previous, value) {
+            if let Some(inner) = table[key.as_str()].as_table_mut() {
+                apply_toml(inner, previous, value)?;
+                continue;
+            }
+        }
+        if value.is_null() {
+            // TOML has no null, so an unset value is left out
+            table.remove(key);
+        } else {
+            table[key.as_str()] = toml_item(value)?;
+        }
+    }
+    for key in before.keys().filter(|key| !after.contains_key(*key)) {
+        table.remove(key);
+    }
+    Ok(())
+}
+
+fn toml_item(value: &Value) -> Result<toml_edit::Item, anyhow::Error> {
+    let mut wrapper = toml::value::Table::new();
+    wrapper.insert(
+        String::from("value"),
+        toml::Value::try_from(without_nulls(value))?,
+    );
+    let document: toml_edit::Document = toml::to_string(&wrapper)?.parse()?;
+    Ok(document["value"].clone())
+}
+
+fn without_nulls(value: &Value) -> Value {
+    match value {
+        Value::Object(map) => Value::Object(
+            map.iter()
+                .filter(|(_, v)| !v.is_null())
+                .map(|(k, v)| (k.clone(), without_nulls(v)))
+                .collect(),
+        ),
+        Value::Array(values) => Value::Array(values.iter().map(without_nulls).collect()),
+        value => value.clone(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::migration::CURRENT_VERSION;
+
+    fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!(
+            "honorable_config_{}_{}.json",
+            name,
+            std::process::id()
+        ))
+    }
+
+    #[test]
+    fn layers_merge_sections_and_replace_values() {
+        let mut config = json!({
+            "rss": { "feeds": ["https://a.example/feed"], "sleep_time_secs": 60 },
+            "scheduler": { "timezone": "UTC" }
+        });
+
+        merge(
+            &mut config,
+            json!({
+                "rss": { "feeds": ["https://b.example/feed"] },
+                "http": { "port": 8080 }
+            }),
+        );
+
+        assert_eq!(
+            config,
+            json!({
+                "rss": { "feeds": ["https://b.example/feed"], "sleep_time_secs": 60 },
+                "scheduler": { "timezone": "UTC" },
+                "http": { "port": 8080 }
+            })
+        );
+    }
+
+    #[test]
+    fn overrides_create_the_sections_they_need() {
+        let mut config = json!({ "discord": "not a section" });
+
+        set(
+            &mut config,
+            &[String::from("discord"), String::from("channel_id")],
+            json!(123),
+        );
+        set(
+            &mut config,
+            &[String::from("http"), String::from("port")],
+            json!(8080),
+        );
+
+        assert_eq!(
+            config,
+            json!({ "discord": { "channel_id": 123 }, "http": { "port": 8080 } })
+        );
+    }
+
+    #[test]
+    fn environment_file_sits_next_to_the_base() {
+        assert_eq!(
+            environment_path(Path::new("conf/config.toml"), "production"),
+            PathBuf::from("conf/config.production.toml")
+        );
+        assert_eq!(
+            environment_path(Path::new("config.json"), "staging"),
+            PathBuf::from("config.staging.json")
+        );
+    }
+
+    #[test]
+    fn format_is_taken_from_the_extension() {
+        assert_eq!(Format::of(Path::new("config.yml")).unwrap(), Format::Yaml);
+        assert_eq!(Format::of(Path::new("config.toml")).unwrap(), Format::Toml);
+        assert!(Format::of(Path::new("config.ini")).is_err());
+    }
+
+    #[test]
+    fn only_changed_values_are_applied() {
+        // What's in the file, including a value an override replaced when it was read
+        let mut file = json!({
+            "rss": { "feeds": ["https://a.example/feed"] },
+            "http": { "port": 8080 },
+            "reddit": { "subreddits": ["polkadot"] }
+        });
+        let before = json!({
+            "rss": { "feeds": ["https://a.example/feed"] },
+            "http": { "port": 9090 },
+            "reddit": { "subreddits": ["polkadot"] }
+        });
+        let after = json!({
+            "rss": { "feeds": ["https://a.example/feed", "https://b.example/feed"] },
+            "http": { "port": 9090 }
+        });
+
+        apply(&mut file, &before, &after);
+
+        assert_eq!(
+            file,
+            json!({
+                "rss": { "feeds": ["https://a.example/feed", "https://b.example/feed"] },
+                "http": { "port": 8080 }
+            })
+        );
+    }
+
+    #[test]
+    fn toml_changes_keep_comments() {
+        let contents = "# The bot's config\nversion = 1\n\n# Feeds to follow\n[rss]\nfeeds = [\"https://a.example/feed\"]\n\n[telegram]\ntoken_file = \"telegram.token\"\n";
+        let mut document: toml_edit::Document = contents.parse().unwrap();
+        let before = json!({
+            "version": 1,
+            "rss": { "feeds": ["https://a.example/feed"] },
+            "telegram": { "token_file": "telegram.token" }
+        });
+        let after = json!({
+            "version": 1,
+            "rss": { "feeds": ["https://a.example/feed", "https://b.example/feed"] },
+            "telegram": { "token_file": null }
+        });
+
+        apply_toml(
+            document.as_table_mut(),
+            before.as_object().unwrap(),
+            after.as_object().unwrap(),
+        )
+        .unwrap();
+
+        let written = document.to_string();
+        assert!(written.contains("# The bot's config"));
+        assert!(written.contains("# Feeds to follow"));
+        assert_eq!(
+            Format::Toml.parse(&written).unwrap(),
+            json!({
+                "version": 1,
+                "rss": { "feeds": ["https://a.example/feed", "https://b.example/feed"] },
+                "telegram": {}
+            })
+        );
+    }
+
+    #[test]
+    fn base_is_repaired_and_migrated_in_place() {
+        let path = temp_path("base");
+        fs::write(
+            &path,
+            "{\n  \"twitter\": {\n    \"subscriptions\": [\"@Polkadot\"]\n  }\n}\n  ]\n}\n",
+        )
+        .unwrap();
+
+        let config = read_base(&path).unwrap();
+
+        let expected = json!({
+            "version": CURRENT_VERSION,
+            "twitter": { "subscriptions": ["Polkadot"] }
+        });
+        assert_eq!(config, expected);
+        assert_eq!(read_file(&path).unwrap(), expected);
+        fs::remove_file(&path).unwrap();
+    }
+}
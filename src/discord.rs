@@ -1,44 +1,137 @@
-use std::sync::Arc;
+use std::{collections::HashMap, sync::Arc};
 
 use anyhow::Context as AnyhowContext;
 
-use crate::gecko::RuleResult;
+#[cfg(feature = "twitter")]
+use crate::command::TwitterCommand;
+#[cfg(feature = "coingecko")]
 use crate::{
-    command::{Command, CommandSender, DiscordCommand, Manager, TwitterCommand},
+    alert::{AlertCondition, PriceAlert},
+    command::{CoingeckoCommand, PortfolioCommand},
+    gecko::RuleResult,
+    portfolio::PortfolioSummary,
+};
+use crate::{
+    audit::{self, Actor, Source},
+    command::{Command, CommandSender, RedditCommand, RssCommand, SchedulerCommand, Worker},
+    events::{self, Event, EventBus},
+    lifecycle::{ManagerHandle, Shutdown},
+    metrics,
+    permissions::{Capability, Grantee, Permissions, PermissionsConfig},
+    scheduler::JobTask,
+    secret::Secret,
     Config,
 };
+#[cfg(feature = "coingecko")]
+use coingecko_tokio::Market;
+use futures::FutureExt;
+#[cfg(feature = "coingecko")]
+use num_format::{Locale, ToFormattedString};
 use serde::{Deserialize, Serialize};
 use serenity::client::{Client, Context, EventHandler};
+use serenity::framework::standard::{
+    macros::{command, group, hook},
+    CommandResult, StandardFramework,
+};
+use serenity::gateway::ConnectionStage;
 use serenity::model::channel::Message;
+use serenity::model::event::ShardStageUpdateEvent;
+use serenity::model::gateway::Ready;
 use serenity::{async_trait, framework::standard::Args, model::channel::ReactionType};
-use serenity::{
-    framework::standard::{
-        macros::{command, group},
-        CommandResult, StandardFramework,
+use tokio::{
+    sync::{
+        mpsc::{self, Sender},
+        oneshot, RwLock,
     },
-    http::Http,
+    task::JoinHandle,
 };
-use tokio::sync::mpsc::{Receiver, Sender};
-use num_format::{Locale, ToFormattedString};
+use tracing::{Instrument, Span};
 
 #[derive(Deserialize, Serialize, Debug, Clone)]
 pub struct DiscordConfig {
     pub channel_id: u64,
-    pub token: String,
+    #[serde(default, skip_serializing)]
+    pub token: Secret,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub token_file: Option<String>,
+    /// Rule results from a single poll beyond this many are posted as one summary message
+    #[serde(default = "default_coalesce_threshold")]
+    pub coalesce_threshold: usize,
+    #[serde(default = "default_api_base")]
+    pub api_base: String,
+}
+
+const OUTBOX_CAPACITY: usize = 256;
+const DEFAULT_AUDIT_RECORDS: usize = 10;
+const MAX_AUDIT_RECORDS: usize = 50;
+const MAX_AUDIT_CHANGE_LEN: usize = 200;
+/// Discord messages are capped at 2000 characters, which leaves room for the code block
+const MAX_AUDIT_MESSAGE_LEN: usize = 1900;
+const MAX_RATE_LIMIT_RETRIES: u32 = 5;
+const MAX_EMBED_DESCRIPTION: usize = 4096;
+
+fn default_coalesce_threshold() -> usize {
+    5
+}
+
+fn default_api_base() -> String {
+    String::from("https://discord.com/api/v9")
+}
+
+impl DiscordConfig {
+    /// Takes the token from `HONORABLE_DISCORD_TOKEN` or the token file when given
+    pub fn resolve_secrets(&mut self) -> Result<(), anyhow::Error> {
+        self.token
+            .resolve("HONORABLE_DISCORD_TOKEN", &self.token_file)
+    }
 }
 
 #[group]
-#[commands(add_subscription)]
+#[commands(
+    add_feed,
+    remove_feed,
+    add_subreddit,
+    remove_subreddit,
+    subreddits,
+    schedule,
+    schedules,
+    unschedule,
+    perms,
+    audit
+)]
 struct General;
 
-struct Handler; //TODO can store shit in here apparently
+#[cfg(feature = "twitter")]
+#[group]
+#[commands(add_subscription)]
+struct Twitter;
+
+#[cfg(feature = "coingecko")]
+#[group]
+#[commands(alert, alerts, portfolio)]
+struct Coingecko;
+
+struct Handler {
+    events: EventBus,
+}
 
 #[async_trait]
-impl EventHandler for Handler {}
+impl EventHandler for Handler {
+    async fn ready(&self, _ctx: Context, _ready: Ready) {
+        events::publish(&self.events, Event::DiscordGateway { connected: true });
+    }
 
+    async fn shard_stage_update(&self, _ctx: Context, update: ShardStageUpdateEvent) {
+        let connected = update.new == ConnectionStage::Connected;
+        if connected != (update.old == ConnectionStage::Connected) {
+            events::publish(&self.events, Event::DiscordGateway { connected });
+        }
+    }
+}
+
+#[cfg(feature = "twitter")]
 #[command]
 #[only_in(guilds)]
-#[allowed_roles("administrator")]
 async fn add_subscription(ctx: &Context, msg: &Message, mut args: Args) -> CommandResult {
     if args.is_empty() {
         msg.reply(ctx, "You need to provide a twitter handle.")
@@ -50,232 +143,1369 @@ async fn add_subscription(ctx: &Context, msg: &Message, mut args: Args) -> Comma
 
     let twitter_handle = args
         .single::<String>()
-        .context("No twitter handle provided")?;
+        .context("No twitter handle provided")?
+        .trim_start_matches('@')
+        .to_string();
     msg.react(ctx, ReactionType::Unicode(String::from("👅")))
         .await?;
 
+    let (reply_tx, reply_rx) = oneshot::channel();
+    let cmd = Command::Twitter(TwitterCommand::AddTwitterSubscription(
+        twitter_handle.clone(),
+        actor(msg),
+        reply_tx,
+    ));
+    if send_command(ctx, cmd).await {
+        let success = format!("Subscribed to @{}", twitter_handle);
+        reply_with_result(ctx, msg, reply_rx, &success).await?;
+    }
+    Ok(())
+}
+
+#[command]
+#[only_in(guilds)]
+async fn add_feed(ctx: &Context, msg: &Message, mut args: Args) -> CommandResult {
+    let url = args.single::<String>().context("No feed url provided")?;
+    send_rss_command(ctx, msg, RssCommand::AddFeed(url, actor(msg))).await
+}
+
+#[command]
+#[only_in(guilds)]
+async fn remove_feed(ctx: &Context, msg: &Message, mut args: Args) -> CommandResult {
+    let url = args.single::<String>().context("No feed url provided")?;
+    send_rss_command(ctx, msg, RssCommand::RemoveFeed(url, actor(msg))).await
+}
+
+async fn send_rss_command(ctx: &Context, msg: &Message, cmd: RssCommand) -> CommandResult {
+    if send_command(ctx, Command::Rss(cmd)).await {
+        msg.react(ctx, ReactionType::Unicode(String::from("✅")))
+            .await?;
+    }
+    Ok(())
+}
+
+#[command]
+#[only_in(guilds)]
+async fn add_subreddit(ctx: &Context, msg: &Message, mut args: Args) -> CommandResult {
+    let name = args.single::<String>().context("No subreddit provided")?;
+    let (reply_tx, reply_rx) = oneshot::channel();
+    let cmd = RedditCommand::AddSubreddit(name.clone(), actor(msg), reply_tx);
+    if send_command(ctx, Command::Reddit(cmd)).await {
+        let success = format!("Watching r/{}", name.trim_start_matches("r/"));
+        reply_with_result(ctx, msg, reply_rx, &success).await?;
+    }
+    Ok(())
+}
+
+#[command]
+#[only_in(guilds)]
+async fn remove_subreddit(ctx: &Context, msg: &Message, mut args: Args) -> CommandResult {
+    let name = args.single::<String>().context("No subreddit provided")?;
+    let (reply_tx, reply_rx) = oneshot::channel();
+    let cmd = RedditCommand::RemoveSubreddit(name.clone(), actor(msg), reply_tx);
+    if send_command(ctx, Command::Reddit(cmd)).await {
+        let success = format!("Stopped watching r/{}", name.trim_start_matches("r/"));
+        reply_with_result(ctx, msg, reply_rx, &success).await?;
+    }
+    Ok(())
+}
+
+#[command]
+#[only_in(guilds)]
+async fn subreddits(ctx: &Context, msg: &Message) -> CommandResult {
+    let (reply_tx, reply_rx) = oneshot::channel();
+    let cmd = RedditCommand::ListSubreddits(reply_tx);
+    if !send_command(ctx, Command::Reddit(cmd)).await {
+        return Ok(());
+    }
+    let subreddits = reply_rx.await.unwrap_or_default();
+    let reply = if subreddits.is_empty() {
+        String::from("No subreddits are watched.")
+    } else {
+        subreddits
+            .iter()
+            .map(|name| format!("r/{}", name))
+            .collect::<Vec<_>>()
+            .join("\n")
+    };
+    msg.reply(ctx, reply).await?;
+    Ok(())
+}
+
+/// Runs a task on a cron schedule, e.g. `~schedule "0 9 * * *" top 10 Europe/London`,
+/// where the timezone is optional
+#[command]
+#[only_in(guilds)]
+async fn schedule(ctx: &Context, msg: &Message, mut args: Args) -> CommandResult {
+    let expression = args
+        .single_quoted::<String>()
+        .context("No schedule provided")?;
+    let words: Vec<String> = args.iter::<String>().filter_map(Result::ok).collect();
+    let task_words: Vec<&str> = words.iter().take(2).map(String::as_str).collect();
+    let task = match JobTask::parse(&task_words) {
+        Ok(task) => task,
+        Err(e) => {
+            msg.reply(ctx, e).await?;
+            return Ok(());
+        }
+    };
+
+    let (reply_tx, reply_rx) = oneshot::channel();
+    let cmd = SchedulerCommand::Add(expression, words.get(2).cloned(), task, reply_tx);
+    if !send_command(ctx, Command::Scheduler(cmd)).await {
+        return Ok(());
+    }
+    let reply = match reply_rx.await {
+        Ok(Ok(id)) => format!("Scheduled job {}", id),
+        Ok(Err(e)) => format!("Couldn't schedule that, {}", e),
+        Err(_) => String::from("The scheduler isn't running."),
+    };
+    msg.reply(ctx, reply).await?;
+    Ok(())
+}
+
+#[command]
+#[only_in(guilds)]
+async fn schedules(ctx: &Context, msg: &Message) -> CommandResult {
+    let (reply_tx, reply_rx) = oneshot::channel();
+    if !send_command(ctx, Command::Scheduler(SchedulerCommand::List(reply_tx))).await {
+        return Ok(());
+    }
+    let jobs = reply_rx.await.unwrap_or_default();
+    let reply = if jobs.is_empty() {
+        String::from("Nothing is scheduled.")
+    } else {
+        jobs.iter()
+            .map(|job| {
+                format!(
+                    "`{}` `{}` ({}) {}{}",
+                    job.id,
+                    job.schedule,
+                    job.timezone,
+                    job.task,
+                    job.owner
+                        .as_ref()
+                        .map(|owner| format!(", registered by {}", owner))
+                        .unwrap_or_default()
+                )
+            })
+            .collect::<Vec<_>>()
+            .join("\n")
+    };
+    msg.reply(ctx, reply).await?;
+    Ok(())
+}
+
+#[command]
+#[only_in(guilds)]
+async fn unschedule(ctx: &Context, msg: &Message, mut args: Args) -> CommandResult {
+    let id = args.single::<u64>().context("No job id provided")?;
+    let (reply_tx, reply_rx) = oneshot::channel();
+    let cmd = SchedulerCommand::Remove(id, reply_tx);
+    if !send_command(ctx, Command::Scheduler(cmd)).await {
+        return Ok(());
+    }
+    if reply_rx.await.unwrap_or(false) {
+        msg.react(ctx, ReactionType::Unicode(String::from("✅")))
+            .await?;
+    } else {
+        msg.reply(ctx, format!("There is no job {}", id)).await?;
+    }
+    Ok(())
+}
+
+/// Direct messages you once a coin's price crosses a level or changes by a percent over a
+/// window, e.g. `~alert btc above 70000` or `~alert eth below -5% 24h`. `~alert delete <id>`
+/// deletes one of your alerts.
+#[cfg(feature = "coingecko")]
+#[command]
+async fn alert(ctx: &Context, msg: &Message, args: Args) -> CommandResult {
+    let words: Vec<String> = args.raw().map(str::to_lowercase).collect();
+    let words: Vec<&str> = words.iter().map(String::as_str).collect();
+    let user_id = msg.author.id.0;
+
+    let reply = match words.as_slice() {
+        ["delete", id] => {
+            let id = id.parse::<u64>().context("Invalid alert id")?;
+            let (reply_tx, reply_rx) = oneshot::channel();
+            let cmd = CoingeckoCommand::DeleteAlert(user_id, id, reply_tx);
+            if !send_command(ctx, Command::Coingecko(cmd)).await {
+                return Ok(());
+            }
+            match reply_rx.await {
+                Ok(true) => format!("Deleted alert {}", id),
+                Ok(false) => format!("You don't have an alert {}", id),
+                Err(_) => String::from("Alerts aren't available yet."),
+            }
+        }
+        [coin, condition @ ..] => {
+            let condition = match AlertCondition::parse(condition) {
+                Ok(condition) => condition,
+                Err(e) => {
+                    msg.reply(ctx, e).await?;
+                    return Ok(());
+                }
+            };
+            let (reply_tx, reply_rx) = oneshot::channel();
+            let cmd = CoingeckoCommand::AddAlert(user_id, coin.to_string(), condition, reply_tx);
+            if !send_command(ctx, Command::Coingecko(cmd)).await {
+                return Ok(());
+            }
+            match reply_rx.await {
+                Ok(Ok(id)) => format!("Added alert {}, I'll DM you when it triggers", id),
+                Ok(Err(e)) => format!("Couldn't add that alert, {}", e),
+                Err(_) => String::from("Alerts aren't available yet."),
+            }
+        }
+        [] => String::from("You need to provide a coin and a condition."),
+    };
+    msg.reply(ctx, reply).await?;
+    Ok(())
+}
+
+#[cfg(feature = "coingecko")]
+#[command]
+async fn alerts(ctx: &Context, msg: &Message) -> CommandResult {
+    let (reply_tx, reply_rx) = oneshot::channel();
+    let cmd = CoingeckoCommand::ListAlerts(msg.author.id.0, reply_tx);
+    if !send_command(ctx, Command::Coingecko(cmd)).await {
+        return Ok(());
+    }
+    let alerts = reply_rx.await.unwrap_or_default();
+    let reply = if alerts.is_empty() {
+        String::from("You don't have any alerts.")
+    } else {
+        alerts
+            .iter()
+            .map(|alert| format!("`{}` {}", alert.id, alert.description()))
+            .collect::<Vec<_>>()
+            .join("\n")
+    };
+    msg.reply(ctx, reply).await?;
+    Ok(())
+}
+
+/// Tracks your holdings, priced at the latest coingecko poll. `~portfolio add btc 0.5 @ 30000`
+/// records a buy, `~portfolio remove btc [amount]` a sell, `~portfolio export` sends it as CSV
+/// and `~portfolio` on its own summarises it.
+#[cfg(feature = "coingecko")]
+#[command]
+async fn portfolio(ctx: &Context, msg: &Message, args: Args) -> CommandResult {
+    let words: Vec<String> = args.raw().map(str::to_lowercase).collect();
+    let words: Vec<&str> = words.iter().map(String::as_str).collect();
+    let user_id = msg.author.id.0;
+
+    match words.as_slice() {
+        ["add", coin, amount, "@", price] | ["add", coin, amount, price] => {
+            let amount = amount.parse::<f64>().context("Invalid amount")?;
+            let price = price
+                .trim_start_matches('@')
+                .parse::<f64>()
+                .context("Invalid price")?;
+            let (tx, rx) = oneshot::channel();
+            let cmd = PortfolioCommand::Add(user_id, coin.to_string(), amount, price, tx);
+            if !send_command(ctx, Command::Portfolio(cmd)).await {
+                return Ok(());
+            }
+            return reply_with_result(ctx, msg, rx, "Added to your portfolio").await;
+        }
+        ["remove", coin] | ["remove", coin, _] => {
+            let amount = match words.get(2) {
+                Some(amount) => Some(amount.parse::<f64>().context("Invalid amount")?),
+                None => None,
+            };
+            let (tx, rx) = oneshot::channel();
+            let cmd = PortfolioCommand::Remove(user_id, coin.to_string(), amount, tx);
+            if !send_command(ctx, Command::Portfolio(cmd)).await {
+                return Ok(());
+            }
+            return reply_with_result(ctx, msg, rx, "Removed from your portfolio").await;
+        }
+        [] | ["export"] => {}
+        _ => {
+            msg.reply(
+                ctx,
+                "Expected `add <coin> <amount> @ <price>`, `remove <coin> [amount]` or `export`",
+            )
+            .await?;
+            return Ok(());
+        }
+    }
+
+    let (reply_tx, reply_rx) = oneshot::channel();
+    let cmd = PortfolioCommand::Summary(user_id, reply_tx);
+    if !send_command(ctx, Command::Portfolio(cmd)).await {
+        return Ok(());
+    }
+    let summary = match reply_rx.await {
+        Ok(Ok(summary)) => summary,
+        Ok(Err(e)) => {
+            msg.reply(ctx, e).await?;
+            return Ok(());
+        }
+        Err(_) => return Ok(()),
+    };
+    if summary.positions.is_empty() {
+        msg.reply(ctx, "Your portfolio is empty.").await?;
+    } else if words.first() == Some(&"export") {
+        let csv = summary.to_csv();
+        msg.channel_id
+            .send_files(ctx, vec![(csv.as_bytes(), "portfolio.csv")], |m| m)
+            .await?;
+    } else {
+        msg.channel_id
+            .send_message(ctx, |m| {
+                m.embed(|e| {
+                    e.title(format!("{}'s portfolio", msg.author.name))
+                        .description(portfolio_table(&summary))
+                })
+            })
+            .await?;
+    }
+    Ok(())
+}
+
+#[cfg(feature = "coingecko")]
+fn portfolio_table(summary: &PortfolioSummary) -> String {
+    let mut lines = vec!["```css".to_string()];
+    for p in &summary.positions {
+        lines.push(format!(
+            "[{}] {} @ ${} [VALUE] ${:.2} [P&L] {:+.2} ({:+.2}%) [ALLOCATION] {:.1}%",
+            p.position.coin,
+            p.position.amount,
+            p.position.cost_basis,
+            p.value,
+            p.pnl,
+            p.pnl_pct,
+            p.allocation_pct
+        ));
+    }
+    lines.push(format!(
+        "[TOTAL] ${:.2} [P&L] {:+.2} ({:+.2}%)",
+        summary.value,
+        summary.pnl(),
+        summary.pnl_pct()
+    ));
+    lines.push("```".to_string());
+    lines.join("\n")
+}
+
+#[cfg(any(feature = "twitter", feature = "coingecko"))]
+async fn reply_with_result(
+    ctx: &Context,
+    msg: &Message,
+    rx: oneshot::Receiver<Result<(), anyhow::Error>>,
+    success: &str,
+) -> CommandResult {
+    match rx.await {
+        Ok(Ok(())) => msg.reply(ctx, success).await?,
+        Ok(Err(e)) => msg.reply(ctx, e).await?,
+        Err(_) => return Ok(()),
+    };
+    Ok(())
+}
+
+/// Lists who has each capability, or changes it with `~perms grant <capability> <@role|@user>`
+/// and `~perms revoke <capability> <@role|@user>`
+#[command]
+#[only_in(guilds)]
+#[required_permissions("ADMINISTRATOR")]
+async fn perms(ctx: &Context, msg: &Message, args: Args) -> CommandResult {
+    let words: Vec<&str> = args.raw().collect();
+    let permissions = {
+        let data = ctx.data.read().await;
+        Arc::clone(
+            data.get::<Permissions>()
+                .expect("Expected Permissions in TypeMap."),
+        )
+    };
+
+    let (grant, capability, grantee) = match words.as_slice() {
+        [] => {
+            let reply = describe_permissions(&*permissions.read().await);
+            msg.reply(ctx, reply).await?;
+            return Ok(());
+        }
+        ["grant", capability, grantee] => (true, capability, grantee),
+        ["revoke", capability, grantee] => (false, capability, grantee),
+        _ => {
+            msg.reply(
+                ctx,
+                "Expected `grant` or `revoke`, a capability and a mention",
+            )
+            .await?;
+            return Ok(());
+        }
+    };
+    let (capability, grantee) = match (capability.parse::<Capability>(), Grantee::parse(grantee)) {
+        (Ok(capability), Ok(grantee)) => (capability, grantee),
+        (Err(e), _) | (_, Err(e)) => {
+            msg.reply(ctx, e).await?;
+            return Ok(());
+        }
+    };
+
+    let mut permissions = permissions.write().await;
+    let previous = permissions.clone();
+    let changed = if grant {
+        permissions.grant(capability, grantee);
+        true
+    } else {
+        permissions.revoke(capability, grantee)
+    };
+    if !changed {
+        msg.reply(ctx, "That wasn't granted.").await?;
+        return Ok(());
+    }
+    let updated = permissions.clone();
+    let action = format!(
+        "perms {} {} {}",
+        if grant { "grant" } else { "revoke" },
+        capability,
+        words[2]
+    );
+    if let Err(e) = Config::modify(&actor(msg), &action, |config| config.permissions = updated) {
+        // Unsaved changes would be lost on the next restart, so they aren't kept meanwhile
+        tracing::error!("Failed to persist config {}", e);
+        *permissions = previous;
+        msg.reply(ctx, format!("Couldn't save that, {}", e)).await?;
+        return Ok(());
+    }
+    msg.react(ctx, ReactionType::Unicode(String::from("✅")))
+        .await?;
+    Ok(())
+}
+
+/// Shows the most recent config changes, `~audit 20` showing the last twenty
+#[command]
+#[only_in(guilds)]
+async fn audit(ctx: &Context, msg: &Message, mut args: Args) -> CommandResult {
+    let count = args
+        .single::<usize>()
+        .unwrap_or(DEFAULT_AUDIT_RECORDS)
+        .min(MAX_AUDIT_RECORDS);
+    let records = audit::recent(count).context("Failed to read the audit log")?;
+    if records.is_empty() {
+        msg.reply(ctx, "Nothing has been changed yet.").await?;
+        return Ok(());
+    }
+
+    let lines: Vec<String> = records
+        .iter()
+        .map(|record| {
+            let changes: Vec<String> = record
+                .changes
+                .iter()
+                .map(|c| {
+                    let change = format!("  {}: {} -> {}", c.path, c.before, c.after);
+                    change.chars().take(MAX_AUDIT_CHANGE_LEN).collect()
+                })
+                .collect();
+            let mut line = format!(
+                "[{}] {} ({:?}) {}",
+                record.timestamp.format("%Y-%m-%d %H:%M:%S"),
+                record.actor.name,
+                record.actor.source,
+                record.action,
+            );
+            for change in changes {
+                line.push('\n');
+                line.push_str(&change);
+            }
+            line
+        })
+        .collect();
+    msg.channel_id
+        .say(ctx, format!("```css\n{}\n```", audit_contents(&lines)))
+        .await?;
+    Ok(())
+}
+
+/// The newest records that fit in one message, oldest first. The newest is cut short when it
+/// doesn't fit by itself, so there is always something to show.
+fn audit_contents(lines: &[String]) -> String {
+    let mut kept: Vec<&str> = vec![];
+    let mut len = 0;
+    for line in lines.iter().rev() {
+        let line_len = line.chars().count() + 1;
+        if len + line_len > MAX_AUDIT_MESSAGE_LEN {
+            break;
+        }
+        len += line_len;
+        kept.push(line);
+    }
+    if kept.is_empty() {
+        if let Some(newest) = lines.last() {
+            let mut cut: String = newest.chars().take(MAX_AUDIT_MESSAGE_LEN - 3).collect();
+            cut.push_str("...");
+            return cut;
+        }
+    }
+    kept.reverse();
+    kept.join("\n")
+}
+
+/// Who a command was run by, for the audit log
+fn actor(msg: &Message) -> Actor {
+    Actor {
+        source: Source::Discord,
+        id: msg.author.id.to_string(),
+        name: msg.author.tag(),
+    }
+}
+
+fn describe_permissions(permissions: &PermissionsConfig) -> String {
+    Capability::ALL
+        .iter()
+        .map(|capability| {
+            let grant = permissions
+                .capabilities
+                .get(capability)
+                .cloned()
+                .unwrap_or_default();
+            let mut holders: Vec<String> = vec![];
+            if grant.everyone {
+                holders.push(String::from("everyone"));
+            }
+            holders.extend(grant.roles.iter().map(|id| format!("<@&{}>", id)));
+            holders.extend(grant.users.iter().map(|id| format!("<@{}>", id)));
+            if holders.is_empty() {
+                holders.push(String::from("administrators only"));
+            }
+            format!("`{}` {}", capability, holders.join(", "))
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// Stops commands the invoking user lacks the capability for, telling them why, and keeps
+/// an audit trail of every command that needs one
+#[hook]
+async fn check_permissions(ctx: &Context, msg: &Message, command_name: &str) -> bool {
+    let capability = match Capability::for_command(command_name) {
+        Some(capability) => capability,
+        None => return true,
+    };
+    let allowed = has_capability(ctx, msg, capability).await;
+    if let Err(e) = audit::record_command(&actor(msg), &msg.content, allowed) {
+        tracing::error!("Failed to record ~{} in the audit log {}", command_name, e);
+    }
+    if !allowed {
+        let reply = format!(
+            "You need the {} permission to use ~{}",
+            capability, command_name
+        );
+        if let Err(e) = msg.reply(ctx, reply).await {
+            tracing::error!("Failed to report permission denial {}", e);
+        }
+    }
+    allowed
+}
+
+async fn has_capability(ctx: &Context, msg: &Message, capability: Capability) -> bool {
+    let permissions = {
+        let data = ctx.data.read().await;
+        Arc::clone(
+            data.get::<Permissions>()
+                .expect("Expected Permissions in TypeMap."),
+        )
+    };
+    let permissions = permissions.read().await;
+    if permissions.allows(capability, msg.author.id.0, &[]) {
+        return true;
+    }
+
+    // Roles and administrators only count in servers, where the author is a member
+    let member = match msg.member(ctx).await {
+        Ok(member) => member,
+        Err(_) => return false,
+    };
+    let roles: Vec<u64> = member.roles.iter().map(|role| role.0).collect();
+    permissions.allows(capability, msg.author.id.0, &roles)
+        || member
+            .permissions(ctx)
+            .await
+            .map_or(false, |p| p.administrator())
+}
+
+/// Sends a command to the managers, returning whether it was sent
+async fn send_command(ctx: &Context, cmd: Command) -> bool {
     let data = ctx.data.read().await;
     let tx = data
         .get::<CommandSender>()
         .expect("Expected CommandSender in TypeMap.");
 
-    if let Err(e) =
-    tx.0.send(Command::Twitter(TwitterCommand::AddTwitterSubscription(
-        twitter_handle,
-    )))
-        .await
-    {
-        log::error!("Failed to send add twitter sub {}", e)
+    match tx.0.send(cmd).await {
+        Ok(()) => true,
+        Err(e) => {
+            tracing::error!("Failed to send command {}", e);
+            false
+        }
     }
-    Ok(())
 }
 
-impl Manager<DiscordCommand> for DiscordConfig {
-    fn start_manager(
+impl Worker for DiscordConfig {
+    fn start_worker(
         &self,
         config_cloned: Arc<Config>,
-        mut rx: Receiver<DiscordCommand>,
         tx: Sender<Command>,
-    ) {
-        log::info!("Starting discord manager");
-        let _ = tokio::spawn(async move {
+        events: EventBus,
+        mut shutdown: Shutdown,
+    ) -> ManagerHandle {
+        tracing::info!("Starting discord manager");
+        let discord = config_cloned
+            .discord
+            .clone()
+            .unwrap_or_else(|| self.clone());
+        let handler = Handler {
+            events: events.clone(),
+        };
+        let bus = events.clone();
+        let mut events = events.subscribe("discord");
+        tokio::spawn(async move {
+            // Whatever a previous run last reported, this one hasn't connected yet
+            events::publish(&bus, Event::DiscordGateway { connected: false });
             let framework = StandardFramework::new()
                 .configure(|c| c.prefix("~"))
+                .before(check_permissions)
                 .group(&GENERAL_GROUP);
+            #[cfg(feature = "twitter")]
+            let framework = framework.group(&TWITTER_GROUP);
+            #[cfg(feature = "coingecko")]
+            let framework = framework.group(&COINGECKO_GROUP);
 
-            let mut client = Client::builder(config_cloned.discord.token.clone())
-                .event_handler(Handler)
+            let mut client = Client::builder(discord.token.expose())
+                .event_handler(handler)
                 .framework(framework)
                 .await
-                .expect("Error creating client");
-            let http = Http::new_with_token(&config_cloned.discord.token);
+                .context("Error creating client")?;
 
             {
                 let mut data = client.data.write().await;
                 data.insert::<CommandSender>(Arc::new(CommandSender(tx.clone())));
+                // Read from disk rather than the startup config, which misses grants made since
+                let permissions = Config::load()
+                    .map(|config| config.permissions)
+                    .unwrap_or_else(|_| config_cloned.permissions.clone());
+                data.insert::<Permissions>(Arc::new(RwLock::new(permissions)));
             }
 
-            let _client_manager = tokio::spawn(async move {
-                if let Err(why) = client.start().await {
-                    println!("An error occurred while running the client: {:?}", why);
-                }
-            });
+            let shard_manager = client.shard_manager.clone();
+            let mut client_manager = tokio::spawn(async move { client.start().await });
 
-            while let Some(cmd) = rx.recv().await {
-                match cmd {
-                    DiscordCommand::SendTweet(tweet) => {
-                        let user_screen_name = tweet.user.as_ref().unwrap().screen_name.clone();
-                        let tweet_url = format!(
-                            "https://twitter.com/{}/status/{}",
-                            user_screen_name, tweet.id
-                        );
-                        let usr = tweet.user.unwrap();
-                        if let Err(e) = http
-                            .send_message(
-                                config_cloned.discord.channel_id,
-                                &serde_json::json!({
-                                    "content": tweet_url,
-                                    "type": "article",
-                                    "embed": {
-                                        "url": tweet_url,
-                                        "embed": {
-                                            "url": tweet_url,
-                                            "image": {
-                                                "height": 200,
-                                                "width": 200,
-                                                "url": usr.profile_image_url
-                                            },
-                                            "title": usr.name,
-                                            "description": tweet.text,
-                                            "provider": {
-                                                "url": tweet_url,
-                                                "name": "test"
-                                            }
-                                        },
-                                        "title": usr.name,
-                                        "description": tweet.text,
-                                        "provider": {
-                                            "url": tweet_url,
-                                            "name": "test"
-                                        }
-                                    }
-                                }),
-                            )
-                            .await
-                        {
-                            log::error!("Error sending message {}", e)
+            let config = &discord;
+            let mut outbox = Outbox::new(config, reqwest::Client::new());
+            let mut pending_rules = PendingRules::default();
+            loop {
+                tokio::select! {
+                    event = events.recv() => match event {
+                        Some(event) => {
+                            handle_event(config, &mut outbox, &mut pending_rules, event).await
                         }
+                        None => break,
+                    },
+                    res = &mut client_manager => {
+                        return Err(anyhow::anyhow!(
+                            "An error occurred while running the client: {:?}",
+                            res
+                        ));
                     }
-                    DiscordCommand::SendCoingeckoBase(mut coins) => {
-                        let body = &serde_json::json!({
-                            "content": "```css\n - [Coingecko Bot Started!] Sending top 50 coins.. ```",
-                            "type": "article",
-                        });
-                        let message = http
-                            .send_message(config_cloned.discord.channel_id, body)
-                            .await;
-
-                        coins.sort_by(|a, b| a.market_cap_rank.cmp(&b.market_cap_rank));
-
-                        for market in coins.chunks(20).take(10) {
-                            let mut contents = vec!["```css\n".to_string()];
-
-                            market.iter().for_each(|market| {
-                                contents.push(format!("[{}] {} [CURRENT_PRICE] ${} [MARKET_CAP] ${}", market.market_cap_rank, market.id, market.current_price, market.market_cap.to_formatted_string(&Locale::en)))
-                            });
-
-                            contents.push("```".to_string());
-
-                            let body = &serde_json::json!({
-                                "content": contents.join("\n"),
-                                "type": "article"
-                            });
-                            http.send_message(config_cloned.discord.channel_id, body)
-                                .await;
-                        }
-                        if let Err(e) = message {
-                            log::error!("Error sending coin state {}", e)
+                    _ = shutdown.wait() => {
+                        // Send whatever was published before the shutdown rather than dropping it
+                        while let Some(Some(event)) = events.recv().now_or_never() {
+                            handle_event(config, &mut outbox, &mut pending_rules, event).await
                         }
+                        break;
                     }
-                    // DiscordCommand::SendCoingeckoPriceIncrease(m) => {
-                    //     let body = &serde_json::json!({
-                    //         "content": "",
-                    //         "type": "article",
-                    //         "embed": {
-                    //             "url": "https://coingecko.com",
-                    //             "title": m.id,
-                    //             "description": format!("{} raises its market price by at least TODO percent!", m.id),
-                    //             "image": {
-                    //                 "height": 150,
-                    //                 "width": 150,
-                    //                 "url": m.image
-                    //             }
-                    //         }
-                    //     });
-                    //     let message = http
-                    //         .send_message(config_cloned.discord.channel_id, body)
-                    //         .await;
-                    //     if let Err(e) = message {
-                    //         log::error!("Error sending market increase {}", e)
-                    //     }
-                    // }
-                    // DiscordCommand::SendCoingeckoRankIncrease(m, previous) => {
-                    //     let body = &serde_json::json!({
-                    //         "content": "",
-                    //         "type": "article",
-                    //         "embed": {
-                    //             "url": "https://coingecko.com",
-                    //             "title": m.id,
-                    //             "description": format!("{} has risen up to the rank of {}, previous rank {}", m.id, m.market_cap_rank, previous),
-                    //             "image": {
-                    //                 "height": 150,
-                    //                 "width": 150,
-                    //                 "url": m.image
-                    //             }
-                    //         }
-                    //     });
-                    //     let message = http
-                    //         .send_message(config_cloned.discord.channel_id, body)
-                    //         .await;
-                    //     if let Err(e) = message {
-                    //         log::error!("Error sending market rank increase {}", e)
-                    //     }
-                    // }
-                    DiscordCommand::SendCoingeckoRuleResult(res) => {
-                        let body = match res {
-                            RuleResult::Percent(is_positive, m, diff) => {
-                                let pos_msg = if is_positive {
-                                    "has risen by"
-                                } else {
-                                    "has declined by"
-                                };
-                                serde_json::json!({
-                                    "content": "",
-                                    "type": "article",
-                                    "embed": {
-                                        "url": "https://coingecko.com",
-                                        "title": m.id,
-                                        "description": format!("This crypto {} {}%", pos_msg, diff),
-                                        "image": {
-                                            "height": 150,
-                                            "width": 150,
-                                            "url": m.image
-                                        }
-                                    }
-                                })
-                            }
-                            RuleResult::Rank(is_positive, m, ranks) => {
-                                let pos_msg = if is_positive {
-                                    "has risen"
-                                } else {
-                                    "has declined"
-                                };
-                                serde_json::json!({
-                                    "content": "",
-                                    "type": "article",
-                                    "embed": {
-                                        "url": "https://coingecko.com",
-                                        "title": m.id,
-                                        "description": format!("{} {} ranks to the rank of {}", pos_msg, ranks, m.market_cap_rank),
-                                        "image": {
-                                            "height": 150,
-                                            "width": 150,
-                                            "url": m.image
-                                        }
-                                    }
-                                })
+                }
+            }
+
+            #[cfg(feature = "coingecko")]
+            send_rules(config, &mut outbox, &mut pending_rules).await;
+            outbox.close().await;
+            shard_manager.lock().await.shutdown_all().await;
+            Ok(())
+        })
+    }
+}
+
+#[derive(Deserialize, Debug)]
+struct RateLimited {
+    retry_after: f64,
+}
+
+#[cfg(feature = "coingecko")]
+#[derive(Deserialize, Debug)]
+struct DmChannel {
+    id: String,
+}
+
+/// Posts messages through the discord API in the order they were queued, with a worker per channel
+/// so a rate limited channel holds up only its own messages. Messages are retried after the
+/// `retry_after` discord gives on a 429 rather than dropped.
+struct Outbox {
+    client: reqwest::Client,
+    api_base: String,
+    token: String,
+    /// Each message is queued with the span it was pushed in, so its delivery is logged there
+    channels: HashMap<u64, (mpsc::Sender<(serde_json::Value, Span)>, JoinHandle<()>)>,
+    #[cfg(feature = "coingecko")]
+    dm_channels: HashMap<u64, u64>,
+}
+
+impl Outbox {
+    fn new(config: &DiscordConfig, client: reqwest::Client) -> Outbox {
+        Outbox {
+            client,
+            api_base: config.api_base.trim_end_matches('/').to_string(),
+            token: config.token.expose().to_string(),
+            channels: HashMap::new(),
+            #[cfg(feature = "coingecko")]
+            dm_channels: HashMap::new(),
+        }
+    }
+
+    /// The id of the direct message channel with a user, opening it the first time
+    #[cfg(feature = "coingecko")]
+    async fn dm_channel(&mut self, user_id: u64) -> Result<u64, anyhow::Error> {
+        if let Some(channel_id) = self.dm_channels.get(&user_id) {
+            return Ok(*channel_id);
+        }
+        let channel: DmChannel = self
+            .client
+            .post(format!("{}/users/@me/channels", self.api_base))
+            .header(
+                reqwest::header::AUTHORIZATION,
+                format!("Bot {}", self.token),
+            )
+            .json(&serde_json::json!({ "recipient_id": user_id.to_string() }))
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+        let channel_id = channel.id.parse()?;
+        self.dm_channels.insert(user_id, channel_id);
+        Ok(channel_id)
+    }
+
+    async fn push(&mut self, channel_id: u64, body: serde_json::Value) {
+        let (client, api_base, token) = (&self.client, &self.api_base, &self.token);
+        let (queue, _) = self.channels.entry(channel_id).or_insert_with(|| {
+            let (queue, mut rx) = mpsc::channel::<(serde_json::Value, Span)>(OUTBOX_CAPACITY);
+            let client = client.clone();
+            let url = format!("{}/channels/{}/messages", api_base, channel_id);
+            let authorization = format!("Bot {}", token);
+            let worker = tokio::spawn(async move {
+                while let Some((body, span)) = rx.recv().await {
+                    async {
+                        match post_message(&client, &url, &authorization, &body).await {
+                            Ok(()) => tracing::debug!("Sent discord message to {}", channel_id),
+                            Err(e) => {
+                                metrics::DISCORD_SEND_ERRORS.inc();
+                                tracing::error!(
+                                    "Failed to send discord message to {} {}",
+                                    channel_id,
+                                    e
+                                )
                             }
-                        };
+                        }
+                    }
+                    .instrument(span)
+                    .await
+                }
+            });
+            (queue, worker)
+        });
+        if let Err(e) = queue.send((body, Span::current())).await {
+            tracing::error!("Failed to queue discord message {}", e)
+        }
+    }
+
+    /// Waits for every queued message to be sent
+    async fn close(self) {
+        for (_, (queue, worker)) in self.channels {
+            drop(queue);
+            let _ = worker.await;
+        }
+    }
+}
+
+async fn post_message(
+    client: &reqwest::Client,
+    url: &str,
+    authorization: &str,
+    body: &serde_json::Value,
+) -> Result<(), anyhow::Error> {
+    for _ in 0..MAX_RATE_LIMIT_RETRIES {
+        let res = client
+            .post(url)
+            .header(reqwest::header::AUTHORIZATION, authorization)
+            .json(body)
+            .send()
+            .await?;
+        if res.status() != reqwest::StatusCode::TOO_MANY_REQUESTS {
+            res.error_for_status()?;
+            return Ok(());
+        }
+        metrics::DISCORD_RATE_LIMITS.inc();
+        let retry_after = res
+            .json::<RateLimited>()
+            .await
+            .map(|limit| limit.retry_after)
+            .unwrap_or(1.0);
+        tracing::warn!("Rate limited by discord, retrying in {}s", retry_after);
+        tokio::time::sleep(tokio::time::Duration::from_secs_f64(retry_after)).await;
+    }
+    Err(anyhow::anyhow!(
+        "Still rate limited after {} retries",
+        MAX_RATE_LIMIT_RETRIES
+    ))
+}
+
+/// Rule results from the current poll, held back until its snapshot arrives
+#[derive(Default)]
+struct PendingRules(#[cfg(feature = "coingecko")] Vec<RuleResult>);
+
+#[cfg_attr(not(feature = "coingecko"), allow(unused_variables))]
+async fn handle_event(
+    config: &DiscordConfig,
+    outbox: &mut Outbox,
+    pending_rules: &mut PendingRules,
+    event: Event,
+) {
+    let span = event.span();
+    async move {
+        match event {
+            #[cfg(feature = "coingecko")]
+            Event::RuleTriggered(res) => pending_rules.0.push(res),
+            #[cfg(feature = "coingecko")]
+            Event::AlertTriggered(alert, market) => match outbox.dm_channel(alert.user_id).await {
+                Ok(channel_id) => {
+                    outbox
+                        .push(channel_id, alert_message(&alert, &market))
+                        .await
+                }
+                Err(e) => tracing::error!("Failed to open a DM with {} {}", alert.user_id, e),
+            },
+            event => {
+                // Coingecko publishes a snapshot once it has published every rule result of a poll
+                #[cfg(feature = "coingecko")]
+                if let Event::MarketSnapshot { .. } = event {
+                    send_rules(config, outbox, pending_rules).await;
+                }
+                for body in messages(&event) {
+                    outbox.push(config.channel_id, body).await;
+                }
+            }
+        }
+    }
+    .instrument(span)
+    .await
+}
+
+/// Sends the rule results of a poll, summarised in one message when there are more than the threshold
+#[cfg(feature = "coingecko")]
+async fn send_rules(config: &DiscordConfig, outbox: &mut Outbox, pending_rules: &mut PendingRules) {
+    let pending_rules = &mut pending_rules.0;
+    if pending_rules.len() > config.coalesce_threshold {
+        let span = tracing::info_span!("rules", count = pending_rules.len());
+        outbox
+            .push(config.channel_id, rules_summary(pending_rules))
+            .instrument(span)
+            .await;
+    } else {
+        for res in pending_rules.iter() {
+            outbox
+                .push(config.channel_id, rule_message(res))
+                .instrument(events::rule_span(res))
+                .await;
+        }
+    }
+    pending_rules.clear();
+}
 
-                        let message = http
-                            .send_message(config_cloned.discord.channel_id, &body)
-                            .await;
-                        if let Err(e) = message {
-                            log::error!("Error sending rule result {}", e)
+#[cfg(feature = "coingecko")]
+fn rules_summary(results: &[RuleResult]) -> serde_json::Value {
+    let mut description = String::new();
+    for (i, res) in results.iter().enumerate() {
+        let line = format!("**{}** {}\n", res.market().id, res.description());
+        if description.len() + line.len() > MAX_EMBED_DESCRIPTION - 32 {
+            description.push_str(&format!("...and {} more", results.len() - i));
+            break;
+        }
+        description.push_str(&line);
+    }
+    serde_json::json!({
+        "content": "",
+        "type": "article",
+        "embed": {
+            "url": "https://coingecko.com",
+            "title": format!("{} rules triggered", results.len()),
+            "description": description,
+        }
+    })
+}
+
+/// The messages an event is posted to the channel as
+fn messages(event: &Event) -> Vec<serde_json::Value> {
+    match event {
+        #[cfg(feature = "twitter")]
+        Event::TweetReceived(tweet) => {
+            let tweet_url = tweet.url();
+            vec![serde_json::json!({
+                "content": tweet_url,
+                "type": "article",
+                "embed": {
+                    "url": tweet_url,
+                    "embed": {
+                        "url": tweet_url,
+                        "image": {
+                            "height": 200,
+                            "width": 200,
+                            "url": tweet.profile_image_url
+                        },
+                        "title": tweet.name,
+                        "description": tweet.text,
+                        "provider": {
+                            "url": tweet_url,
+                            "name": "test"
                         }
+                    },
+                    "title": tweet.name,
+                    "description": tweet.text,
+                    "provider": {
+                        "url": tweet_url,
+                        "name": "test"
                     }
                 }
+            })]
+        }
+        Event::FeedItemPublished(item) => vec![serde_json::json!({
+            "content": "",
+            "type": "article",
+            "embed": {
+                "url": item.link,
+                "title": item.title,
+                "description": item.summary,
+                "author": {
+                    "name": item.feed_title
+                }
+            }
+        })],
+        Event::RedditPostFound(post) => {
+            let mut embed = serde_json::json!({
+                "url": post.url(),
+                "title": post.title,
+                "description": format!("Posted by u/{} with a score of {}", post.author, post.score),
+                "author": {
+                    "name": format!("r/{}", post.subreddit)
+                }
+            });
+            if let Some(flair) = &post.link_flair_text {
+                embed["footer"] = serde_json::json!({ "text": flair });
+            }
+            if post.thumbnail.starts_with("http") {
+                embed["thumbnail"] = serde_json::json!({ "url": post.thumbnail });
+            }
+            vec![serde_json::json!({
+                "content": "",
+                "type": "article",
+                "embed": embed
+            })]
+        }
+        #[cfg(feature = "coingecko")]
+        Event::MarketSnapshot {
+            markets,
+            first: true,
+        } => {
+            let mut coins = markets.to_vec();
+            coins.sort_by(|a, b| a.market_cap_rank.cmp(&b.market_cap_rank));
+            coins.truncate(200);
+            coin_list(
+                "```css\n - [Coingecko Bot Started!] Sending top 50 coins.. ```",
+                &coins,
+            )
+        }
+        #[cfg(feature = "coingecko")]
+        Event::TopCoins(markets) => coin_list(
+            &format!("```css\n - [Top {} coins] ```", markets.len()),
+            markets,
+        ),
+        #[cfg(feature = "coingecko")]
+        Event::MarketDigest(digest) => {
+            let fields: Vec<serde_json::Value> = digest
+                .sections()
+                .into_iter()
+                .map(|(name, value)| {
+                    serde_json::json!({
+                        "name": name,
+                        "value": value,
+                        "inline": true
+                    })
+                })
+                .collect();
+            vec![serde_json::json!({
+                "content": "",
+                "type": "article",
+                "embed": {
+                    "url": "https://coingecko.com",
+                    "title": format!("{} market digest", digest.name),
+                    "description": format!("Since {}", digest.since.format("%Y-%m-%d %H:%M UTC")),
+                    "fields": fields
+                }
+            })]
+        }
+        #[cfg(feature = "coingecko")]
+        Event::RuleTriggered(res) => vec![rule_message(res)],
+        _ => vec![],
+    }
+}
+
+#[cfg(feature = "coingecko")]
+fn alert_message(alert: &PriceAlert, market: &Market) -> serde_json::Value {
+    serde_json::json!({
+        "content": "",
+        "type": "article",
+        "embed": {
+            "url": format!("https://www.coingecko.com/en/coins/{}", market.id),
+            "title": format!("Alert {} triggered", alert.id),
+            "description": format!("{}, it's now ${}", alert.description(), market.current_price),
+            "image": {
+                "height": 150,
+                "width": 150,
+                "url": market.image
             }
+        }
+    })
+}
+
+/// A header message followed by the coins in blocks of twenty
+#[cfg(feature = "coingecko")]
+fn coin_list(header: &str, coins: &[Market]) -> Vec<serde_json::Value> {
+    let mut messages = vec![serde_json::json!({
+        "content": header,
+        "type": "article",
+    })];
+
+    for market in coins.chunks(20) {
+        let mut contents = vec!["```css\n".to_string()];
+
+        market.iter().for_each(|market| {
+            contents.push(format!(
+                "[{}] {} [CURRENT_PRICE] ${} [MARKET_CAP] ${}",
+                market.market_cap_rank,
+                market.id,
+                market.current_price,
+                market.market_cap.to_formatted_string(&Locale::en)
+            ))
         });
+
+        contents.push("```".to_string());
+
+        messages.push(serde_json::json!({
+            "content": contents.join("\n"),
+            "type": "article"
+        }));
+    }
+    messages
+}
+
+#[cfg(feature = "coingecko")]
+fn rule_message(res: &RuleResult) -> serde_json::Value {
+    match res {
+        RuleResult::Percent(is_positive, m, diff) => {
+            let pos_msg = if *is_positive {
+                "has risen by"
+            } else {
+                "has declined by"
+            };
+            serde_json::json!({
+                "content": "",
+                "type": "article",
+                "embed": {
+                    "url": "https://coingecko.com",
+                    "title": m.id,
+                    "description": format!("This crypto {} {}%", pos_msg, diff),
+                    "image": {
+                        "height": 150,
+                        "width": 150,
+                        "url": m.image
+                    }
+                }
+            })
+        }
+        RuleResult::Rank(is_positive, m, ranks) => {
+            let pos_msg = if *is_positive {
+                "has risen"
+            } else {
+                "has declined"
+            };
+            serde_json::json!({
+                "content": "",
+                "type": "article",
+                "embed": {
+                    "url": "https://coingecko.com",
+                    "title": m.id,
+                    "description": format!("{} {} ranks to the rank of {}", pos_msg, ranks, m.market_cap_rank),
+                    "image": {
+                        "height": 150,
+                        "width": 150,
+                        "url": m.image
+                    }
+                }
+            })
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #[cfg(feature = "twitter")]
+    use futures::{future, TryStreamExt};
+    #[cfg(any(feature = "twitter", feature = "coingecko"))]
+    use serde_json::json;
+    #[cfg(any(feature = "twitter", feature = "coingecko"))]
+    use warp::http::{Method, StatusCode};
+
+    use super::*;
+    #[cfg(feature = "coingecko")]
+    use crate::gecko::{self, CoingeckoConfig, MarketFeed, Rule};
+    #[cfg(any(feature = "twitter", feature = "coingecko"))]
+    use crate::harness::{self, MockServer, Recorded};
+    #[cfg(feature = "twitter")]
+    use crate::twitter::{v2::V2Stream, StreamItem, Tweet, TwitterConfig, TwitterStream};
+
+    #[cfg(any(feature = "twitter", feature = "coingecko"))]
+    const MESSAGES_PATH: &str = "/channels/42/messages";
+
+    #[cfg(any(feature = "twitter", feature = "coingecko"))]
+    fn config(api_base: &str) -> DiscordConfig {
+        serde_json::from_value(json!({ "channel_id": 42, "api_base": api_base })).unwrap()
+    }
+
+    /// Handles the events as the manager would, waiting for every message to be posted
+    #[cfg(any(feature = "twitter", feature = "coingecko"))]
+    async fn deliver(config: &DiscordConfig, events: Vec<Event>) {
+        let mut outbox = Outbox::new(config, reqwest::Client::new());
+        let mut pending_rules = PendingRules::default();
+        for event in events {
+            handle_event(config, &mut outbox, &mut pending_rules, event).await;
+        }
+        outbox.close().await;
+    }
+
+    #[cfg(feature = "twitter")]
+    fn tweet_message(screen_name: &str, id: &str, text: &str) -> serde_json::Value {
+        let url = format!("https://twitter.com/{}/status/{}", screen_name, id);
+        let embed = json!({
+            "url": url,
+            "image": {
+                "height": 200,
+                "width": 200,
+                "url": format!("https://pbs.twimg.com/{}.jpg", screen_name)
+            },
+            "title": screen_name,
+            "description": text,
+            "provider": { "url": url, "name": "test" }
+        });
+        json!({
+            "content": url,
+            "type": "article",
+            "embed": {
+                "url": url,
+                "embed": embed,
+                "title": screen_name,
+                "description": text,
+                "provider": { "url": url, "name": "test" }
+            }
+        })
+    }
+
+    #[cfg(feature = "twitter")]
+    #[tokio::test]
+    async fn tweets_from_the_stream_are_posted_as_embeds() {
+        let twitter = MockServer::new()
+            .respond(
+                Method::GET,
+                "/2/tweets/search/stream",
+                StatusCode::OK,
+                &harness::tweet_stream(&[
+                    (
+                        "1400000000000000001",
+                        "Auctions are live",
+                        "33625805",
+                        "Polkadot",
+                    ),
+                    (
+                        "1400000000000000002",
+                        "Block 1000000",
+                        "1042351",
+                        "kusamanetwork",
+                    ),
+                ]),
+            )
+            .start();
+        let discord = MockServer::new()
+            .respond(Method::POST, MESSAGES_PATH, StatusCode::OK, "{}")
+            .start();
+
+        let twitter_config = TwitterConfig {
+            api_base: twitter.base.clone(),
+            ..Default::default()
+        };
+        let tweets: Vec<Event> = V2Stream::new(&twitter_config, reqwest::Client::new())
+            .connect()
+            .await
+            .unwrap()
+            .try_filter_map(|item| {
+                future::ready(Ok(match item {
+                    StreamItem::Tweet(tweet) => Some(Event::TweetReceived(tweet)),
+                    StreamItem::KeepAlive => None,
+                }))
+            })
+            .try_collect()
+            .await
+            .unwrap();
+        deliver(&config(&discord.base), tweets).await;
+
+        let posted: Vec<_> = discord
+            .requests_to(MESSAGES_PATH)
+            .iter()
+            .map(Recorded::json)
+            .collect();
+        assert_eq!(
+            posted,
+            vec![
+                tweet_message("Polkadot", "1400000000000000001", "Auctions are live"),
+                tweet_message("kusamanetwork", "1400000000000000002", "Block 1000000"),
+            ]
+        );
+    }
+
+    #[cfg(feature = "coingecko")]
+    #[tokio::test]
+    async fn rules_between_polls_are_posted_once_the_snapshot_arrives() {
+        let coingecko = MockServer::new()
+            .respond(
+                Method::GET,
+                "/coins/markets",
+                StatusCode::OK,
+                &harness::markets(&[("bitcoin", 100.0, 1), ("ethereum", 50.0, 2)]),
+            )
+            .respond(
+                Method::GET,
+                "/coins/markets",
+                StatusCode::OK,
+                &harness::markets(&[("bitcoin", 125.0, 1), ("ethereum", 49.0, 2)]),
+            )
+            .start();
+        let discord = MockServer::new()
+            .respond(Method::POST, MESSAGES_PATH, StatusCode::OK, "{}")
+            .start();
+
+        let coingecko_config = CoingeckoConfig {
+            api_base: coingecko.base.clone(),
+            rules: vec![Rule::PositivePercent(5.0), Rule::NegativePercent(-5.0)],
+            ..Default::default()
+        };
+        let feed = MarketFeed::new(&coingecko_config, reqwest::Client::new());
+        let first = feed.fetch().await.unwrap();
+        let second = feed.fetch().await.unwrap();
+
+        let bus = EventBus::new();
+        let mut rx = bus.subscribe("test");
+        gecko::compare_state(&bus, &first, &second, &coingecko_config);
+        events::publish(
+            &bus,
+            Event::MarketSnapshot {
+                markets: Arc::new(second),
+                first: false,
+            },
+        );
+        deliver(&config(&discord.base), events::drain(&mut rx)).await;
+
+        assert_eq!(
+            coingecko.requests_to("/coins/markets")[0].query,
+            "vs_currency=usd&order=market_cap_desc&per_page=250"
+        );
+        let posted: Vec<_> = discord
+            .requests_to(MESSAGES_PATH)
+            .iter()
+            .map(Recorded::json)
+            .collect();
+        assert_eq!(
+            posted,
+            vec![json!({
+                "content": "",
+                "type": "article",
+                "embed": {
+                    "url": "https://coingecko.com",
+                    "title": "bitcoin",
+                    "description": "This crypto has risen by 25%",
+                    "image": {
+                        "height": 150,
+                        "width": 150,
+                        "url": "https://assets.coingecko.com/coins/images/bitcoin.png"
+                    }
+                }
+            })]
+        );
+    }
+
+    #[cfg(feature = "twitter")]
+    #[tokio::test]
+    async fn rate_limited_messages_are_retried() {
+        let discord = MockServer::new()
+            .respond(
+                Method::POST,
+                MESSAGES_PATH,
+                StatusCode::TOO_MANY_REQUESTS,
+                r#"{"message": "You are being rate limited.", "retry_after": 0.01, "global": false}"#,
+            )
+            .respond(Method::POST, MESSAGES_PATH, StatusCode::OK, "{}")
+            .start();
+
+        let tweet = Tweet {
+            id: 1400000000000000001,
+            text: String::from("Auctions are live"),
+            user_id: 33625805,
+            screen_name: String::from("Polkadot"),
+            name: String::from("Polkadot"),
+            profile_image_url: String::from("https://pbs.twimg.com/Polkadot.jpg"),
+        };
+        deliver(&config(&discord.base), vec![Event::TweetReceived(tweet)]).await;
+
+        let expected = tweet_message("Polkadot", "1400000000000000001", "Auctions are live");
+        let posted: Vec<_> = discord
+            .requests_to(MESSAGES_PATH)
+            .iter()
+            .map(Recorded::json)
+            .collect();
+        assert_eq!(posted, vec![expected.clone(), expected]);
+    }
+
+    #[test]
+    fn audit_keeps_the_newest_records_that_fit() {
+        let lines: Vec<String> = (0..30)
+            .map(|i| format!("{:02}{}", i, "x".repeat(98)))
+            .collect();
+
+        let contents = audit_contents(&lines);
+
+        assert!(contents.chars().count() <= MAX_AUDIT_MESSAGE_LEN);
+        assert!(contents.starts_with("12"));
+        assert!(contents.ends_with(&lines[29]));
+    }
+
+    #[test]
+    fn audit_cuts_short_a_newest_record_too_long_to_fit() {
+        let lines = vec![String::from("older"), "y".repeat(MAX_AUDIT_MESSAGE_LEN * 2)];
+
+        let contents = audit_contents(&lines);
+
+        assert_eq!(contents.chars().count(), MAX_AUDIT_MESSAGE_LEN);
+        assert!(contents.starts_with('y'));
+        assert!(contents.ends_with("..."));
     }
 }
@@ -0,0 +1,313 @@
+use std::sync::{Arc, Mutex};
+
+use chrono::{DateTime, Utc};
+#[cfg(feature = "coingecko")]
+use coingecko_tokio::Market;
+#[cfg(test)]
+use futures::FutureExt;
+use serde::Serialize;
+use tokio::sync::mpsc::{self, error::TrySendError};
+
+#[cfg(feature = "twitter")]
+use crate::twitter::Tweet;
+#[cfg(feature = "coingecko")]
+use crate::{
+    alert::PriceAlert,
+    gecko::{MarketDigest, RuleResult},
+};
+use crate::{metrics, reddit::RedditPost, rss::FeedItem, storage};
+
+/// How many events can wait for a subscriber before it starts missing them
+const SUBSCRIBER_CAPACITY: usize = 256;
+const LOG_PATH: &str = "events.jsonl";
+
+/// Something that happened in one of the sources we follow, published for every sink to
+/// present however it likes. Producers don't know or care who is listening.
+#[derive(Clone)]
+pub enum Event {
+    #[cfg(feature = "twitter")]
+    TweetReceived(Tweet),
+    #[cfg(feature = "coingecko")]
+    RuleTriggered(RuleResult),
+    /// The market state from a coingecko poll, `first` being the one taken when the bot started
+    #[cfg(feature = "coingecko")]
+    MarketSnapshot {
+        markets: Arc<Vec<Market>>,
+        first: bool,
+    },
+    /// The top coins by market cap, posted on request of a scheduled job
+    #[cfg(feature = "coingecko")]
+    TopCoins(Arc<Vec<Market>>),
+    /// A scheduled summary of how the market moved over the digest's period
+    #[cfg(feature = "coingecko")]
+    MarketDigest(MarketDigest),
+    /// A user's price alert fired, along with the market that fired it
+    #[cfg(feature = "coingecko")]
+    AlertTriggered(PriceAlert, Market),
+    FeedItemPublished(FeedItem),
+    RedditPostFound(RedditPost),
+    /// The discord gateway connected or dropped
+    DiscordGateway {
+        connected: bool,
+    },
+    /// The twitter stream showed it was still open without delivering a tweet
+    TwitterKeepAlive,
+    /// The scheduler (re)started without the jobs managers registered with it
+    SchedulerStarted,
+}
+
+impl Event {
+    /// A span carrying what identifies the event, entered wherever it is handled so one tweet,
+    /// rule or alert can be followed from its source to each sink delivering it
+    pub fn span(&self) -> tracing::Span {
+        match self {
+            #[cfg(feature = "twitter")]
+            Event::TweetReceived(tweet) => tweet_span(tweet),
+            #[cfg(feature = "coingecko")]
+            Event::RuleTriggered(res) => rule_span(res),
+            #[cfg(feature = "coingecko")]
+            Event::AlertTriggered(alert, _) => {
+                tracing::info_span!("alert", alert_id = alert.id, coin = %alert.coin)
+            }
+            _ => tracing::Span::none(),
+        }
+    }
+
+    /// The variant's name, for telling events apart in the stored history
+    pub fn kind(&self) -> &'static str {
+        match self {
+            #[cfg(feature = "twitter")]
+            Event::TweetReceived(_) => "tweet_received",
+            #[cfg(feature = "coingecko")]
+            Event::RuleTriggered(_) => "rule_triggered",
+            #[cfg(feature = "coingecko")]
+            Event::MarketSnapshot { .. } => "market_snapshot",
+            #[cfg(feature = "coingecko")]
+            Event::TopCoins(_) => "top_coins",
+            #[cfg(feature = "coingecko")]
+            Event::MarketDigest(_) => "market_digest",
+            #[cfg(feature = "coingecko")]
+            Event::AlertTriggered(..) => "alert_triggered",
+            Event::FeedItemPublished(_) => "feed_item_published",
+            Event::RedditPostFound(_) => "reddit_post_found",
+            Event::DiscordGateway { .. } => "discord_gateway",
+            Event::TwitterKeepAlive => "twitter_keep_alive",
+            Event::SchedulerStarted => "scheduler_started",
+        }
+    }
+
+    pub fn summary(&self) -> String {
+        match self {
+            #[cfg(feature = "twitter")]
+            Event::TweetReceived(tweet) => {
+                format!("Tweet {} from @{}", tweet.id, tweet.screen_name)
+            }
+            #[cfg(feature = "coingecko")]
+            Event::RuleTriggered(res) => format!(
+                "Rule triggered for {}: {}",
+                res.market().id,
+                res.description()
+            ),
+            #[cfg(feature = "coingecko")]
+            Event::MarketSnapshot { markets, .. } => {
+                format!("Market snapshot of {} coins", markets.len())
+            }
+            #[cfg(feature = "coingecko")]
+            Event::TopCoins(markets) => format!("Top {} coins", markets.len()),
+            #[cfg(feature = "coingecko")]
+            Event::MarketDigest(digest) => format!("Market digest {}", digest.name),
+            #[cfg(feature = "coingecko")]
+            Event::AlertTriggered(alert, _) => {
+                format!("Alert {} triggered: {}", alert.id, alert.description())
+            }
+            Event::FeedItemPublished(item) => {
+                format!("Feed item \"{}\" from {}", item.title, item.feed_title)
+            }
+            Event::RedditPostFound(post) => {
+                format!("Reddit post {} in r/{}", post.id, post.subreddit)
+            }
+            Event::DiscordGateway { connected: true } => String::from("Discord gateway connected"),
+            Event::DiscordGateway { connected: false } => {
+                String::from("Discord gateway disconnected")
+            }
+            Event::TwitterKeepAlive => String::from("Twitter keep-alive"),
+            Event::SchedulerStarted => String::from("Scheduler started"),
+        }
+    }
+}
+
+#[cfg(feature = "twitter")]
+pub fn tweet_span(tweet: &Tweet) -> tracing::Span {
+    tracing::info_span!("tweet", tweet_id = tweet.id, handle = %tweet.screen_name)
+}
+
+#[cfg(feature = "coingecko")]
+pub fn rule_span(res: &RuleResult) -> tracing::Span {
+    tracing::info_span!("rule", coin = %res.market().id, rule = metrics::rule_label(res))
+}
+
+/// Hands every published event to each subscriber's own bounded queue. A subscriber that falls
+/// behind misses the events that don't fit in its queue, so publishers never wait on a sink.
+#[derive(Clone, Default)]
+pub struct EventBus {
+    subscribers: Arc<Mutex<Subscribers>>,
+}
+
+#[derive(Default)]
+struct Subscribers {
+    next_id: u64,
+    queues: Vec<(u64, &'static str, mpsc::Sender<Event>)>,
+}
+
+impl EventBus {
+    pub fn new() -> EventBus {
+        EventBus::default()
+    }
+
+    /// A queue of every event published from now on, named for its depth metric
+    pub fn subscribe(&self, name: &'static str) -> mpsc::Receiver<Event> {
+        let (tx, rx) = mpsc::channel(SUBSCRIBER_CAPACITY);
+        let mut subscribers = self.subscribers.lock().unwrap();
+        let id = subscribers.next_id;
+        subscribers.next_id += 1;
+        subscribers.queues.push((id, name, tx));
+        rx
+    }
+}
+
+/// Queues an event for every subscriber, dropping it for any whose queue is full. Subscribers
+/// that have gone away, such as a manager that died, are dropped from the bus.
+pub fn publish(bus: &EventBus, event: Event) {
+    let mut subscribers = bus.subscribers.lock().unwrap();
+    let mut closed = vec![];
+    for (id, name, tx) in &subscribers.queues {
+        match tx.try_send(event.clone()) {
+            Ok(()) => {}
+            Err(TrySendError::Full(_)) => {
+                metrics::EVENTS_DROPPED.with_label_values(&[name]).inc();
+                tracing::warn!(
+                    "The {} subscriber is behind, dropping {}",
+                    name,
+                    event.kind()
+                );
+            }
+            Err(TrySendError::Closed(_)) => {
+                closed.push(*id);
+                continue;
+            }
+        }
+        metrics::EVENT_QUEUE_DEPTH
+            .with_label_values(&[name])
+            .set((SUBSCRIBER_CAPACITY - tx.capacity()) as i64);
+    }
+    if !closed.is_empty() {
+        subscribers.queues.retain(|(id, ..)| !closed.contains(id));
+    }
+}
+
+/// Every event already queued for a subscriber, without waiting for more
+#[cfg(test)]
+pub fn drain(rx: &mut mpsc::Receiver<Event>) -> Vec<Event> {
+    std::iter::from_fn(|| rx.recv().now_or_never().flatten()).collect()
+}
+
+/// Logs every event published on the bus
+pub fn start_log_sink(bus: &EventBus) {
+    let mut rx = bus.subscribe("log");
+    let _ = tokio::spawn(async move {
+        while let Some(event) = rx.recv().await {
+            event.span().in_scope(|| match event {
+                // These arrive every few seconds and only matter when they stop
+                Event::TwitterKeepAlive => tracing::debug!("{}", event.summary()),
+                _ => tracing::info!("{}", event.summary()),
+            });
+        }
+    });
+}
+
+/// An event as kept in the history on disk
+#[derive(Serialize, Debug)]
+struct EventRecord {
+    timestamp: DateTime<Utc>,
+    kind: &'static str,
+    summary: String,
+}
+
+/// Appends every event but the keep-alives to the history on disk
+pub fn start_storage_sink(bus: &EventBus) {
+    let mut rx = bus.subscribe("storage");
+    let _ = tokio::spawn(async move {
+        while let Some(event) = rx.recv().await {
+            if let Event::TwitterKeepAlive = event {
+                continue;
+            }
+            let record = EventRecord {
+                timestamp: Utc::now(),
+                kind: event.kind(),
+                summary: event.summary(),
+            };
+            let appended = tokio::task::spawn_blocking(move || storage::append(LOG_PATH, &record));
+            if let Err(e) = appended.await.map_err(anyhow::Error::from).and_then(|r| r) {
+                event
+                    .span()
+                    .in_scope(|| tracing::error!("Failed to store event {}", e));
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn every_subscriber_gets_every_event_in_order() {
+        let bus = EventBus::new();
+        let mut first = bus.subscribe("first");
+        let mut second = bus.subscribe("second");
+
+        publish(&bus, Event::TwitterKeepAlive);
+        publish(&bus, Event::DiscordGateway { connected: true });
+
+        for rx in [&mut first, &mut second].iter_mut() {
+            let kinds: Vec<_> = drain(rx).iter().map(Event::kind).collect();
+            assert_eq!(kinds, vec!["twitter_keep_alive", "discord_gateway"]);
+        }
+    }
+
+    #[test]
+    fn a_full_queue_drops_events_for_that_subscriber_only() {
+        let bus = EventBus::new();
+        let mut slow = bus.subscribe("slow");
+        for _ in 0..SUBSCRIBER_CAPACITY {
+            publish(&bus, Event::TwitterKeepAlive);
+        }
+        let mut fast = bus.subscribe("fast");
+
+        publish(&bus, Event::DiscordGateway { connected: true });
+
+        let events = drain(&mut slow);
+        assert_eq!(events.len(), SUBSCRIBER_CAPACITY);
+        assert_eq!(events.last().map(Event::kind), Some("twitter_keep_alive"));
+        assert_eq!(
+            drain(&mut fast).iter().map(Event::kind).collect::<Vec<_>>(),
+            vec!["discord_gateway"]
+        );
+        assert_eq!(
+            metrics::EVENTS_DROPPED.with_label_values(&["slow"]).get(),
+            1
+        );
+    }
+
+    #[test]
+    fn subscribers_that_went_away_are_dropped() {
+        let bus = EventBus::new();
+        drop(bus.subscribe("gone"));
+        let mut rx = bus.subscribe("kept");
+
+        publish(&bus, Event::TwitterKeepAlive);
+
+        assert_eq!(bus.subscribers.lock().unwrap().queues.len(), 1);
+        assert_eq!(drain(&mut rx).len(), 1);
+    }
+}
@@ -1,18 +1,64 @@
-use std::sync::Arc;
+use std::{cmp::Ordering, collections::HashMap, fmt, sync::Arc};
 
 use crate::{
-    command::{CoingeckoCommand, Command, DiscordCommand, Manager},
-    Config,
+    alert::{self, AlertState, PriceHistory},
+    command::{CoingeckoCommand, Command, Manager, SchedulerCommand},
+    events::{self, Event, EventBus},
+    lifecycle::{ManagerHandle, Shutdown},
+    metrics,
+    scheduler::JobTask,
+    storage, Config,
 };
-use coingecko_tokio::PriceChangePercentage::OneHour;
-use coingecko_tokio::{Market, MarketRequest, Order};
+use chrono::{DateTime, Utc};
+use coingecko_tokio::Market;
 use serde::{Deserialize, Serialize};
 use tokio::sync::mpsc::{Receiver, Sender};
+use tracing::Instrument;
 
 #[derive(Deserialize, Serialize, Debug, Clone)]
 pub struct CoingeckoConfig {
+    #[serde(default = "default_sleep_time_secs")]
     pub sleep_time_secs: u64,
+    #[serde(default)]
     pub rules: Vec<Rule>,
+    #[serde(default)]
+    pub digests: Vec<DigestConfig>,
+    #[serde(default = "default_api_base")]
+    pub api_base: String,
+}
+
+fn default_sleep_time_secs() -> u64 {
+    60
+}
+
+fn default_api_base() -> String {
+    String::from("https://api.coingecko.com/api/v3")
+}
+
+impl Default for CoingeckoConfig {
+    fn default() -> Self {
+        CoingeckoConfig {
+            sleep_time_secs: default_sleep_time_secs(),
+            rules: vec![],
+            digests: vec![],
+            api_base: default_api_base(),
+        }
+    }
+}
+
+/// A summary of how the market moved between each run of its schedule
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct DigestConfig {
+    pub name: String,
+    /// A cron expression, e.g. `0 * * * *` for hourly, evaluated in the scheduler's timezone
+    pub schedule: String,
+    /// How many coins to list in each section
+    #[serde(default = "default_digest_size")]
+    pub size: usize,
+}
+
+fn default_digest_size() -> usize {
+    5
 }
 
 #[derive(Deserialize, Serialize, Debug, Clone)]
@@ -23,88 +69,553 @@ pub enum Rule {
     NegativeRank(i16),
 }
 
+impl Rule {
+    /// Parses a rule as written on the command line, e.g. `positive_percent 5`
+    pub fn parse(kind: &str, value: &str) -> Result<Rule, anyhow::Error> {
+        match kind {
+            "positive_percent" => Ok(Rule::PositivePercent(value.parse()?)),
+            "negative_percent" => Ok(Rule::NegativePercent(value.parse()?)),
+            "positive_rank" => Ok(Rule::PositiveRank(value.parse()?)),
+            "negative_rank" => Ok(Rule::NegativeRank(value.parse()?)),
+            _ => Err(anyhow::anyhow!(
+                "Unknown rule {}, expected positive_percent, negative_percent, positive_rank or negative_rank",
+                kind
+            )),
+        }
+    }
+}
+
+impl fmt::Display for Rule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Rule::PositivePercent(v) => write!(f, "positive_percent {}", v),
+            Rule::NegativePercent(v) => write!(f, "negative_percent {}", v),
+            Rule::PositiveRank(v) => write!(f, "positive_rank {}", v),
+            Rule::NegativeRank(v) => write!(f, "negative_rank {}", v),
+        }
+    }
+}
+
+#[derive(Clone)]
 pub enum RuleResult {
     Percent(bool, Market, f32),
     Rank(bool, Market, i16),
 }
 
+impl RuleResult {
+    pub fn market(&self) -> &Market {
+        match self {
+            RuleResult::Percent(_, m, _) | RuleResult::Rank(_, m, _) => m,
+        }
+    }
+
+    pub fn description(&self) -> String {
+        match self {
+            RuleResult::Percent(is_positive, _, diff) => {
+                let pos_msg = if *is_positive {
+                    "has risen by"
+                } else {
+                    "has declined by"
+                };
+                format!("This crypto {} {}%", pos_msg, diff)
+            }
+            RuleResult::Rank(is_positive, m, ranks) => {
+                let pos_msg = if *is_positive {
+                    "has risen"
+                } else {
+                    "has declined"
+                };
+                format!(
+                    "{} {} ranks to the rank of {}",
+                    pos_msg, ranks, m.market_cap_rank
+                )
+            }
+        }
+    }
+}
+
+/// Where each digest's baseline is kept, so a restart doesn't reset the period it covers
+pub const DIGEST_STATE_PATH: &str = "digests.json";
+
+/// The market as each digest last saw it, keyed by digest name
+#[derive(Deserialize, Serialize, Debug, Default)]
+pub struct DigestState {
+    baselines: HashMap<String, DigestBaseline>,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+struct DigestBaseline {
+    since: DateTime<Utc>,
+    coins: Vec<BaselineCoin>,
+}
+
+/// The parts of a market a digest compares against
+#[derive(Deserialize, Serialize, Debug, Clone)]
+struct BaselineCoin {
+    id: String,
+    current_price: f64,
+    market_cap: f64,
+    market_cap_rank: i64,
+}
+
+impl DigestBaseline {
+    fn of(since: DateTime<Utc>, state: &[Market]) -> DigestBaseline {
+        let coins = state
+            .iter()
+            .map(|m| BaselineCoin {
+                id: m.id.clone(),
+                current_price: m.current_price,
+                market_cap: m.market_cap as f64,
+                market_cap_rank: m.market_cap_rank as i64,
+            })
+            .collect();
+        DigestBaseline { since, coins }
+    }
+}
+
+impl DigestState {
+    /// Starts any digest that has no stored baseline from the current state
+    fn seed(&mut self, digests: &[DigestConfig], now: DateTime<Utc>, state: &[Market]) {
+        for digest in digests {
+            self.baselines
+                .entry(digest.name.clone())
+                .or_insert_with(|| DigestBaseline::of(now, state));
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct MarketDigest {
+    pub name: String,
+    pub since: DateTime<Utc>,
+    pub gainers: Vec<(Market, f32)>,
+    pub losers: Vec<(Market, f32)>,
+    pub rank_movers: Vec<(Market, i16)>,
+    pub market_cap_change: f32,
+}
+
+impl MarketDigest {
+    /// The digest as titled sections of one line per coin
+    pub fn sections(&self) -> Vec<(&'static str, String)> {
+        let or_none = |lines: Vec<String>| {
+            if lines.is_empty() {
+                String::from("None")
+            } else {
+                lines.join("\n")
+            }
+        };
+        let price_lines = |coins: &[(Market, f32)]| {
+            or_none(
+                coins
+                    .iter()
+                    .map(|(m, diff)| format!("{} {:+.2}%", m.id, diff))
+                    .collect(),
+            )
+        };
+        vec![
+            ("Top gainers", price_lines(&self.gainers)),
+            ("Top losers", price_lines(&self.losers)),
+            (
+                "Rank movers",
+                or_none(
+                    self.rank_movers
+                        .iter()
+                        .map(|(m, ranks)| format!("{} {:+} to #{}", m.id, ranks, m.market_cap_rank))
+                        .collect(),
+                ),
+            ),
+            (
+                "Total market cap",
+                format!("{:+.2}%", self.market_cap_change),
+            ),
+        ]
+    }
+}
+
+/// The top coins by market cap from the coingecko markets endpoint
+pub struct MarketFeed {
+    client: reqwest::Client,
+    url: String,
+}
+
+impl MarketFeed {
+    pub fn new(config: &CoingeckoConfig, client: reqwest::Client) -> MarketFeed {
+        MarketFeed {
+            client,
+            url: format!("{}/coins/markets", config.api_base.trim_end_matches('/')),
+        }
+    }
+
+    pub async fn fetch(&self) -> Result<Vec<Market>, anyhow::Error> {
+        Ok(self
+            .client
+            .get(&self.url)
+            .query(&[
+                ("vs_currency", "usd"),
+                ("order", "market_cap_desc"),
+                ("per_page", "250"),
+            ])
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?)
+    }
+}
+
 impl Manager<CoingeckoCommand> for CoingeckoConfig {
     fn start_manager(
         &self,
         config: Arc<Config>,
-        _rx: Receiver<CoingeckoCommand>,
+        mut rx: Receiver<CoingeckoCommand>,
         tx: Sender<Command>,
-    ) {
-        log::info!("Starting coingecko manager");
-        let _ = tokio::spawn(async move {
-            let client = coingecko_tokio::Client::new(reqwest::Client::new());
-
-            let req = MarketRequest::new(
-                String::from("usd"),
-                None,
-                None,
-                Some(Order::MarketCapDesc),
-                Some(250),
-                None,
-                None,
+        events: EventBus,
+        mut shutdown: Shutdown,
+    ) -> ManagerHandle {
+        tracing::info!("Starting coingecko manager");
+        let mut config = config.coingecko.clone().unwrap_or_else(|| self.clone());
+        let mut published = events.subscribe("coingecko");
+        tokio::spawn(async move {
+            let feed = MarketFeed::new(&config, reqwest::Client::new());
+
+            let mut state = match feed.fetch().await {
+                Ok(state) => state,
+                Err(e) => {
+                    return Err(anyhow::anyhow!(
+                        "Couldnt get base state for coingecko {:?}",
+                        e
+                    ))
+                }
+            };
+            events::publish(
+                &events,
+                Event::MarketSnapshot {
+                    markets: Arc::new(state.clone()),
+                    first: true,
+                },
             );
 
-            match client.markets(req.clone()).await {
-                Ok(mut state) => {
-                    let _ = tx
-                        .send(Command::Discord(DiscordCommand::SendCoingeckoBase(
-                            state.clone(),
-                        )))
-                        .await;
-
-                    loop {
-                        if let Ok(new_state) = client.markets(req.clone()).await {
-                            compare_state(tx.clone(), &state, &new_state, &config).await;
-                            state = new_state;
+            register_digests(&tx, &config.digests).await;
+            let mut digests: DigestState = storage::read(DIGEST_STATE_PATH).unwrap_or_else(|e| {
+                tracing::error!("Failed to read digest baselines, starting fresh {}", e);
+                DigestState::default()
+            });
+            digests.seed(&config.digests, Utc::now(), &state);
+            persist_digests(&digests);
+
+            let mut alerts: AlertState = storage::read(alert::STATE_PATH).unwrap_or_else(|e| {
+                tracing::error!("Failed to read alerts, starting fresh {}", e);
+                AlertState::default()
+            });
+            let mut history = PriceHistory::default();
+            history.record(Utc::now(), &state);
+
+            let mut interval =
+                tokio::time::interval(tokio::time::Duration::from_secs(config.sleep_time_secs));
+            // The first tick completes immediately and we already have the base state
+            interval.tick().await;
+            let mut polls: u64 = 0;
+
+            loop {
+                tokio::select! {
+                    _ = interval.tick() => {
+                        polls += 1;
+                        let span = tracing::info_span!("coingecko_poll", poll = polls);
+                        let timer = metrics::COINGECKO_POLL_SECONDS.start_timer();
+                        let polled = feed.fetch().instrument(span.clone()).await;
+                        timer.observe_duration();
+                        span.in_scope(|| match polled {
+                            Ok(new_state) => {
+                                compare_state(&events, &state, &new_state, &config);
+                                check_alerts(&events, &mut alerts, &mut history, &new_state);
+                                events::publish(
+                                    &events,
+                                    Event::MarketSnapshot {
+                                        markets: Arc::new(new_state.clone()),
+                                        first: false,
+                                    },
+                                );
+                                state = new_state;
+                            }
+                            Err(e) => {
+                                metrics::COINGECKO_POLL_FAILURES.inc();
+                                tracing::error!("Failed to poll coingecko {:?}", e);
+                            }
+                        });
+                    }
+                    Some(cmd) = rx.recv() => match cmd {
+                        CoingeckoCommand::Price(coin, reply) => {
+                            let _ = reply.send(find_market(&state, &coin).cloned());
+                        }
+                        CoingeckoCommand::Top(n) => {
+                            let mut coins = state.clone();
+                            coins.sort_by(|a, b| a.market_cap_rank.cmp(&b.market_cap_rank));
+                            coins.truncate(n);
+                            events::publish(&events, Event::TopCoins(Arc::new(coins)));
+                        }
+                        CoingeckoCommand::Digest(name) => {
+                            run_digest(&events, &config, &mut digests, name, &state)
+                        }
+                        CoingeckoCommand::AddAlert(user_id, coin, condition, reply) => {
+                            let res = alerts.add(user_id, &coin, condition, &state);
+                            if res.is_ok() {
+                                persist_alerts(&alerts);
+                            }
+                            let _ = reply.send(res);
+                        }
+                        CoingeckoCommand::ListAlerts(user_id, reply) => {
+                            let _ = reply.send(alerts.for_user(user_id));
+                        }
+                        CoingeckoCommand::DeleteAlert(user_id, id, reply) => {
+                            let deleted = alerts.delete(user_id, id);
+                            if deleted {
+                                persist_alerts(&alerts);
+                            }
+                            let _ = reply.send(deleted);
+                        }
+                        CoingeckoCommand::Markets(reply) => {
+                            let _ = reply.send(state.clone());
+                        }
+                        CoingeckoCommand::ListRules(reply) => {
+                            let _ = reply.send(config.rules.clone());
+                        }
+                        CoingeckoCommand::AddRule(rule, actor, reply) => {
+                            let action = format!("add_rule {}", rule);
+                            let persisted = Config::modify(&actor, &action, |c| {
+                                c.coingecko.get_or_insert_with(Default::default).rules.push(rule)
+                            });
+                            let _ = reply.send(update_rules(&mut config, persisted));
+                        }
+                        CoingeckoCommand::RemoveRule(number, actor, reply) => {
+                            let mut removed = None;
+                            let action = format!("remove_rule {}", number);
+                            let persisted = Config::modify(&actor, &action, |c| {
+                                removed = remove_rule(c, number)
+                            });
+                            let res = update_rules(&mut config, persisted).and_then(|()| {
+                                removed.ok_or_else(|| anyhow::anyhow!("There is no rule {}", number))
+                            });
+                            let _ = reply.send(res);
+                        }
+                    },
+                    Some(event) = published.recv() => {
+                        // Jobs registered with a scheduler that restarted went with it
+                        if let Event::SchedulerStarted = event {
+                            register_digests(&tx, &config.digests).await;
                         }
-                        tokio::time::sleep(tokio::time::Duration::from_secs(
-                            config.coingecko.sleep_time_secs,
-                        ))
-                        .await;
                     }
+                    _ = shutdown.wait() => return Ok(()),
                 }
-                Err(e) => log::error!("Couldnt get base state for coingecko {:?}", e),
             }
-        });
+        })
+    }
+}
+
+/// Has the scheduler run each digest, which it forgets when it restarts
+async fn register_digests(tx: &Sender<Command>, digests: &[DigestConfig]) {
+    for digest in digests {
+        let register = SchedulerCommand::Register(
+            String::from("coingecko"),
+            digest.schedule.clone(),
+            JobTask::Digest(digest.name.clone()),
+        );
+        if let Err(e) = tx.send(Command::Scheduler(register)).await {
+            tracing::error!("Failed to register digest {} {}", digest.name, e);
+        }
+    }
+}
+
+/// Takes a rule out of the config by its number, counting from 1 in the order the rules are
+/// listed. Changes are made to the base config file, so that is what the number is resolved
+/// against rather than the layered config the bot runs with.
+pub fn remove_rule(config: &mut Config, number: usize) -> Option<Rule> {
+    let rules = &mut config.coingecko.as_mut()?.rules;
+    let index = number.checked_sub(1).filter(|i| *i < rules.len())?;
+    Some(rules.remove(index))
+}
+
+/// Applies rule changes the moment they're persisted, rather than on the next restart
+fn update_rules(
+    config: &mut CoingeckoConfig,
+    persisted: Result<Config, anyhow::Error>,
+) -> Result<(), anyhow::Error> {
+    match persisted {
+        Ok(persisted) => {
+            config.rules = persisted.coingecko.map(|c| c.rules).unwrap_or_default();
+            Ok(())
+        }
+        Err(e) => {
+            tracing::error!("Failed to persist config {}", e);
+            Err(e)
+        }
+    }
+}
+
+fn check_alerts(
+    events: &EventBus,
+    alerts: &mut AlertState,
+    history: &mut PriceHistory,
+    state: &[Market],
+) {
+    let now = Utc::now();
+    history.record(now, state);
+    let triggered = alerts.take_triggered(state, history, now);
+    if triggered.is_empty() {
+        return;
+    }
+    persist_alerts(alerts);
+    for (alert, market) in triggered {
+        events::publish(events, Event::AlertTriggered(alert, market));
+    }
+}
+
+fn persist_alerts(alerts: &AlertState) {
+    if let Err(e) = storage::persist(alert::STATE_PATH, alerts) {
+        tracing::error!("Failed to persist alerts {}", e);
+    }
+}
+
+fn persist_digests(digests: &DigestState) {
+    if let Err(e) = storage::persist(DIGEST_STATE_PATH, digests) {
+        tracing::error!("Failed to persist digest baselines {}", e);
+    }
+}
+
+/// Publishes the named digest, starting its next period from the current state
+fn run_digest(
+    events: &EventBus,
+    config: &CoingeckoConfig,
+    digests: &mut DigestState,
+    name: String,
+    state: &[Market],
+) {
+    let size = config
+        .digests
+        .iter()
+        .find(|d| d.name == name)
+        .map_or_else(default_digest_size, |d| d.size);
+    let now = Utc::now();
+    let baseline = digests
+        .baselines
+        .remove(&name)
+        .unwrap_or_else(|| DigestBaseline::of(now, state));
+    digests
+        .baselines
+        .insert(name.clone(), DigestBaseline::of(now, state));
+    persist_digests(digests);
+    events::publish(
+        events,
+        Event::MarketDigest(build_digest(&name, size, &baseline, state)),
+    );
+}
+
+fn build_digest(
+    name: &str,
+    size: usize,
+    baseline: &DigestBaseline,
+    current: &[Market],
+) -> MarketDigest {
+    let mut changes: Vec<(&Market, f32, i16)> = current
+        .iter()
+        .filter_map(|m| {
+            baseline.coins.iter().find(|b| b.id == m.id).map(|b| {
+                (
+                    m,
+                    get_price_diff_pct(&b.current_price, &m.current_price),
+                    (b.market_cap_rank - m.market_cap_rank as i64) as i16,
+                )
+            })
+        })
+        .collect();
+
+    changes.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap_or(Ordering::Equal));
+    let gainers = changes
+        .iter()
+        .filter(|(_, diff, _)| *diff > 0.0)
+        .take(size)
+        .map(|(m, diff, _)| ((*m).clone(), *diff))
+        .collect();
+    let losers = changes
+        .iter()
+        .rev()
+        .filter(|(_, diff, _)| *diff < 0.0)
+        .take(size)
+        .map(|(m, diff, _)| ((*m).clone(), *diff))
+        .collect();
+
+    changes.sort_by_key(|(_, _, ranks)| -ranks.abs());
+    let rank_movers = changes
+        .iter()
+        .filter(|(_, _, ranks)| *ranks != 0)
+        .take(size)
+        .map(|(m, _, ranks)| ((*m).clone(), *ranks))
+        .collect();
+
+    let baseline_cap = baseline.coins.iter().map(|b| b.market_cap).sum::<f64>();
+    let current_cap = current.iter().map(|m| m.market_cap as f64).sum::<f64>();
+    MarketDigest {
+        name: name.to_string(),
+        since: baseline.since,
+        gainers,
+        losers,
+        rank_movers,
+        market_cap_change: get_price_diff_pct(&baseline_cap, &current_cap),
     }
 }
 
+/// Finds a coin in the latest state by its coingecko id or ticker symbol
+pub fn find_market<'a>(state: &'a [Market], coin: &str) -> Option<&'a Market> {
+    let coin = coin.to_lowercase();
+    state
+        .iter()
+        .find(|m| m.id == coin)
+        .or_else(|| state.iter().find(|m| m.symbol.to_lowercase() == coin))
+}
+
 fn get_price_diff_pct(initial: &f64, current: &f64) -> f32 {
     (((current / initial) * 100_f64) - 100_f64) as f32
 }
 
-async fn compare_state(
-    tx: Sender<Command>,
+/// Publishes every rule result between two polls
+pub fn compare_state(
+    events: &EventBus,
     initial_state: &[Market],
     new_state: &[Market],
-    config: &Config,
+    config: &CoingeckoConfig,
 ) {
+    for res in evaluate_rules(&config.rules, initial_state, new_state) {
+        events::rule_span(&res).in_scope(|| tracing::debug!("{}", res.description()));
+        metrics::RULES_FIRED
+            .with_label_values(&[metrics::rule_label(&res)])
+            .inc();
+        events::publish(events, Event::RuleTriggered(res));
+    }
+}
+
+/// Every rule result between two states of the market, for the coins in both
+pub fn evaluate_rules(
+    rules: &[Rule],
+    initial_state: &[Market],
+    new_state: &[Market],
+) -> Vec<RuleResult> {
+    let mut results = vec![];
     for market in new_state {
         let market_initial = initial_state.iter().find(|m| m.id == market.id);
         if let Some(market_initial) = market_initial {
-            for res in apply_rules(&config, &market_initial, &market) {
-                tx.send(Command::Discord(DiscordCommand::SendCoingeckoRuleResult(
-                    res,
-                )))
-                .await;
-            }
+            results.extend(apply_rules(rules, &market_initial, &market));
         }
     }
+    results
 }
 
 fn calculate_market_cap_rank_diff(initial: &Market, current: &Market) -> i16 {
     (initial.market_cap_rank - current.market_cap_rank) as i16
 }
 
-fn apply_rules(config: &Config, initial: &Market, current: &Market) -> Vec<RuleResult> {
+fn apply_rules(rules: &[Rule], initial: &Market, current: &Market) -> Vec<RuleResult> {
     let mut rule_results: Vec<RuleResult> = vec![];
 
-    config.coingecko.rules.iter().for_each(|rule| match rule {
+    rules.iter().for_each(|rule| match rule {
         Rule::PositivePercent(max) => {
             let price_percentage =
                 get_price_diff_pct(&initial.current_price, &current.current_price);
@@ -139,3 +650,103 @@ fn apply_rules(config: &Config, initial: &Market, current: &Market) -> Vec<RuleR
 
     rule_results
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::harness;
+
+    fn markets(coins: &[(&str, f64, i64)]) -> Vec<Market> {
+        serde_json::from_str(&harness::markets(coins)).unwrap()
+    }
+
+    fn digest_config(name: &str) -> DigestConfig {
+        DigestConfig {
+            name: name.to_string(),
+            schedule: String::from("0 * * * *"),
+            size: default_digest_size(),
+        }
+    }
+
+    #[test]
+    fn seed_keeps_stored_baselines() {
+        let earlier = Utc::now() - chrono::Duration::hours(6);
+        let mut digests = DigestState::default();
+        digests.seed(
+            &[digest_config("daily")],
+            earlier,
+            &markets(&[("bitcoin", 100.0, 1)]),
+        );
+        // As read back after a restart
+        let mut digests: DigestState =
+            serde_json::from_str(&serde_json::to_string(&digests).unwrap()).unwrap();
+        digests.seed(
+            &[digest_config("daily"), digest_config("hourly")],
+            Utc::now(),
+            &markets(&[("bitcoin", 200.0, 1)]),
+        );
+
+        assert_eq!(digests.baselines["daily"].since, earlier);
+        assert_eq!(digests.baselines["daily"].coins[0].current_price, 100.0);
+        assert_eq!(digests.baselines["hourly"].coins[0].current_price, 200.0);
+    }
+
+    #[test]
+    fn digest_compares_against_the_baseline() {
+        let since = Utc::now() - chrono::Duration::hours(1);
+        let baseline = DigestBaseline::of(
+            since,
+            &markets(&[
+                ("bitcoin", 100.0, 1),
+                ("ethereum", 50.0, 2),
+                ("dogecoin", 1.0, 3),
+            ]),
+        );
+        let current = markets(&[
+            ("bitcoin", 90.0, 2),
+            ("ethereum", 60.0, 1),
+            ("solana", 10.0, 3),
+        ]);
+
+        let digest = build_digest("hourly", 5, &baseline, &current);
+
+        assert_eq!(digest.since, since);
+        assert_eq!(digest.gainers.len(), 1);
+        assert_eq!(digest.gainers[0].0.id, "ethereum");
+        assert!((digest.gainers[0].1 - 20.0).abs() < 0.01);
+        assert_eq!(digest.losers[0].0.id, "bitcoin");
+        let movers: Vec<_> = digest
+            .rank_movers
+            .iter()
+            .map(|(m, r)| (m.id.as_str(), *r))
+            .collect();
+        assert_eq!(movers, vec![("ethereum", 1), ("bitcoin", -1)]);
+    }
+
+    #[tokio::test]
+    async fn digests_are_registered_as_coingecko_jobs() {
+        let (tx, mut rx) = tokio::sync::mpsc::channel(4);
+
+        register_digests(&tx, &[digest_config("hourly"), digest_config("daily")]).await;
+        drop(tx);
+
+        let mut registered = vec![];
+        while let Some(cmd) = rx.recv().await {
+            match cmd {
+                Command::Scheduler(SchedulerCommand::Register(owner, schedule, task)) => {
+                    assert_eq!(owner, "coingecko");
+                    assert_eq!(schedule, "0 * * * *");
+                    registered.push(task);
+                }
+                _ => panic!("Expected a job to be registered"),
+            }
+        }
+        assert_eq!(
+            registered,
+            vec![
+                JobTask::Digest(String::from("hourly")),
+                JobTask::Digest(String::from("daily"))
+            ]
+        );
+    }
+}
@@ -0,0 +1,169 @@
+//! Local stand-ins for the twitter, coingecko and discord apis, so the integrations can be
+//! exercised offline by pointing their `api_base` at a mock server
+
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::{Arc, Mutex},
+};
+
+use warp::{
+    http::{Method, Response, StatusCode},
+    hyper::body::Bytes,
+    path::FullPath,
+    Filter,
+};
+
+/// A request the mock server received
+#[derive(Debug, Clone)]
+pub struct Recorded {
+    pub path: String,
+    pub query: String,
+    pub body: Bytes,
+}
+
+impl Recorded {
+    pub fn json(&self) -> serde_json::Value {
+        serde_json::from_slice(&self.body).expect("Expected a json request body")
+    }
+}
+
+type Responses = HashMap<(Method, String), VecDeque<(StatusCode, String)>>;
+
+/// Canned responses per method and path, given out in the order they were added. The last one
+/// for a route is repeated once the others are used up, and unknown routes get a 404.
+#[derive(Default)]
+pub struct MockServer {
+    responses: Responses,
+}
+
+/// A running mock server, which stops with the test's runtime
+pub struct RunningServer {
+    pub base: String,
+    requests: Arc<Mutex<Vec<Recorded>>>,
+}
+
+impl MockServer {
+    pub fn new() -> MockServer {
+        MockServer::default()
+    }
+
+    pub fn respond(mut self, method: Method, path: &str, status: StatusCode, body: &str) -> Self {
+        self.responses
+            .entry((method, path.to_string()))
+            .or_default()
+            .push_back((status, body.to_string()));
+        self
+    }
+
+    pub fn start(self) -> RunningServer {
+        let responses = Arc::new(Mutex::new(self.responses));
+        let requests = Arc::new(Mutex::new(vec![]));
+        let recorded = Arc::clone(&requests);
+
+        let route = warp::method()
+            .and(warp::path::full())
+            .and(warp::query::raw().or(warp::any().map(String::new)).unify())
+            .and(warp::body::bytes())
+            .map(
+                move |method: Method, path: FullPath, query: String, body: Bytes| {
+                    let path = path.as_str().to_string();
+                    recorded.lock().unwrap().push(Recorded {
+                        path: path.clone(),
+                        query,
+                        body,
+                    });
+                    let mut responses = responses.lock().unwrap();
+                    let (status, body) = match responses.get_mut(&(method, path)) {
+                        Some(queue) if queue.len() > 1 => queue.pop_front().unwrap(),
+                        Some(queue) => queue.front().cloned().unwrap(),
+                        None => (StatusCode::NOT_FOUND, String::new()),
+                    };
+                    Response::builder()
+                        .status(status)
+                        .header("content-type", "application/json")
+                        .body(body)
+                        .unwrap()
+                },
+            );
+
+        let (addr, server) = warp::serve(route).bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(server);
+        RunningServer {
+            base: format!("http://{}", addr),
+            requests,
+        }
+    }
+}
+
+impl RunningServer {
+    /// Every request received so far on the given path, oldest first
+    pub fn requests_to(&self, path: &str) -> Vec<Recorded> {
+        self.requests
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|r| r.path == path)
+            .cloned()
+            .collect()
+    }
+}
+
+/// A v2 filtered stream body, a tweet per line with blank keep-alive lines between them
+#[cfg(feature = "twitter")]
+pub fn tweet_stream(tweets: &[(&str, &str, &str, &str)]) -> String {
+    let mut body = String::from("\r\n");
+    for (id, text, author_id, username) in tweets {
+        let page = serde_json::json!({
+            "data": { "id": id, "text": text, "author_id": author_id },
+            "includes": {
+                "users": [{
+                    "id": author_id,
+                    "name": username,
+                    "username": username,
+                    "profile_image_url": format!("https://pbs.twimg.com/{}.jpg", username)
+                }]
+            }
+        });
+        body.push_str(&format!("{}\r\n\r\n", page));
+    }
+    body
+}
+
+/// A coins/markets response with every field the real api returns, for `(id, price, rank)`
+#[cfg(feature = "coingecko")]
+pub fn markets(coins: &[(&str, f64, i64)]) -> String {
+    let markets: Vec<_> = coins
+        .iter()
+        .map(|(id, price, rank)| {
+            serde_json::json!({
+                "id": id,
+                "symbol": &id[..3],
+                "name": id,
+                "image": format!("https://assets.coingecko.com/coins/images/{}.png", id),
+                "current_price": price,
+                "market_cap": (price * 1_000_000.0) as i64,
+                "market_cap_rank": rank,
+                "fully_diluted_valuation": null,
+                "total_volume": 1_000_000,
+                "high_24h": price,
+                "low_24h": price,
+                "price_change_24h": 0.0,
+                "price_change_percentage_24h": 0.0,
+                "market_cap_change_24h": 0.0,
+                "market_cap_change_percentage_24h": 0.0,
+                "circulating_supply": 1_000_000,
+                "total_supply": 1_000_000,
+                "max_supply": null,
+                "ath": price,
+                "ath_change_percentage": 0.0,
+                "ath_date": "2021-05-01T00:00:00.000Z",
+                "atl": price,
+                "atl_change_percentage": 0.0,
+                "atl_date": "2021-05-01T00:00:00.000Z",
+                "roi": null,
+                "last_updated": "2021-05-01T00:00:00.000Z"
+            })
+        })
+        .collect();
+    serde_json::to_string(&markets).unwrap()
+}
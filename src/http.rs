@@ -0,0 +1,165 @@
+use std::{convert::Infallible, net::SocketAddr};
+
+use serde::{Deserialize, Serialize};
+use tokio::{sync::mpsc::Sender, task::JoinHandle};
+use warp::{http::StatusCode, Filter};
+
+use crate::{
+    api,
+    command::Command,
+    lifecycle::{Health, HealthReport, Shutdown},
+    metrics,
+    secret::Secret,
+};
+
+/// The bot's own HTTP endpoints, which are only served when given an address to listen on
+#[derive(Deserialize, Serialize, Debug, Clone, Default)]
+pub struct HttpConfig {
+    /// e.g. `127.0.0.1:9090`
+    #[serde(default)]
+    pub listen: Option<SocketAddr>,
+    /// The bearer token the control api needs, which is disabled without one
+    #[serde(default, skip_serializing)]
+    pub api_token: Secret,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub api_token_file: Option<String>,
+}
+
+impl HttpConfig {
+    pub fn resolve_secrets(&mut self) -> Result<(), anyhow::Error> {
+        self.api_token
+            .resolve("HONORABLE_HTTP_API_TOKEN", &self.api_token_file)
+    }
+}
+
+/// Serves `/metrics`, the `/healthz` and `/readyz` probes and the control api until shutdown
+pub fn serve(
+    addr: SocketAddr,
+    api_token: Secret,
+    health: Health,
+    tx: Sender<Command>,
+    mut shutdown: Shutdown,
+) -> JoinHandle<()> {
+    tracing::info!("Serving http on {}", addr);
+    if api_token.is_empty() {
+        tracing::info!("No http.api_token is set, so the control api rejects every request");
+    }
+    let routes = routes(api_token, health, tx);
+
+    tokio::spawn(async move {
+        let bound = warp::serve(routes)
+            .try_bind_with_graceful_shutdown(addr, async move { shutdown.wait().await });
+        match bound {
+            Ok((_, server)) => server.await,
+            Err(e) => tracing::error!("Failed to serve http on {} {}", addr, e),
+        }
+    })
+}
+
+/// Every endpoint, with rejections turned into JSON errors
+fn routes(
+    api_token: Secret,
+    health: Health,
+    tx: Sender<Command>,
+) -> impl Filter<Extract = (impl warp::Reply,), Error = Infallible> + Clone {
+    let metrics_health = health.clone();
+    let metrics = warp::path("metrics")
+        .and(warp::path::end())
+        .and(warp::get())
+        .map(move || {
+            warp::reply::with_header(
+                metrics::render(&metrics_health),
+                "content-type",
+                prometheus::TEXT_FORMAT,
+            )
+        });
+    let health_reports = health.clone();
+    let healthz = warp::path("healthz")
+        .and(warp::path::end())
+        .and(warp::get())
+        .map(move || {
+            let report = health_reports.report();
+            status_reply(&report, report.healthy)
+        });
+    let readyz = warp::path("readyz")
+        .and(warp::path::end())
+        .and(warp::get())
+        .map(move || {
+            let report = health.report();
+            status_reply(&report, report.ready)
+        });
+    metrics
+        .or(healthz)
+        .or(readyz)
+        .or(api::routes(api_token, tx))
+        .recover(api::handle_rejection)
+}
+
+/// The report with a 503 when the probe fails, so orchestrators needn't parse the body
+fn status_reply(report: &HealthReport, ok: bool) -> impl warp::Reply {
+    let status = if ok {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+    warp::reply::with_status(warp::reply::json(report), status)
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::{json, Value};
+    use tokio::sync::mpsc;
+    use warp::test::request;
+
+    use super::*;
+    use crate::lifecycle::ManagerState;
+
+    #[tokio::test]
+    async fn readyz_waits_for_connections_that_havent_come_up() {
+        let health = Health::default();
+        health.set_state("discord", ManagerState::Running);
+        let (tx, _rx) = mpsc::channel(1);
+        let routes = routes(Secret::default(), health, tx);
+
+        let res = request().path("/readyz").reply(&routes).await;
+        assert_eq!(res.status(), StatusCode::SERVICE_UNAVAILABLE);
+        let report: Value = serde_json::from_slice(res.body()).unwrap();
+        assert_eq!(report["ready"], json!(false));
+        assert_eq!(
+            report["waiting_for"],
+            json!(["The discord gateway to connect"])
+        );
+
+        // Waiting isn't a problem, so the bot is still healthy
+        let res = request().path("/healthz").reply(&routes).await;
+        assert_eq!(res.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn healthz_fails_when_a_manager_stops() {
+        let health = Health::default();
+        health.set_state("rss", ManagerState::Running);
+        health.set_state("scheduler", ManagerState::Stopped);
+        let (tx, _rx) = mpsc::channel(1);
+        let routes = routes(Secret::default(), health, tx);
+
+        let res = request().path("/healthz").reply(&routes).await;
+        assert_eq!(res.status(), StatusCode::SERVICE_UNAVAILABLE);
+        let report: Value = serde_json::from_slice(res.body()).unwrap();
+        assert_eq!(
+            report["problems"],
+            json!(["The scheduler manager is Stopped (no error)"])
+        );
+    }
+
+    #[tokio::test]
+    async fn readyz_is_ok_once_everything_is_running() {
+        let health = Health::default();
+        health.set_state("rss", ManagerState::Running);
+        let (tx, _rx) = mpsc::channel(1);
+        let routes = routes(Secret::default(), health, tx);
+
+        let res = request().path("/readyz").reply(&routes).await;
+        assert_eq!(res.status(), StatusCode::OK);
+    }
+}
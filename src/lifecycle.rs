@@ -0,0 +1,450 @@
+use std::{
+    collections::HashMap,
+    future::Future,
+    sync::{Arc, RwLock},
+    time::Instant,
+};
+
+use chrono::{DateTime, Utc};
+use futures::future;
+use serde::Serialize;
+use tokio::{
+    sync::{
+        mpsc::{self, error::SendError, Sender},
+        watch, RwLock as AsyncRwLock,
+    },
+    task::JoinHandle,
+    time::Duration,
+};
+
+use crate::{
+    command::{Command, Manager, Worker},
+    events::{Event, EventBus},
+    metrics, Config,
+};
+
+const MIN_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(300);
+const QUEUE_DEPTH_INTERVAL: Duration = Duration::from_secs(5);
+/// A manager that stayed up this long is considered to have recovered, resetting its backoff
+const STABLE_AFTER: Duration = Duration::from_secs(600);
+/// Both twitter apis send a keep-alive at least every 30 seconds on an open stream
+const TWITTER_KEEP_ALIVE_TIMEOUT: Duration = Duration::from_secs(90);
+/// Coingecko counts as stalled after this many polls without a successful one
+const COINGECKO_MISSED_POLLS: u32 = 3;
+
+/// The task running a manager, finishing with an error if the manager gave up
+pub type ManagerHandle = JoinHandle<Result<(), anyhow::Error>>;
+
+/// Tells managers the bot is shutting down, so they can finish what they are doing and return
+#[derive(Clone)]
+pub struct Shutdown(watch::Receiver<bool>);
+
+impl Shutdown {
+    pub fn channel() -> (watch::Sender<bool>, Shutdown) {
+        let (tx, rx) = watch::channel(false);
+        (tx, Shutdown(rx))
+    }
+
+    pub fn is_triggered(&self) -> bool {
+        *self.0.borrow()
+    }
+
+    /// Completes once shutdown has been triggered
+    pub async fn wait(&mut self) {
+        while !self.is_triggered() {
+            if self.0.changed().await.is_err() {
+                return;
+            }
+        }
+    }
+}
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+pub enum ManagerState {
+    Running,
+    Restarting,
+    Stopped,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct ManagerStatus {
+    pub state: ManagerState,
+    pub restarts: u32,
+    pub last_error: Option<String>,
+    #[serde(skip)]
+    pub since: Instant,
+}
+
+/// What the connections managers depend on last reported on the event bus
+#[derive(Serialize, Debug, Clone, Default)]
+pub struct Connections {
+    pub discord_connected: Option<bool>,
+    /// The last keep-alive or tweet from the twitter stream
+    pub twitter_last_keep_alive: Option<DateTime<Utc>>,
+    pub coingecko_last_poll: Option<DateTime<Utc>>,
+    #[serde(skip)]
+    coingecko_interval: Duration,
+}
+
+/// The state of every manager and its connection, and what is wrong with them
+#[derive(Serialize, Debug)]
+pub struct HealthReport {
+    /// Every manager is running and none of the connections has dropped or gone quiet
+    pub healthy: bool,
+    /// Healthy, and every connection has come up since starting
+    pub ready: bool,
+    pub problems: Vec<String>,
+    pub waiting_for: Vec<String>,
+    pub managers: HashMap<&'static str, ManagerStatus>,
+    pub connections: Connections,
+}
+
+/// The current state of every supervised manager, keyed by manager name
+#[derive(Clone, Default)]
+pub struct Health {
+    statuses: Arc<RwLock<HashMap<&'static str, ManagerStatus>>>,
+    connections: Arc<RwLock<Connections>>,
+}
+
+impl Health {
+    fn update(&self, name: &'static str, state: ManagerState, error: Option<String>) {
+        let mut statuses = self.statuses.write().unwrap();
+        let status = statuses.entry(name).or_insert(ManagerStatus {
+            state,
+            restarts: 0,
+            last_error: None,
+            since: Instant::now(),
+        });
+        if state == ManagerState::Restarting {
+            status.restarts += 1;
+        }
+        if state != status.state {
+            status.since = Instant::now();
+        }
+        status.state = state;
+        if error.is_some() {
+            status.last_error = error;
+        }
+    }
+
+    /// Puts a manager in a state as its supervisor would
+    #[cfg(test)]
+    pub fn set_state(&self, name: &'static str, state: ManagerState) {
+        self.update(name, state, None)
+    }
+
+    pub fn statuses(&self) -> HashMap<&'static str, ManagerStatus> {
+        self.statuses.read().unwrap().clone()
+    }
+
+    /// Keeps the connections up to date from the event bus, expecting a coingecko poll every
+    /// `coingecko_interval`
+    pub fn watch_connections(&self, bus: &EventBus, coingecko_interval: Duration) {
+        self.connections.write().unwrap().coingecko_interval = coingecko_interval;
+        let connections = Arc::clone(&self.connections);
+        let mut rx = bus.subscribe("health");
+        tokio::spawn(async move {
+            while let Some(event) = rx.recv().await {
+                let mut connections = connections.write().unwrap();
+                match event {
+                    Event::DiscordGateway { connected } => {
+                        connections.discord_connected = Some(connected)
+                    }
+                    Event::TwitterKeepAlive => {
+                        connections.twitter_last_keep_alive = Some(Utc::now())
+                    }
+                    #[cfg(feature = "twitter")]
+                    Event::TweetReceived(_) => {
+                        connections.twitter_last_keep_alive = Some(Utc::now())
+                    }
+                    #[cfg(feature = "coingecko")]
+                    Event::MarketSnapshot { .. } => {
+                        connections.coingecko_last_poll = Some(Utc::now())
+                    }
+                    _ => {}
+                }
+            }
+        });
+    }
+
+    pub fn report(&self) -> HealthReport {
+        let managers = self.statuses();
+        let connections = self.connections.read().unwrap().clone();
+        let mut problems = vec![];
+        let mut waiting_for = vec![];
+
+        for (name, status) in &managers {
+            if status.state != ManagerState::Running {
+                let error = status.last_error.as_deref().unwrap_or("no error");
+                problems.push(format!(
+                    "The {} manager is {:?} ({})",
+                    name, status.state, error
+                ));
+                continue;
+            }
+            match *name {
+                "discord" => match connections.discord_connected {
+                    Some(true) => {}
+                    Some(false) => {
+                        problems.push(String::from("The discord gateway is disconnected"))
+                    }
+                    None => waiting_for.push(String::from("The discord gateway to connect")),
+                },
+                "twitter" => check_quiet(
+                    "The twitter stream",
+                    connections.twitter_last_keep_alive,
+                    status.since,
+                    TWITTER_KEEP_ALIVE_TIMEOUT,
+                    &mut problems,
+                    &mut waiting_for,
+                ),
+                "coingecko" => check_quiet(
+                    "Coingecko",
+                    connections.coingecko_last_poll,
+                    status.since,
+                    connections.coingecko_interval * COINGECKO_MISSED_POLLS,
+                    &mut problems,
+                    &mut waiting_for,
+                ),
+                _ => {}
+            }
+        }
+
+        HealthReport {
+            healthy: problems.is_empty(),
+            ready: problems.is_empty() && waiting_for.is_empty(),
+            problems,
+            waiting_for,
+            managers,
+            connections,
+        }
+    }
+}
+
+/// Flags a connection that hasn't been heard from within `timeout`, counting from when its
+/// manager started if it never has
+fn check_quiet(
+    name: &str,
+    last: Option<DateTime<Utc>>,
+    started: Instant,
+    timeout: Duration,
+    problems: &mut Vec<String>,
+    waiting_for: &mut Vec<String>,
+) {
+    let quiet = match last {
+        Some(last) => (Utc::now() - last).to_std().unwrap_or_default(),
+        None if started.elapsed() <= timeout => {
+            waiting_for.push(format!("{} to be heard from", name));
+            return;
+        }
+        None => started.elapsed(),
+    };
+    if quiet > timeout {
+        problems.push(format!(
+            "{} hasn't been heard from in {}s",
+            name,
+            quiet.as_secs()
+        ));
+    }
+}
+
+/// Where the router sends a manager's commands. A restarted manager gets a fresh channel,
+/// so the sender is swapped out underneath anyone holding the mailbox.
+pub struct Mailbox<T>(Arc<AsyncRwLock<Sender<T>>>);
+
+impl<T> Clone for Mailbox<T> {
+    fn clone(&self) -> Self {
+        Mailbox(Arc::clone(&self.0))
+    }
+}
+
+impl<T> From<Sender<T>> for Mailbox<T> {
+    fn from(tx: Sender<T>) -> Self {
+        Mailbox(Arc::new(AsyncRwLock::new(tx)))
+    }
+}
+
+impl<T> Mailbox<T> {
+    pub async fn send(&self, cmd: T) -> Result<(), SendError<T>> {
+        let tx = self.0.read().await.clone();
+        tx.send(cmd).await
+    }
+}
+
+/// Everything a supervisor needs to (re)start its manager
+pub struct Supervisor {
+    pub config: Arc<Config>,
+    pub tx: Sender<Command>,
+    pub events: EventBus,
+    pub shutdown: Shutdown,
+    pub health: Health,
+}
+
+impl Supervisor {
+    /// Starts the manager, restarting it with exponential backoff whenever it fails or panics.
+    /// A manager returning cleanly is left stopped. Returns the manager's mailbox and the
+    /// supervising task, which finishes once the manager has stopped for good.
+    pub fn supervise<T, M>(
+        &self,
+        name: &'static str,
+        manager: M,
+        capacity: usize,
+    ) -> (Mailbox<T>, JoinHandle<()>)
+    where
+        T: Send + 'static,
+        M: Manager<T> + Send + Sync + 'static,
+    {
+        let (first_tx, first_rx) = mpsc::channel(capacity);
+        let mailbox = Mailbox::from(first_tx);
+        track_queue_depth(name, mailbox.clone(), capacity, self.shutdown.clone());
+
+        let manager = Arc::new(manager);
+        let supervised_mailbox = mailbox.clone();
+        let mut first_rx = Some(first_rx);
+        let handle = self.keep_running(name, move |config, tx, events, shutdown| {
+            let (manager, mailbox) = (Arc::clone(&manager), supervised_mailbox.clone());
+            let first_rx = first_rx.take();
+            async move {
+                let rx = match first_rx {
+                    Some(rx) => rx,
+                    None => {
+                        let (mailbox_tx, rx) = mpsc::channel(capacity);
+                        *mailbox.0.write().await = mailbox_tx;
+                        rx
+                    }
+                };
+                manager.start_manager(config, rx, tx, events, shutdown)
+            }
+        });
+
+        (mailbox, handle)
+    }
+
+    /// Starts a worker, restarting it the same way as a manager
+    pub fn supervise_worker<W>(&self, name: &'static str, worker: W) -> JoinHandle<()>
+    where
+        W: Worker + Send + Sync + 'static,
+    {
+        self.keep_running(name, move |config, tx, events, shutdown| {
+            future::ready(worker.start_worker(config, tx, events, shutdown))
+        })
+    }
+
+    /// Runs whatever `start` starts until it returns cleanly or the bot shuts down, starting it
+    /// again with exponential backoff whenever it fails or panics. Each restart reads the config
+    /// again, so it picks up whatever was changed while it ran.
+    fn keep_running<F, Fut>(&self, name: &'static str, mut start: F) -> JoinHandle<()>
+    where
+        F: FnMut(Arc<Config>, Sender<Command>, EventBus, Shutdown) -> Fut + Send + 'static,
+        Fut: Future<Output = ManagerHandle> + Send,
+    {
+        let (mut config, tx, events, health) = (
+            Arc::clone(&self.config),
+            self.tx.clone(),
+            self.events.clone(),
+            self.health.clone(),
+        );
+        let mut shutdown = self.shutdown.clone();
+
+        tokio::spawn(async move {
+            let mut backoff = MIN_BACKOFF;
+            loop {
+                let handle = start(
+                    Arc::clone(&config),
+                    tx.clone(),
+                    events.clone(),
+                    shutdown.clone(),
+                )
+                .await;
+                health.update(name, ManagerState::Running, None);
+                let started = Instant::now();
+                let result = handle.await;
+
+                let error = match result {
+                    Ok(Ok(())) => {
+                        tracing::info!("The {} manager stopped", name);
+                        health.update(name, ManagerState::Stopped, None);
+                        return;
+                    }
+                    Ok(Err(e)) => format!("{:#}", e),
+                    Err(e) if e.is_panic() => String::from("panicked"),
+                    Err(e) => e.to_string(),
+                };
+                if shutdown.is_triggered() {
+                    health.update(name, ManagerState::Stopped, Some(error));
+                    return;
+                }
+
+                if started.elapsed() > STABLE_AFTER {
+                    backoff = MIN_BACKOFF;
+                }
+                tracing::error!(
+                    "The {} manager died ({}), restarting in {:?}",
+                    name,
+                    error,
+                    backoff
+                );
+                health.update(name, ManagerState::Restarting, Some(error));
+                tokio::select! {
+                    _ = tokio::time::sleep(backoff) => {}
+                    _ = shutdown.wait() => {
+                        health.update(name, ManagerState::Stopped, None);
+                        return;
+                    }
+                }
+                backoff = std::cmp::min(backoff * 2, MAX_BACKOFF);
+
+                let reloaded = tokio::task::spawn_blocking(Config::read).await;
+                match reloaded.map_err(anyhow::Error::from).and_then(|r| r) {
+                    Ok(reloaded) => config = reloaded,
+                    Err(e) => tracing::error!(
+                        "Failed to reload the config for the {} manager, keeping the last one {:#}",
+                        name,
+                        e
+                    ),
+                }
+            }
+        })
+    }
+}
+
+/// Keeps the queue depth metric for a mailbox up to date until shutdown
+pub fn track_queue_depth<T: Send + 'static>(
+    name: &'static str,
+    mailbox: Mailbox<T>,
+    capacity: usize,
+    mut shutdown: Shutdown,
+) {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(QUEUE_DEPTH_INTERVAL);
+        loop {
+            tokio::select! {
+                _ = interval.tick() => {
+                    let available = mailbox.0.read().await.capacity();
+                    metrics::QUEUE_DEPTH
+                        .with_label_values(&[name])
+                        .set(capacity.saturating_sub(available) as i64);
+                }
+                _ = shutdown.wait() => return,
+            }
+        }
+    });
+}
+
+/// Completes when the process is asked to stop, by Ctrl-C or SIGTERM
+#[cfg(unix)]
+pub async fn shutdown_signal() {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut terminate = signal(SignalKind::terminate()).expect("Failed to listen for SIGTERM");
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => {}
+        _ = terminate.recv() => {}
+    }
+}
+
+#[cfg(not(unix))]
+pub async fn shutdown_signal() {
+    let _ = tokio::signal::ctrl_c().await;
+}
@@ -0,0 +1,73 @@
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Serialize};
+use tracing_subscriber::EnvFilter;
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct LoggingConfig {
+    #[serde(default)]
+    pub format: LogFormat,
+    /// The level for everything without one of its own, e.g. `info`
+    #[serde(default = "default_level")]
+    pub level: String,
+    /// Levels per module, e.g. `"honorable_bot::gecko": "debug"`
+    #[serde(default)]
+    pub modules: BTreeMap<String, String>,
+}
+
+fn default_level() -> String {
+    String::from("info")
+}
+
+impl Default for LoggingConfig {
+    fn default() -> Self {
+        LoggingConfig {
+            format: LogFormat::default(),
+            level: default_level(),
+            modules: BTreeMap::new(),
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum LogFormat {
+    /// Human readable lines
+    Pretty,
+    /// A JSON object per line, carrying the fields of every span it was logged in
+    Json,
+}
+
+impl Default for LogFormat {
+    fn default() -> Self {
+        LogFormat::Pretty
+    }
+}
+
+impl LoggingConfig {
+    /// The levels as a filter, which `RUST_LOG` replaces when it is set
+    pub fn filter(&self) -> Result<EnvFilter, anyhow::Error> {
+        let mut directives = vec![self.level.clone()];
+        directives.extend(
+            self.modules
+                .iter()
+                .map(|(module, level)| format!("{}={}", module, level)),
+        );
+        Ok(EnvFilter::try_new(directives.join(","))?)
+    }
+}
+
+/// Starts logging, which also picks up anything dependencies log through the `log` crate
+pub fn init(config: &LoggingConfig) {
+    let filter = match EnvFilter::try_from_default_env() {
+        Ok(filter) => filter,
+        Err(_) => config
+            .filter()
+            .unwrap_or_else(|_| EnvFilter::new(default_level())),
+    };
+    let subscriber = tracing_subscriber::fmt().with_env_filter(filter);
+    match config.format {
+        LogFormat::Pretty => subscriber.init(),
+        LogFormat::Json => subscriber.json().init(),
+    }
+}
@@ -1,90 +1,391 @@
-use std::{
-    fs::{File, OpenOptions},
-    io::{BufReader, Write},
-    sync::Arc,
-};
+use std::sync::Arc;
 
-use command::{Command, Manager, TwitterCommand};
+use anyhow::Context;
+use audit::Actor;
+use command::Command;
+#[cfg(feature = "discord")]
 use discord::DiscordConfig;
+use futures::future;
+#[cfg(feature = "coingecko")]
 use gecko::CoingeckoConfig;
+use http::HttpConfig;
+use lifecycle::{Health, Mailbox, Shutdown, Supervisor};
+use logging::LoggingConfig;
+use permissions::PermissionsConfig;
+#[cfg(feature = "coingecko")]
+use portfolio::PortfolioConfig;
+use reddit::RedditConfig;
+use rss::RssConfig;
+use scheduler::SchedulerConfig;
+use telegram::TelegramConfig;
 
 use serde::{Deserialize, Serialize};
+use structopt::StructOpt;
 
 use tokio::sync::mpsc::{self, Receiver, Sender};
+#[cfg(feature = "twitter")]
 use twitter::TwitterConfig;
+use webhook::WebhookConfig;
 
+#[cfg(feature = "coingecko")]
+pub mod alert;
+pub mod api;
+pub mod audit;
+pub mod cli;
 pub mod command;
+pub mod config_file;
+#[cfg(feature = "discord")]
 pub mod discord;
+pub mod events;
+#[cfg(feature = "coingecko")]
 pub mod gecko;
+#[cfg(test)]
+mod harness;
+pub mod http;
+pub mod lifecycle;
+pub mod logging;
+pub mod metrics;
+pub mod migration;
+pub mod permissions;
+#[cfg(feature = "coingecko")]
+pub mod portfolio;
+pub mod reddit;
+pub mod rss;
+pub mod scheduler;
+pub mod secret;
+pub mod sink;
+pub mod storage;
+pub mod telegram;
+#[cfg(feature = "twitter")]
 pub mod twitter;
+pub mod validation;
+pub mod webhook;
+
+/// How many commands can wait for the router before senders have to wait
+const COMMAND_CAPACITY: usize = 256;
+/// How long managers get to finish up after a shutdown signal before the process exits anyway
+const SHUTDOWN_GRACE: tokio::time::Duration = tokio::time::Duration::from_secs(10);
 
 #[derive(Deserialize, Serialize, Debug, Clone)]
 pub struct Config {
-    pub twitter: TwitterConfig,
-    pub discord: DiscordConfig,
-    pub coingecko: CoingeckoConfig,
+    /// The layout version, for migrating configs written by older builds
+    #[serde(default)]
+    pub version: u64,
+    /// Each of the twitter, discord and coingecko managers is only started when its section is present
+    #[cfg(feature = "twitter")]
+    #[serde(default)]
+    pub twitter: Option<TwitterConfig>,
+    /// Kept as it was written when built without twitter, so persisting doesn't drop it
+    #[cfg(not(feature = "twitter"))]
+    #[serde(default)]
+    pub twitter: Option<serde_json::Value>,
+    #[cfg(feature = "discord")]
+    #[serde(default)]
+    pub discord: Option<DiscordConfig>,
+    /// Kept as it was written when built without discord, so persisting doesn't drop it
+    #[cfg(not(feature = "discord"))]
+    #[serde(default)]
+    pub discord: Option<serde_json::Value>,
+    #[cfg(feature = "coingecko")]
+    #[serde(default)]
+    pub coingecko: Option<CoingeckoConfig>,
+    #[cfg(not(feature = "coingecko"))]
+    #[serde(default)]
+    pub coingecko: Option<serde_json::Value>,
+    #[serde(default)]
+    pub rss: RssConfig,
+    #[serde(default)]
+    pub reddit: RedditConfig,
+    #[serde(default)]
+    pub webhooks: WebhookConfig,
+    #[serde(default)]
+    pub telegram: TelegramConfig,
+    #[serde(default)]
+    pub scheduler: SchedulerConfig,
+    #[cfg(feature = "coingecko")]
+    #[serde(default)]
+    pub portfolio: PortfolioConfig,
+    #[cfg(not(feature = "coingecko"))]
+    #[serde(default)]
+    pub portfolio: Option<serde_json::Value>,
+    #[serde(default)]
+    pub permissions: PermissionsConfig,
+    #[serde(default)]
+    pub http: HttpConfig,
+    #[serde(default)]
+    pub logging: LoggingConfig,
 }
 
 impl Config {
-    fn persist(&self) -> Result<(), anyhow::Error> {
-        let mut file = OpenOptions::new()
-            .append(false)
-            .write(true)
-            .create(true)
-            .open("config.json")?;
-        file.write_all(serde_json::to_string_pretty(self)?.as_bytes())?;
-
-        Ok(())
+    /// Writes what changed since `before` back to the config file
+    fn persist(&self, before: &Config) -> Result<(), anyhow::Error> {
+        config_file::write_changes(&serde_json::to_value(before)?, &serde_json::to_value(self)?)
+    }
+    /// Reads the layered config as it's written, without its secrets or validating it, for
+    /// reading or changing single values
+    fn load() -> Result<Config, anyhow::Error> {
+        serde_json::from_value(config_file::load()?)
+            .context("The config doesn't match the config layout")
+    }
+    /// Reads only the base config file, which is what changes are made to
+    fn load_base() -> Result<Config, anyhow::Error> {
+        serde_json::from_value(config_file::load_base()?)
+            .context("The config doesn't match the config layout")
     }
+    /// Reads the layered config, then fills in its secrets and validates it
     fn read() -> Result<Arc<Config>, anyhow::Error> {
-        let config = File::open("config.json")?;
-        let reader = BufReader::new(config);
-        Ok(Arc::new(serde_json::from_reader(reader)?))
+        let mut config = Config::load()?;
+        config.resolve_secrets()?;
+        validation::validate(&config)?;
+        Ok(Arc::new(config))
+    }
+    /// Fills in credentials from the environment and secret files
+    fn resolve_secrets(&mut self) -> Result<(), anyhow::Error> {
+        #[cfg(feature = "twitter")]
+        if let Some(twitter) = &mut self.twitter {
+            twitter.resolve_secrets()?;
+        }
+        #[cfg(feature = "discord")]
+        if let Some(discord) = &mut self.discord {
+            discord.resolve_secrets()?;
+        }
+        self.http.resolve_secrets()?;
+        self.webhooks.resolve_secrets()?;
+        self.telegram.resolve_secrets()
+    }
+    /// Applies a change on top of the config as it is on disk, so managers changing different
+    /// sections don't overwrite each other with their stale copies. The change is made to the
+    /// base file alone, so values from the environment's file or overrides aren't written into
+    /// it, and is recorded in the audit log against the actor who asked for it. Returns the
+    /// layered config as it is after the change.
+    fn modify<F: FnOnce(&mut Config)>(
+        actor: &Actor,
+        action: &str,
+        f: F,
+    ) -> Result<Config, anyhow::Error> {
+        let before = Config::load_base()?;
+        let mut config = before.clone();
+        f(&mut config);
+        validation::validate_change(&before, &config)?;
+        config.persist(&before)?;
+        if let Err(e) = audit::record(actor, action, &before, &config) {
+            tracing::error!("Failed to record config change in the audit log {}", e);
+        }
+
+        Config::load()
     }
 }
 
 #[tokio::main]
 async fn main() -> Result<(), anyhow::Error> {
-    pretty_env_logger::init();
+    let opt = cli::Opt::from_args();
+    if let Some(path) = opt.config {
+        config_file::set_path(path);
+    }
+    // Only the logging section is needed here, anything wrong with the rest of the config is
+    // reported once the command reads it
+    let logging = config_file::load()
+        .ok()
+        .and_then(|config| serde_json::from_value(config["logging"].clone()).ok())
+        .unwrap_or_default();
+    logging::init(&logging);
+    match opt.command {
+        None | Some(cli::Subcommand::Run) => run().await,
+        Some(command) => cli::execute(command),
+    }
+}
+
+async fn run() -> Result<(), anyhow::Error> {
     let config = Config::read()?;
 
-    let (tx, mut rx): (Sender<Command>, Receiver<Command>) = mpsc::channel(256);
-    let (twitter_tx, twitter_rx) = mpsc::channel(64);
-    let (discord_tx, discord_rx) = mpsc::channel(256);
-    let (_coingecko_tx, coingecko_rx) = mpsc::channel(64);
-
-    config
-        .twitter
-        .start_manager(Arc::clone(&config), twitter_rx, tx.clone());
-    config
-        .discord
-        .start_manager(Arc::clone(&config), discord_rx, tx.clone());
-    config
+    let (tx, mut rx): (Sender<Command>, Receiver<Command>) = mpsc::channel(COMMAND_CAPACITY);
+    let events = events::EventBus::new();
+    let (shutdown_tx, shutdown) = Shutdown::channel();
+    let health = Health::default();
+
+    events::start_log_sink(&events);
+    events::start_storage_sink(&events);
+    #[cfg(feature = "coingecko")]
+    let coingecko_interval = config
         .coingecko
-        .start_manager(Arc::clone(&config), coingecko_rx, tx.clone());
+        .as_ref()
+        .map(|c| c.sleep_time_secs)
+        .unwrap_or_default();
+    #[cfg(not(feature = "coingecko"))]
+    let coingecko_interval = 0;
+    health.watch_connections(
+        &events,
+        tokio::time::Duration::from_secs(coingecko_interval),
+    );
+
+    let supervisor = Supervisor {
+        config: Arc::clone(&config),
+        tx: tx.clone(),
+        events: events.clone(),
+        shutdown,
+        health: health.clone(),
+    };
+    let mut supervised = vec![];
+    lifecycle::track_queue_depth(
+        "router",
+        Mailbox::from(tx.clone()),
+        COMMAND_CAPACITY,
+        supervisor.shutdown.clone(),
+    );
+    if let Some(addr) = config.http.listen {
+        supervised.push(http::serve(
+            addr,
+            config.http.api_token.clone(),
+            health.clone(),
+            tx.clone(),
+            supervisor.shutdown.clone(),
+        ));
+    }
 
-    let _main: Result<(), anyhow::Error> = tokio::spawn(async move {
+    #[cfg(feature = "twitter")]
+    let twitter_tx = start(&supervisor, &mut supervised, "twitter", &config.twitter);
+    #[cfg(not(feature = "twitter"))]
+    not_built("twitter", config.twitter.is_some());
+    #[cfg(feature = "discord")]
+    start_worker(&supervisor, &mut supervised, "discord", &config.discord);
+    #[cfg(not(feature = "discord"))]
+    not_built("discord", config.discord.is_some());
+    #[cfg(feature = "coingecko")]
+    let coingecko_tx = start(&supervisor, &mut supervised, "coingecko", &config.coingecko);
+    #[cfg(not(feature = "coingecko"))]
+    not_built("coingecko", config.coingecko.is_some());
+    let (rss_tx, handle) = supervisor.supervise("rss", config.rss.clone(), 64);
+    supervised.push(handle);
+    let (reddit_tx, handle) = supervisor.supervise("reddit", config.reddit.clone(), 64);
+    supervised.push(handle);
+    let (scheduler_tx, handle) = supervisor.supervise("scheduler", config.scheduler.clone(), 64);
+    supervised.push(handle);
+    #[cfg(feature = "coingecko")]
+    let (portfolio_tx, handle) = supervisor.supervise("portfolio", config.portfolio.clone(), 64);
+    #[cfg(feature = "coingecko")]
+    supervised.push(handle);
+    if !config.webhooks.hooks.is_empty() {
+        supervised.push(supervisor.supervise_worker("webhooks", config.webhooks.clone()));
+    }
+    if config.telegram.is_enabled() {
+        supervised.push(supervisor.supervise_worker("telegram", config.telegram.clone()));
+    }
+
+    tokio::spawn(async move {
         while let Some(cmd) = rx.recv().await {
             match cmd {
-                Command::Twitter(c) => {
-                    let _ = twitter_tx
+                #[cfg(feature = "twitter")]
+                Command::Twitter(c) => route("twitter", &twitter_tx, c).await,
+                #[cfg(feature = "coingecko")]
+                Command::Coingecko(c) => route("coingecko", &coingecko_tx, c).await,
+                Command::Rss(c) => {
+                    let _ = rss_tx
+                        .send(c)
+                        .await
+                        .map_err(|e| tracing::error!("Failed to send command {}", e));
+                }
+                Command::Reddit(c) => {
+                    let _ = reddit_tx
+                        .send(c)
+                        .await
+                        .map_err(|e| tracing::error!("Failed to send command {}", e));
+                }
+                Command::Scheduler(c) => {
+                    let _ = scheduler_tx
                         .send(c)
                         .await
-                        .map_err(|e| log::error!("Failed to send command {}", e));
+                        .map_err(|e| tracing::error!("Failed to send command {}", e));
                 }
-                Command::Discord(c) => {
-                    let _ = discord_tx
+                #[cfg(feature = "coingecko")]
+                Command::Portfolio(c) => {
+                    let _ = portfolio_tx
                         .send(c)
                         .await
-                        .map_err(|e| log::error!("Failed to send command {}", e));
+                        .map_err(|e| tracing::error!("Failed to send command {}", e));
                 }
-                Command::Coingecko(_) => {}
             }
         }
-        Ok(())
-    })
-    .await
-    .expect("Main command loop died");
+    });
+
+    lifecycle::shutdown_signal().await;
+    tracing::info!("Shutting down, waiting for managers to finish");
+    let _ = shutdown_tx.send(true);
+    if tokio::time::timeout(SHUTDOWN_GRACE, future::join_all(supervised))
+        .await
+        .is_err()
+    {
+        tracing::warn!(
+            "Managers didn't stop within {:?}, exiting anyway",
+            SHUTDOWN_GRACE
+        );
+    }
 
     Ok(())
 }
+
+/// Supervises a manager if its section is in the config, returning its mailbox
+#[cfg(any(feature = "twitter", feature = "coingecko"))]
+fn start<T, M>(
+    supervisor: &Supervisor,
+    supervised: &mut Vec<tokio::task::JoinHandle<()>>,
+    name: &'static str,
+    section: &Option<M>,
+) -> Option<Mailbox<T>>
+where
+    T: Send + 'static,
+    M: command::Manager<T> + Clone + Send + Sync + 'static,
+{
+    let section = match section {
+        Some(section) => section,
+        None => {
+            tracing::info!("No {} section in the config, not starting it", name);
+            return None;
+        }
+    };
+    let (tx, handle) = supervisor.supervise(name, section.clone(), 64);
+    supervised.push(handle);
+    Some(tx)
+}
+
+/// Supervises a worker if its section is in the config
+#[cfg(feature = "discord")]
+fn start_worker<W>(
+    supervisor: &Supervisor,
+    supervised: &mut Vec<tokio::task::JoinHandle<()>>,
+    name: &'static str,
+    section: &Option<W>,
+) where
+    W: command::Worker + Clone + Send + Sync + 'static,
+{
+    match section {
+        Some(section) => supervised.push(supervisor.supervise_worker(name, section.clone())),
+        None => tracing::info!("No {} section in the config, not starting it", name),
+    }
+}
+
+/// Stands in for a manager this build was compiled without
+#[cfg(not(all(feature = "twitter", feature = "discord", feature = "coingecko")))]
+fn not_built(name: &str, configured: bool) {
+    if configured {
+        tracing::warn!(
+            "The config has a {} section but this build doesn't have the {} feature",
+            name,
+            name
+        );
+    }
+}
+
+/// Sends a command to its manager, dropping it if that manager isn't running, in which case
+/// anyone waiting on a reply sees it as unavailable
+#[cfg(any(feature = "twitter", feature = "coingecko"))]
+async fn route<T>(name: &str, tx: &Option<Mailbox<T>>, cmd: T) {
+    match tx {
+        Some(tx) => {
+            let _ = tx
+                .send(cmd)
+                .await
+                .map_err(|e| tracing::error!("Failed to send command {}", e));
+        }
+        None => tracing::warn!("Dropping a command for {}, which isn't running", name),
+    }
+}
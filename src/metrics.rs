@@ -0,0 +1,166 @@
+use once_cell::sync::Lazy;
+use prometheus::{
+    Encoder, Histogram, HistogramOpts, IntCounter, IntCounterVec, IntGaugeVec, Opts, Registry,
+    TextEncoder,
+};
+
+#[cfg(feature = "coingecko")]
+use crate::gecko::RuleResult;
+use crate::lifecycle::{Health, ManagerState};
+
+static REGISTRY: Lazy<Registry> = Lazy::new(Registry::new);
+
+fn register<T: prometheus::core::Collector + Clone + 'static>(collector: T) -> T {
+    REGISTRY
+        .register(Box::new(collector.clone()))
+        .expect("Failed to register metric");
+    collector
+}
+
+/// Every tweet the stream or a backfill produced, including ones already forwarded
+pub static TWEETS_RECEIVED: Lazy<IntCounterVec> = Lazy::new(|| {
+    register(
+        IntCounterVec::new(
+            Opts::new("tweets_received_total", "Tweets received per handle"),
+            &["handle"],
+        )
+        .unwrap(),
+    )
+});
+
+pub static TWEETS_FORWARDED: Lazy<IntCounterVec> = Lazy::new(|| {
+    register(
+        IntCounterVec::new(
+            Opts::new("tweets_forwarded_total", "Tweets published per handle"),
+            &["handle"],
+        )
+        .unwrap(),
+    )
+});
+
+pub static COINGECKO_POLL_SECONDS: Lazy<Histogram> = Lazy::new(|| {
+    register(
+        Histogram::with_opts(HistogramOpts::new(
+            "coingecko_poll_seconds",
+            "How long each coingecko markets poll took",
+        ))
+        .unwrap(),
+    )
+});
+
+pub static COINGECKO_POLL_FAILURES: Lazy<IntCounter> = Lazy::new(|| {
+    register(
+        IntCounter::new(
+            "coingecko_poll_failures_total",
+            "Coingecko markets polls that failed",
+        )
+        .unwrap(),
+    )
+});
+
+pub static RULES_FIRED: Lazy<IntCounterVec> = Lazy::new(|| {
+    register(
+        IntCounterVec::new(
+            Opts::new("rules_fired_total", "Rule results per rule type"),
+            &["rule"],
+        )
+        .unwrap(),
+    )
+});
+
+pub static DISCORD_SEND_ERRORS: Lazy<IntCounter> = Lazy::new(|| {
+    register(
+        IntCounter::new(
+            "discord_send_errors_total",
+            "Discord messages that couldn't be sent",
+        )
+        .unwrap(),
+    )
+});
+
+pub static DISCORD_RATE_LIMITS: Lazy<IntCounter> = Lazy::new(|| {
+    register(
+        IntCounter::new(
+            "discord_rate_limits_total",
+            "Responses from discord with a 429 status",
+        )
+        .unwrap(),
+    )
+});
+
+/// Commands waiting in each channel, the main router's being `router`
+pub static QUEUE_DEPTH: Lazy<IntGaugeVec> = Lazy::new(|| {
+    register(
+        IntGaugeVec::new(
+            Opts::new("command_queue_depth", "Commands waiting to be handled"),
+            &["queue"],
+        )
+        .unwrap(),
+    )
+});
+
+/// Events waiting in each subscriber's queue on the event bus
+pub static EVENT_QUEUE_DEPTH: Lazy<IntGaugeVec> = Lazy::new(|| {
+    register(
+        IntGaugeVec::new(
+            Opts::new("event_queue_depth", "Events waiting for a subscriber"),
+            &["subscriber"],
+        )
+        .unwrap(),
+    )
+});
+
+/// Events a subscriber missed because its queue was full
+pub static EVENTS_DROPPED: Lazy<IntCounterVec> = Lazy::new(|| {
+    register(
+        IntCounterVec::new(
+            Opts::new(
+                "events_dropped_total",
+                "Events dropped for a subscriber that fell behind",
+            ),
+            &["subscriber"],
+        )
+        .unwrap(),
+    )
+});
+
+static MANAGER_UPTIME: Lazy<IntGaugeVec> = Lazy::new(|| {
+    register(
+        IntGaugeVec::new(
+            Opts::new(
+                "manager_uptime_seconds",
+                "How long each manager has been running since it last (re)started, 0 when it isn't",
+            ),
+            &["manager"],
+        )
+        .unwrap(),
+    )
+});
+
+/// The rule type a result came from, as its label
+#[cfg(feature = "coingecko")]
+pub fn rule_label(res: &RuleResult) -> &'static str {
+    match res {
+        RuleResult::Percent(true, ..) => "positive_percent",
+        RuleResult::Percent(false, ..) => "negative_percent",
+        RuleResult::Rank(true, ..) => "positive_rank",
+        RuleResult::Rank(false, ..) => "negative_rank",
+    }
+}
+
+/// Every metric in the prometheus text format
+pub fn render(health: &Health) -> String {
+    for (name, status) in health.statuses() {
+        let uptime = match status.state {
+            ManagerState::Running => status.since.elapsed().as_secs() as i64,
+            _ => 0,
+        };
+        MANAGER_UPTIME.with_label_values(&[name]).set(uptime);
+    }
+
+    let mut buffer = vec![];
+    if let Err(e) = TextEncoder::new().encode(&REGISTRY.gather(), &mut buffer) {
+        tracing::error!("Failed to encode metrics {}", e);
+    }
+    String::from_utf8(buffer).unwrap_or_default()
+}
@@ -0,0 +1,131 @@
+use serde_json::Value;
+
+/// The layout version of the config this build writes
+pub const CURRENT_VERSION: u64 = 1;
+
+/// Unversioned builds rewrote config.json in place without truncating it, so a rewrite shorter
+/// than what was there before left the end of the old contents after the JSON. Returns the
+/// config without that tail when that's what is wrong with the file.
+pub fn strip_stale_tail(contents: &str) -> Option<Value> {
+    let mut values = serde_json::Deserializer::from_str(contents).into_iter::<Value>();
+    let config = values.next()?.ok()?;
+    let has_tail = values.byte_offset() < contents.trim_end().len();
+    if has_tail && config.is_object() && config.get("version").is_none() {
+        Some(config)
+    } else {
+        None
+    }
+}
+
+/// Upgrades a config from the version it was written in, one version at a time, returning
+/// whether anything had to be done
+pub fn migrate(config: &mut Value) -> Result<bool, anyhow::Error> {
+    let object = config
+        .as_object_mut()
+        .ok_or_else(|| anyhow::anyhow!("The config should be a table of sections"))?;
+    let version = object.get("version").and_then(Value::as_u64).unwrap_or(0);
+    if version > CURRENT_VERSION {
+        return Err(anyhow::anyhow!(
+            "The config is version {} but this build only understands up to {}",
+            version,
+            CURRENT_VERSION
+        ));
+    }
+
+    for from in version..CURRENT_VERSION {
+        tracing::info!("Migrating the config from version {} to {}", from, from + 1);
+        match from {
+            0 => normalise_subscriptions(config),
+            _ => unreachable!("No migration from version {}", from),
+        }
+    }
+    if let Some(object) = config.as_object_mut() {
+        object.insert(String::from("version"), Value::from(CURRENT_VERSION));
+    }
+    Ok(version != CURRENT_VERSION)
+}
+
+/// Unversioned builds saved handles as they were typed into `~add_subscription`, so the same
+/// account could be there both with and without its `@`
+fn normalise_subscriptions(config: &mut Value) {
+    let subscriptions = match config
+        .pointer_mut("/twitter/subscriptions")
+        .and_then(Value::as_array_mut)
+    {
+        Some(subscriptions) => subscriptions,
+        None => return,
+    };
+    let mut handles: Vec<Value> = vec![];
+    for handle in subscriptions.drain(..) {
+        let handle = match handle.as_str() {
+            Some(h) => Value::from(h.trim_start_matches('@')),
+            None => handle,
+        };
+        if !handles.contains(&handle) {
+            handles.push(handle);
+        }
+    }
+    *subscriptions = handles;
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn unversioned_subscriptions_are_normalised() {
+        let mut config = json!({
+            "twitter": { "subscriptions": ["@Polkadot", "Polkadot", "kusamanetwork", "@kusamanetwork"] },
+            "discord": { "channel_id": 42, "token": "" },
+            "coingecko": { "sleep_time_secs": 60, "rules": [{ "PositivePercent": 5.0 }] }
+        });
+
+        assert!(migrate(&mut config).unwrap());
+
+        assert_eq!(config["version"], json!(CURRENT_VERSION));
+        assert_eq!(
+            config["twitter"]["subscriptions"],
+            json!(["Polkadot", "kusamanetwork"])
+        );
+        assert_eq!(config["discord"], json!({ "channel_id": 42, "token": "" }));
+    }
+
+    #[test]
+    fn unversioned_config_without_twitter_is_stamped() {
+        let mut config = json!({ "coingecko": { "sleep_time_secs": 60, "rules": [] } });
+
+        assert!(migrate(&mut config).unwrap());
+        assert_eq!(config["version"], json!(CURRENT_VERSION));
+    }
+
+    #[test]
+    fn current_config_is_left_alone() {
+        let mut config =
+            json!({ "version": CURRENT_VERSION, "twitter": { "subscriptions": ["@a"] } });
+        let before = config.clone();
+
+        assert!(!migrate(&mut config).unwrap());
+        assert_eq!(config, before);
+    }
+
+    #[test]
+    fn newer_and_malformed_configs_are_refused() {
+        assert!(migrate(&mut json!({ "version": CURRENT_VERSION + 1 })).is_err());
+        assert!(migrate(&mut json!(["twitter"])).is_err());
+    }
+
+    #[test]
+    fn stale_tail_is_stripped_from_unversioned_configs() {
+        let contents = "{\n  \"twitter\": {\n    \"subscriptions\": []\n  }\n}\n  }\n  ]\n}\n";
+
+        assert_eq!(
+            strip_stale_tail(contents),
+            Some(json!({ "twitter": { "subscriptions": [] } }))
+        );
+        assert_eq!(strip_stale_tail("{\"twitter\": {}}\n"), None);
+        assert_eq!(strip_stale_tail("{\"version\": 1}\n}"), None);
+        assert_eq!(strip_stale_tail("not json"), None);
+    }
+}
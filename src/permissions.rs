@@ -0,0 +1,229 @@
+use std::{collections::HashMap, fmt, str::FromStr};
+
+use serde::{Deserialize, Serialize};
+
+/// Something a command lets its user do, granted to roles and users
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[serde(rename_all = "snake_case")]
+pub enum Capability {
+    /// Changing what the bot follows, such as twitter accounts and feeds
+    ManageSubscriptions,
+    /// Changing what the bot posts on its own, such as scheduled jobs
+    ManageRules,
+    /// Asking the bot about prices, alerts and portfolios
+    Query,
+}
+
+impl Capability {
+    pub const ALL: [Capability; 3] = [
+        Capability::ManageSubscriptions,
+        Capability::ManageRules,
+        Capability::Query,
+    ];
+
+    /// The capability a command needs, if any
+    pub fn for_command(command: &str) -> Option<Capability> {
+        match command {
+            "add_subscription" | "add_feed" | "remove_feed" | "add_subreddit"
+            | "remove_subreddit" => Some(Capability::ManageSubscriptions),
+            "schedule" | "unschedule" | "audit" => Some(Capability::ManageRules),
+            "schedules" | "subreddits" | "alert" | "alerts" | "portfolio" => {
+                Some(Capability::Query)
+            }
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Capability {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Capability::ManageSubscriptions => "manage_subscriptions",
+            Capability::ManageRules => "manage_rules",
+            Capability::Query => "query",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+impl FromStr for Capability {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Capability::ALL
+            .iter()
+            .find(|c| c.to_string() == s)
+            .copied()
+            .ok_or_else(|| anyhow::anyhow!("Unknown capability {}", s))
+    }
+}
+
+/// Who has a capability. Server administrators have every capability regardless.
+#[derive(Deserialize, Serialize, Debug, Clone, Default)]
+pub struct Grant {
+    #[serde(default)]
+    pub everyone: bool,
+    #[serde(default)]
+    pub roles: Vec<u64>,
+    #[serde(default)]
+    pub users: Vec<u64>,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct PermissionsConfig {
+    #[serde(default)]
+    pub capabilities: HashMap<Capability, Grant>,
+}
+
+impl Default for PermissionsConfig {
+    fn default() -> Self {
+        let mut capabilities = HashMap::new();
+        capabilities.insert(
+            Capability::Query,
+            Grant {
+                everyone: true,
+                ..Grant::default()
+            },
+        );
+        PermissionsConfig { capabilities }
+    }
+}
+
+/// A role or user a capability is granted to
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Grantee {
+    Role(u64),
+    User(u64),
+}
+
+impl Grantee {
+    /// Parses a role or user mention, e.g. `<@&123>` or `<@123>`
+    pub fn parse(mention: &str) -> Result<Grantee, anyhow::Error> {
+        let inner = mention
+            .strip_prefix("<@")
+            .and_then(|m| m.strip_suffix('>'))
+            .ok_or_else(|| anyhow::anyhow!("Expected a role or user mention"))?;
+        match inner.strip_prefix('&') {
+            Some(role) => Ok(Grantee::Role(role.parse()?)),
+            None => Ok(Grantee::User(inner.trim_start_matches('!').parse()?)),
+        }
+    }
+}
+
+impl PermissionsConfig {
+    /// Whether a user, with the given roles, has been granted the capability
+    pub fn allows(&self, capability: Capability, user_id: u64, roles: &[u64]) -> bool {
+        self.capabilities.get(&capability).map_or(false, |grant| {
+            grant.everyone
+                || grant.users.contains(&user_id)
+                || roles.iter().any(|role| grant.roles.contains(role))
+        })
+    }
+
+    pub fn grant(&mut self, capability: Capability, grantee: Grantee) {
+        let grant = self.capabilities.entry(capability).or_default();
+        match grantee {
+            Grantee::Role(id) if !grant.roles.contains(&id) => grant.roles.push(id),
+            Grantee::User(id) if !grant.users.contains(&id) => grant.users.push(id),
+            _ => {}
+        }
+    }
+
+    /// Revokes a grant, returning whether there was one
+    pub fn revoke(&mut self, capability: Capability, grantee: Grantee) -> bool {
+        let grant = match self.capabilities.get_mut(&capability) {
+            Some(grant) => grant,
+            None => return false,
+        };
+        let (ids, id) = match grantee {
+            Grantee::Role(id) => (&mut grant.roles, id),
+            Grantee::User(id) => (&mut grant.users, id),
+        };
+        let before = ids.len();
+        ids.retain(|granted| *granted != id);
+        ids.len() != before
+    }
+}
+
+/// The permissions as changed at runtime, shared with the discord command handlers
+#[cfg(feature = "discord")]
+pub struct Permissions;
+
+#[cfg(feature = "discord")]
+impl serenity::prelude::TypeMapKey for Permissions {
+    type Value = std::sync::Arc<tokio::sync::RwLock<PermissionsConfig>>;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn commands_need_their_capability() {
+        assert_eq!(
+            Capability::for_command("add_feed"),
+            Some(Capability::ManageSubscriptions)
+        );
+        assert_eq!(
+            Capability::for_command("unschedule"),
+            Some(Capability::ManageRules)
+        );
+        assert_eq!(Capability::for_command("alerts"), Some(Capability::Query));
+        // perms is limited to administrators by discord instead
+        assert_eq!(Capability::for_command("perms"), None);
+    }
+
+    #[test]
+    fn capabilities_parse_from_their_names() {
+        for capability in Capability::ALL.iter() {
+            assert_eq!(
+                capability.to_string().parse::<Capability>().unwrap(),
+                *capability
+            );
+        }
+        assert!("admin".parse::<Capability>().is_err());
+    }
+
+    #[test]
+    fn grantees_parse_from_mentions() {
+        assert_eq!(Grantee::parse("<@&123>").unwrap(), Grantee::Role(123));
+        assert_eq!(Grantee::parse("<@456>").unwrap(), Grantee::User(456));
+        // Nickname mentions
+        assert_eq!(Grantee::parse("<@!456>").unwrap(), Grantee::User(456));
+        assert!(Grantee::parse("456").is_err());
+        assert!(Grantee::parse("<@&someone>").is_err());
+        assert!(Grantee::parse("<#789>").is_err());
+    }
+
+    #[test]
+    fn only_query_is_granted_to_everyone_by_default() {
+        let permissions = PermissionsConfig::default();
+
+        assert!(permissions.allows(Capability::Query, 1, &[]));
+        assert!(!permissions.allows(Capability::ManageSubscriptions, 1, &[]));
+        assert!(!permissions.allows(Capability::ManageRules, 1, &[10]));
+    }
+
+    #[test]
+    fn grants_are_allowed_by_user_or_role_until_revoked() {
+        let mut permissions = PermissionsConfig::default();
+        permissions.grant(Capability::ManageRules, Grantee::Role(10));
+        permissions.grant(Capability::ManageRules, Grantee::Role(10));
+        permissions.grant(Capability::ManageRules, Grantee::User(2));
+
+        assert_eq!(
+            permissions.capabilities[&Capability::ManageRules].roles,
+            vec![10]
+        );
+        assert!(permissions.allows(Capability::ManageRules, 1, &[5, 10]));
+        assert!(permissions.allows(Capability::ManageRules, 2, &[]));
+        assert!(!permissions.allows(Capability::ManageRules, 1, &[5]));
+        assert!(!permissions.allows(Capability::ManageSubscriptions, 2, &[10]));
+
+        assert!(permissions.revoke(Capability::ManageRules, Grantee::Role(10)));
+        assert!(!permissions.revoke(Capability::ManageRules, Grantee::Role(10)));
+        assert!(!permissions.revoke(Capability::ManageSubscriptions, Grantee::User(2)));
+        assert!(!permissions.allows(Capability::ManageRules, 1, &[10]));
+        assert!(permissions.allows(Capability::ManageRules, 2, &[]));
+    }
+}
@@ -0,0 +1,408 @@
+use std::{collections::HashMap, sync::Arc};
+
+use coingecko_tokio::Market;
+use serde::{Deserialize, Serialize};
+use tokio::sync::mpsc::{Receiver, Sender};
+
+use crate::{
+    command::{Command, Manager, PortfolioCommand},
+    events::{Event, EventBus},
+    gecko::find_market,
+    lifecycle::{ManagerHandle, Shutdown},
+    storage, Config,
+};
+
+const STATE_PATH: &str = "portfolios.json";
+
+#[derive(Deserialize, Serialize, Debug, Clone, Default)]
+pub struct PortfolioConfig {}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct Position {
+    /// The coingecko id of the coin
+    pub coin: String,
+    pub amount: f64,
+    /// The average price paid per coin
+    pub cost_basis: f64,
+}
+
+/// Every user's positions, keyed by discord user id
+#[derive(Deserialize, Serialize, Debug, Default)]
+struct PortfolioState {
+    portfolios: HashMap<u64, Vec<Position>>,
+}
+
+#[derive(Debug, Clone)]
+pub struct PositionSummary {
+    pub position: Position,
+    pub price: f64,
+    pub value: f64,
+    pub pnl: f64,
+    pub pnl_pct: f64,
+    pub allocation_pct: f64,
+}
+
+/// A user's positions priced at the latest market state
+#[derive(Debug, Clone)]
+pub struct PortfolioSummary {
+    pub positions: Vec<PositionSummary>,
+    pub cost: f64,
+    pub value: f64,
+}
+
+impl PortfolioSummary {
+    fn new(positions: &[Position], markets: &[Market]) -> PortfolioSummary {
+        let priced: Vec<(Position, f64)> = positions
+            .iter()
+            .map(|p| {
+                let price = markets
+                    .iter()
+                    .find(|m| m.id == p.coin)
+                    .map_or(0.0, |m| m.current_price);
+                (p.clone(), price)
+            })
+            .collect();
+        let value: f64 = priced.iter().map(|(p, price)| p.amount * price).sum();
+        let cost: f64 = positions.iter().map(|p| p.amount * p.cost_basis).sum();
+
+        let mut positions: Vec<PositionSummary> = priced
+            .into_iter()
+            .map(|(position, price)| {
+                let cost = position.amount * position.cost_basis;
+                let position_value = position.amount * price;
+                PositionSummary {
+                    price,
+                    value: position_value,
+                    pnl: position_value - cost,
+                    pnl_pct: percent_of(position_value - cost, cost),
+                    allocation_pct: percent_of(position_value, value),
+                    position,
+                }
+            })
+            .collect();
+        positions.sort_by(|a, b| {
+            b.value
+                .partial_cmp(&a.value)
+                .unwrap_or(std::cmp::Ordering::Equal)
+        });
+
+        PortfolioSummary {
+            positions,
+            cost,
+            value,
+        }
+    }
+
+    pub fn pnl(&self) -> f64 {
+        self.value - self.cost
+    }
+
+    pub fn pnl_pct(&self) -> f64 {
+        percent_of(self.pnl(), self.cost)
+    }
+
+    pub fn to_csv(&self) -> String {
+        let mut csv =
+            String::from("coin,amount,cost_basis,price,value,pnl,pnl_percent,allocation_percent\n");
+        for p in &self.positions {
+            csv.push_str(&format!(
+                "{},{},{},{},{:.2},{:.2},{:.2},{:.2}\n",
+                p.position.coin,
+                p.position.amount,
+                p.position.cost_basis,
+                p.price,
+                p.value,
+                p.pnl,
+                p.pnl_pct,
+                p.allocation_pct
+            ));
+        }
+        csv
+    }
+}
+
+fn percent_of(part: f64, total: f64) -> f64 {
+    if total == 0.0 {
+        0.0
+    } else {
+        part / total * 100.0
+    }
+}
+
+impl PortfolioState {
+    fn add(
+        &mut self,
+        user_id: u64,
+        coin: &str,
+        amount: f64,
+        price: f64,
+        markets: &[Market],
+    ) -> Result<(), anyhow::Error> {
+        if !positive(amount) || !positive(price) {
+            return Err(anyhow::anyhow!("Amounts and prices must be positive"));
+        }
+        let market = find_market(markets, coin)
+            .ok_or_else(|| anyhow::anyhow!("Couldn't find a coin called {}", coin))?;
+        let positions = self.portfolios.entry(user_id).or_default();
+        match positions.iter_mut().find(|p| p.coin == market.id) {
+            Some(position) => {
+                let total = position.amount + amount;
+                position.cost_basis =
+                    (position.amount * position.cost_basis + amount * price) / total;
+                position.amount = total;
+            }
+            None => positions.push(Position {
+                coin: market.id.clone(),
+                amount,
+                cost_basis: price,
+            }),
+        }
+        Ok(())
+    }
+
+    /// Removes some or all of a position, keeping the cost basis of what is left
+    fn remove(
+        &mut self,
+        user_id: u64,
+        coin: &str,
+        amount: Option<f64>,
+        markets: &[Market],
+    ) -> Result<(), anyhow::Error> {
+        if amount.map_or(false, |amount| !positive(amount)) {
+            return Err(anyhow::anyhow!("Amounts must be positive"));
+        }
+        let coin = find_market(markets, coin).map_or(coin, |m| m.id.as_str());
+        let positions = self.portfolios.entry(user_id).or_default();
+        let position = positions
+            .iter_mut()
+            .find(|p| p.coin == coin)
+            .ok_or_else(|| anyhow::anyhow!("You don't hold any {}", coin))?;
+        match amount {
+            Some(amount) if amount < position.amount => position.amount -= amount,
+            _ => positions.retain(|p| p.coin != coin),
+        }
+        if positions.is_empty() {
+            self.portfolios.remove(&user_id);
+        }
+        Ok(())
+    }
+}
+
+/// Whether a number given for an amount or price is one, NaN and infinity being parseable
+fn positive(value: f64) -> bool {
+    value.is_finite() && value > 0.0
+}
+
+impl Manager<PortfolioCommand> for PortfolioConfig {
+    fn start_manager(
+        &self,
+        _config: Arc<Config>,
+        mut rx: Receiver<PortfolioCommand>,
+        _tx: Sender<Command>,
+        events: EventBus,
+        mut shutdown: Shutdown,
+    ) -> ManagerHandle {
+        tracing::info!("Starting portfolio manager");
+        let mut events = events.subscribe("portfolio");
+        tokio::spawn(async move {
+            let mut state: PortfolioState = storage::read(STATE_PATH).unwrap_or_else(|e| {
+                tracing::error!("Failed to read portfolios, starting fresh {}", e);
+                PortfolioState::default()
+            });
+            // Positions are priced from the latest coingecko snapshot
+            let mut markets: Option<Arc<Vec<Market>>> = None;
+
+            loop {
+                tokio::select! {
+                    event = events.recv() => match event {
+                        Some(Event::MarketSnapshot { markets: latest, .. }) => {
+                            markets = Some(latest)
+                        }
+                        Some(_) => {}
+                        None => return Ok(()),
+                    },
+                    cmd = rx.recv() => {
+                        let cmd = match cmd {
+                            Some(cmd) => cmd,
+                            None => return Ok(()),
+                        };
+                        let markets = match &markets {
+                            Some(markets) => markets,
+                            None => {
+                                cmd.unavailable();
+                                continue;
+                            }
+                        };
+                        match cmd {
+                            PortfolioCommand::Add(user_id, coin, amount, price, reply) => {
+                                let res = state.add(user_id, &coin, amount, price, markets);
+                                if res.is_ok() {
+                                    persist_state(&state);
+                                }
+                                let _ = reply.send(res);
+                            }
+                            PortfolioCommand::Remove(user_id, coin, amount, reply) => {
+                                let res = state.remove(user_id, &coin, amount, markets);
+                                if res.is_ok() {
+                                    persist_state(&state);
+                                }
+                                let _ = reply.send(res);
+                            }
+                            PortfolioCommand::Summary(user_id, reply) => {
+                                let positions = state
+                                    .portfolios
+                                    .get(&user_id)
+                                    .map_or(&[][..], |p| &p[..]);
+                                let summary = PortfolioSummary::new(positions, markets);
+                                let _ = reply.send(Ok(summary));
+                            }
+                        }
+                    }
+                    _ = shutdown.wait() => return Ok(()),
+                }
+            }
+        })
+    }
+}
+
+impl PortfolioCommand {
+    /// Replies that there are no prices to work with yet
+    fn unavailable(self) {
+        let err = anyhow::anyhow!("Prices aren't available yet");
+        match self {
+            PortfolioCommand::Add(.., reply) | PortfolioCommand::Remove(.., reply) => {
+                let _ = reply.send(Err(err));
+            }
+            PortfolioCommand::Summary(_, reply) => {
+                let _ = reply.send(Err(err));
+            }
+        }
+    }
+}
+
+fn persist_state(state: &PortfolioState) {
+    if let Err(e) = storage::persist(STATE_PATH, state) {
+        tracing::error!("Failed to persist portfolios {}", e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::harness;
+
+    fn markets(coins: &[(&str, f64, i64)]) -> Vec<Market> {
+        serde_json::from_str(&harness::markets(coins)).unwrap()
+    }
+
+    fn position(coin: &str, amount: f64, cost_basis: f64) -> Position {
+        Position {
+            coin: coin.to_string(),
+            amount,
+            cost_basis,
+        }
+    }
+
+    #[test]
+    fn summary_prices_positions() {
+        let positions = [
+            position("ethereum", 2.0, 50.0),
+            position("bitcoin", 1.0, 200.0),
+        ];
+        let summary = PortfolioSummary::new(
+            &positions,
+            &markets(&[("bitcoin", 100.0, 1), ("ethereum", 150.0, 2)]),
+        );
+
+        assert_eq!(summary.cost, 300.0);
+        assert_eq!(summary.value, 400.0);
+        assert_eq!(summary.pnl(), 100.0);
+        assert!((summary.pnl_pct() - 33.33).abs() < 0.01);
+
+        let eth = &summary.positions[0];
+        assert_eq!(eth.position.coin, "ethereum");
+        assert_eq!(eth.value, 300.0);
+        assert_eq!(eth.pnl, 200.0);
+        assert_eq!(eth.pnl_pct, 200.0);
+        assert_eq!(eth.allocation_pct, 75.0);
+        let btc = &summary.positions[1];
+        assert_eq!(btc.pnl, -100.0);
+        assert_eq!(btc.pnl_pct, -50.0);
+        assert_eq!(btc.allocation_pct, 25.0);
+    }
+
+    #[test]
+    fn summary_values_unlisted_coins_at_zero() {
+        let positions = [position("delisted", 10.0, 0.0)];
+        let summary = PortfolioSummary::new(&positions, &markets(&[("bitcoin", 100.0, 1)]));
+
+        assert_eq!(summary.value, 0.0);
+        assert_eq!(summary.pnl_pct(), 0.0);
+        assert_eq!(summary.positions[0].allocation_pct, 0.0);
+    }
+
+    #[test]
+    fn adding_averages_the_cost_basis() {
+        let markets = markets(&[("bitcoin", 100.0, 1)]);
+        let mut state = PortfolioState::default();
+        state.add(1, "bitcoin", 1.0, 100.0, &markets).unwrap();
+        state.add(1, "bit", 3.0, 200.0, &markets).unwrap();
+
+        let positions = &state.portfolios[&1];
+        assert_eq!(positions.len(), 1);
+        assert_eq!(positions[0].amount, 4.0);
+        assert_eq!(positions[0].cost_basis, 175.0);
+    }
+
+    #[test]
+    fn adding_rejects_bad_amounts_and_unknown_coins() {
+        let markets = markets(&[("bitcoin", 100.0, 1)]);
+        let mut state = PortfolioState::default();
+
+        assert!(state.add(1, "bitcoin", 0.0, 100.0, &markets).is_err());
+        assert!(state.add(1, "bitcoin", -1.0, 100.0, &markets).is_err());
+        assert!(state.add(1, "bitcoin", f64::NAN, 100.0, &markets).is_err());
+        assert!(state
+            .add(1, "bitcoin", f64::INFINITY, 100.0, &markets)
+            .is_err());
+        assert!(state.add(1, "bitcoin", 1.0, -1.0, &markets).is_err());
+        assert!(state.add(1, "bitcoin", 1.0, 0.0, &markets).is_err());
+        assert!(state.add(1, "bitcoin", 1.0, f64::NAN, &markets).is_err());
+        assert!(state.add(1, "dogecoin", 1.0, 1.0, &markets).is_err());
+        assert!(state.portfolios.is_empty());
+    }
+
+    #[test]
+    fn removing_keeps_the_cost_basis() {
+        let markets = markets(&[("bitcoin", 100.0, 1), ("ethereum", 10.0, 2)]);
+        let mut state = PortfolioState::default();
+        state.add(1, "bitcoin", 4.0, 175.0, &markets).unwrap();
+        state.add(1, "ethereum", 1.0, 10.0, &markets).unwrap();
+
+        state.remove(1, "bitcoin", Some(1.5), &markets).unwrap();
+        let btc = &state.portfolios[&1][0];
+        assert_eq!(btc.amount, 2.5);
+        assert_eq!(btc.cost_basis, 175.0);
+
+        state.remove(1, "bitcoin", Some(10.0), &markets).unwrap();
+        assert_eq!(state.portfolios[&1].len(), 1);
+        assert!(state.remove(1, "bitcoin", None, &markets).is_err());
+
+        state.remove(1, "ethereum", None, &markets).unwrap();
+        assert!(state.portfolios.is_empty());
+    }
+
+    #[test]
+    fn removing_rejects_bad_amounts() {
+        let markets = markets(&[("bitcoin", 100.0, 1)]);
+        let mut state = PortfolioState::default();
+        state.add(1, "bitcoin", 4.0, 175.0, &markets).unwrap();
+
+        assert!(state.remove(1, "bitcoin", Some(-1.0), &markets).is_err());
+        assert!(state.remove(1, "bitcoin", Some(0.0), &markets).is_err());
+        assert!(state
+            .remove(1, "bitcoin", Some(f64::NAN), &markets)
+            .is_err());
+        assert_eq!(state.portfolios[&1][0].amount, 4.0);
+    }
+}
@@ -0,0 +1,546 @@
+use std::{
+    cmp::Ordering,
+    collections::{HashMap, VecDeque},
+    sync::Arc,
+};
+
+use serde::{Deserialize, Serialize};
+use tokio::sync::mpsc::{Receiver, Sender};
+
+use crate::{
+    command::{Command, Manager, RedditCommand},
+    events::{self, Event, EventBus},
+    lifecycle::{ManagerHandle, Shutdown},
+    storage, Config,
+};
+
+const STATE_PATH: &str = "reddit_state.json";
+const USER_AGENT: &str = concat!("honorable-bot/", env!("CARGO_PKG_VERSION"));
+/// How many posted ids to remember per subreddit, comfortably more than a listing page holds
+const MAX_REMEMBERED: usize = 500;
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct RedditConfig {
+    #[serde(default = "default_sleep_time_secs")]
+    pub sleep_time_secs: u64,
+    #[serde(default = "default_api_base")]
+    pub api_base: String,
+    #[serde(default)]
+    pub subreddits: Vec<SubredditConfig>,
+}
+
+fn default_sleep_time_secs() -> u64 {
+    120
+}
+
+fn default_api_base() -> String {
+    String::from("https://www.reddit.com")
+}
+
+impl Default for RedditConfig {
+    fn default() -> Self {
+        RedditConfig {
+            sleep_time_secs: default_sleep_time_secs(),
+            api_base: default_api_base(),
+            subreddits: vec![],
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct SubredditConfig {
+    pub name: String,
+    #[serde(default = "default_listings")]
+    pub listings: Vec<Listing>,
+    #[serde(default)]
+    pub min_score: i64,
+    /// Only posts with one of these flairs are sent, any flair when empty
+    #[serde(default)]
+    pub flairs: Vec<String>,
+}
+
+impl SubredditConfig {
+    /// A subreddit with the default filters, which is what adding one from chat watches
+    fn named(name: &str) -> SubredditConfig {
+        SubredditConfig {
+            name: name.trim_start_matches("r/").to_string(),
+            listings: default_listings(),
+            min_score: 0,
+            flairs: vec![],
+        }
+    }
+}
+
+fn default_listings() -> Vec<Listing> {
+    vec![Listing::New, Listing::Hot]
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, Copy)]
+pub enum Listing {
+    New,
+    Hot,
+}
+
+impl Listing {
+    fn path(&self) -> &'static str {
+        match self {
+            Listing::New => "new",
+            Listing::Hot => "hot",
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct RedditPost {
+    pub id: String,
+    pub subreddit: String,
+    pub title: String,
+    pub author: String,
+    pub score: i64,
+    pub permalink: String,
+    #[serde(default)]
+    pub thumbnail: String,
+    pub link_flair_text: Option<String>,
+    #[serde(default)]
+    pub stickied: bool,
+    #[serde(default)]
+    pub created_utc: f64,
+}
+
+impl RedditPost {
+    pub fn url(&self) -> String {
+        format!("https://www.reddit.com{}", self.permalink)
+    }
+
+    fn matches(&self, filter: &SubredditConfig) -> bool {
+        let flair_matches = filter.flairs.is_empty()
+            || self.link_flair_text.as_ref().map_or(false, |flair| {
+                filter.flairs.iter().any(|f| f.eq_ignore_ascii_case(flair))
+            });
+        !self.stickied && self.score >= filter.min_score && flair_matches
+    }
+}
+
+#[derive(Deserialize, Debug)]
+struct Thing<T> {
+    data: T,
+}
+
+#[derive(Deserialize, Debug)]
+struct Listings {
+    children: Vec<Thing<RedditPost>>,
+}
+
+/// The ids of the posts already sent for each subreddit, newest last
+#[derive(Deserialize, Serialize, Debug, Default)]
+struct RedditState {
+    posted: HashMap<String, VecDeque<String>>,
+}
+
+impl Manager<RedditCommand> for RedditConfig {
+    fn start_manager(
+        &self,
+        config: Arc<Config>,
+        mut rx: Receiver<RedditCommand>,
+        _tx: Sender<Command>,
+        events: EventBus,
+        mut shutdown: Shutdown,
+    ) -> ManagerHandle {
+        tracing::info!("Starting reddit manager");
+        tokio::spawn(async move {
+            let client = reqwest::Client::builder().user_agent(USER_AGENT).build()?;
+            let api_base = config.reddit.api_base.clone();
+            let mut subreddits = config.reddit.subreddits.clone();
+            let mut state: RedditState = storage::read(STATE_PATH).unwrap_or_else(|e| {
+                tracing::error!("Failed to read reddit state, starting fresh {}", e);
+                RedditState::default()
+            });
+            let mut interval = tokio::time::interval(tokio::time::Duration::from_secs(
+                config.reddit.sleep_time_secs,
+            ));
+
+            loop {
+                tokio::select! {
+                    _ = interval.tick() => {
+                        for subreddit in &subreddits {
+                            poll_subreddit(&client, &api_base, &events, &mut state, subreddit)
+                                .await;
+                        }
+                        persist_state(&state);
+                    }
+                    cmd = rx.recv() => match cmd {
+                        Some(RedditCommand::AddSubreddit(name, actor, reply)) => {
+                            let subreddit = SubredditConfig::named(&name);
+                            if watches(&subreddits, &subreddit.name) {
+                                let _ = reply.send(Err(anyhow::anyhow!(
+                                    "r/{} is already watched",
+                                    subreddit.name
+                                )));
+                                continue;
+                            }
+                            let action = format!("add_subreddit {}", subreddit.name);
+                            let added = subreddit.clone();
+                            let persisted = Config::modify(&actor, &action, |config| {
+                                config.reddit.subreddits.push(added)
+                            });
+                            let res = update_subreddits(&mut subreddits, persisted);
+                            if res.is_ok() {
+                                // Records what's there now, so only newer posts are published
+                                poll_subreddit(&client, &api_base, &events, &mut state, &subreddit)
+                                    .await;
+                                persist_state(&state);
+                            }
+                            let _ = reply.send(res);
+                        }
+                        Some(RedditCommand::RemoveSubreddit(name, actor, reply)) => {
+                            let name = name.trim_start_matches("r/").to_string();
+                            if !watches(&subreddits, &name) {
+                                let _ = reply.send(Err(anyhow::anyhow!("r/{} isn't watched", name)));
+                                continue;
+                            }
+                            let action = format!("remove_subreddit {}", name);
+                            let persisted = Config::modify(&actor, &action, |config| {
+                                config
+                                    .reddit
+                                    .subreddits
+                                    .retain(|s| !s.name.eq_ignore_ascii_case(&name))
+                            });
+                            let res = update_subreddits(&mut subreddits, persisted);
+                            if res.is_ok() {
+                                state.posted.retain(|s, _| !s.eq_ignore_ascii_case(&name));
+                                persist_state(&state);
+                            }
+                            let _ = reply.send(res);
+                        }
+                        Some(RedditCommand::ListSubreddits(reply)) => {
+                            let _ = reply.send(subreddits.iter().map(|s| s.name.clone()).collect());
+                        }
+                        None => return Ok(()),
+                    },
+                    _ = shutdown.wait() => return Ok(()),
+                }
+            }
+        })
+    }
+}
+
+fn watches(subreddits: &[SubredditConfig], name: &str) -> bool {
+    subreddits.iter().any(|s| s.name.eq_ignore_ascii_case(name))
+}
+
+/// Applies subreddit changes the moment they're persisted, rather than on the next restart
+fn update_subreddits(
+    subreddits: &mut Vec<SubredditConfig>,
+    persisted: Result<Config, anyhow::Error>,
+) -> Result<(), anyhow::Error> {
+    match persisted {
+        Ok(persisted) => {
+            *subreddits = persisted.reddit.subreddits;
+            Ok(())
+        }
+        Err(e) => {
+            tracing::error!("Failed to persist config {}", e);
+            Err(e)
+        }
+    }
+}
+
+fn persist_state(state: &RedditState) {
+    if let Err(e) = storage::persist(STATE_PATH, state) {
+        tracing::error!("Failed to persist reddit state {}", e);
+    }
+}
+
+async fn fetch_listing(
+    client: &reqwest::Client,
+    api_base: &str,
+    subreddit: &str,
+    listing: Listing,
+) -> Result<Vec<RedditPost>, anyhow::Error> {
+    let listing: Thing<Listings> = client
+        .get(format!(
+            "{}/r/{}/{}.json",
+            api_base.trim_end_matches('/'),
+            subreddit,
+            listing.path()
+        ))
+        .query(&[("limit", "50")])
+        .send()
+        .await?
+        .error_for_status()?
+        .json()
+        .await?;
+
+    Ok(listing.data.children.into_iter().map(|c| c.data).collect())
+}
+
+/// Publishes the posts passing the subreddit's filters that haven't been sent yet, oldest first
+/// across every listing. On the first poll of a subreddit the matching posts are only recorded,
+/// rather than flooding the channel. A poll where no listing could be fetched doesn't count as
+/// the first, so the posts it missed aren't all published on the next one.
+async fn poll_subreddit(
+    client: &reqwest::Client,
+    api_base: &str,
+    events: &EventBus,
+    state: &mut RedditState,
+    subreddit: &SubredditConfig,
+) {
+    let first_poll = !state.posted.contains_key(&subreddit.name);
+
+    let mut fetched = false;
+    let mut found: Vec<RedditPost> = vec![];
+    for listing in &subreddit.listings {
+        let posts = match fetch_listing(client, api_base, &subreddit.name, *listing).await {
+            Ok(posts) => posts,
+            Err(e) => {
+                tracing::error!("Failed to fetch r/{} {:?} {}", subreddit.name, listing, e);
+                continue;
+            }
+        };
+        fetched = true;
+        let posted = state.posted.get(&subreddit.name);
+        for post in posts {
+            if !post.matches(subreddit)
+                || posted.map_or(false, |posted| posted.contains(&post.id))
+                || found.iter().any(|p| p.id == post.id)
+            {
+                continue;
+            }
+            found.push(post);
+        }
+    }
+    if !fetched {
+        return;
+    }
+    let posted = state.posted.entry(subreddit.name.clone()).or_default();
+    found.sort_by(|a, b| {
+        a.created_utc
+            .partial_cmp(&b.created_utc)
+            .unwrap_or(Ordering::Equal)
+    });
+
+    for post in found {
+        posted.push_back(post.id.clone());
+        if posted.len() > MAX_REMEMBERED {
+            posted.pop_front();
+        }
+        if first_poll {
+            continue;
+        }
+        events::publish(events, Event::RedditPostFound(post));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::sync::mpsc;
+    use warp::http::{Method, StatusCode};
+
+    use super::*;
+    use crate::harness::MockServer;
+
+    fn post(id: &str, created_utc: f64) -> serde_json::Value {
+        serde_json::json!({
+            "id": id,
+            "subreddit": "polkadot",
+            "title": format!("Post {}", id),
+            "author": "gavofyork",
+            "score": 10,
+            "permalink": format!("/r/polkadot/comments/{}/", id),
+            "link_flair_text": null,
+            "stickied": false,
+            "created_utc": created_utc
+        })
+    }
+
+    fn listing(posts: &[serde_json::Value]) -> String {
+        let children: Vec<_> = posts
+            .iter()
+            .map(|post| serde_json::json!({ "kind": "t3", "data": post }))
+            .collect();
+        serde_json::json!({ "kind": "Listing", "data": { "children": children } }).to_string()
+    }
+
+    fn subreddit() -> SubredditConfig {
+        SubredditConfig {
+            name: String::from("polkadot"),
+            listings: default_listings(),
+            min_score: 0,
+            flairs: vec![],
+        }
+    }
+
+    fn found(rx: &mut mpsc::Receiver<Event>) -> Vec<String> {
+        events::drain(rx)
+            .into_iter()
+            .filter_map(|event| match event {
+                Event::RedditPostFound(post) => Some(post.id),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn new_posts_are_published_oldest_first_across_listings() {
+        let server = MockServer::new()
+            .respond(
+                Method::GET,
+                "/r/polkadot/new.json",
+                StatusCode::OK,
+                &listing(&[post("a", 100.0)]),
+            )
+            .respond(
+                Method::GET,
+                "/r/polkadot/new.json",
+                StatusCode::OK,
+                &listing(&[post("d", 400.0), post("b", 200.0), post("a", 100.0)]),
+            )
+            .respond(
+                Method::GET,
+                "/r/polkadot/hot.json",
+                StatusCode::OK,
+                &listing(&[post("a", 100.0)]),
+            )
+            .respond(
+                Method::GET,
+                "/r/polkadot/hot.json",
+                StatusCode::OK,
+                &listing(&[post("c", 300.0), post("b", 200.0)]),
+            )
+            .start();
+        let client = reqwest::Client::new();
+        let bus = EventBus::new();
+        let mut rx = bus.subscribe("test");
+        let mut state = RedditState::default();
+
+        poll_subreddit(&client, &server.base, &bus, &mut state, &subreddit()).await;
+        assert!(found(&mut rx).is_empty());
+
+        poll_subreddit(&client, &server.base, &bus, &mut state, &subreddit()).await;
+        assert_eq!(found(&mut rx), vec!["b", "c", "d"]);
+
+        poll_subreddit(&client, &server.base, &bus, &mut state, &subreddit()).await;
+        assert!(found(&mut rx).is_empty());
+        assert_eq!(
+            server.requests_to("/r/polkadot/new.json")[0].query,
+            "limit=50"
+        );
+    }
+
+    #[tokio::test]
+    async fn posts_failing_the_filters_are_skipped() {
+        let mut stickied = post("stickied", 200.0);
+        stickied["stickied"] = serde_json::json!(true);
+        let mut low_score = post("low_score", 300.0);
+        low_score["score"] = serde_json::json!(1);
+        let mut other_flair = post("other_flair", 400.0);
+        other_flair["link_flair_text"] = serde_json::json!("Meme");
+        let mut news = post("news", 500.0);
+        news["link_flair_text"] = serde_json::json!("News");
+        let server = MockServer::new()
+            .respond(
+                Method::GET,
+                "/r/polkadot/new.json",
+                StatusCode::OK,
+                &listing(&[]),
+            )
+            .respond(
+                Method::GET,
+                "/r/polkadot/new.json",
+                StatusCode::OK,
+                &listing(&[news, other_flair, low_score, stickied]),
+            )
+            .start();
+        let subreddit = SubredditConfig {
+            listings: vec![Listing::New],
+            min_score: 5,
+            flairs: vec![String::from("news")],
+            ..subreddit()
+        };
+        let client = reqwest::Client::new();
+        let bus = EventBus::new();
+        let mut rx = bus.subscribe("test");
+        let mut state = RedditState::default();
+
+        poll_subreddit(&client, &server.base, &bus, &mut state, &subreddit).await;
+        poll_subreddit(&client, &server.base, &bus, &mut state, &subreddit).await;
+
+        assert_eq!(found(&mut rx), vec!["news"]);
+    }
+
+    #[tokio::test]
+    async fn a_failing_listing_leaves_the_others_polled() {
+        let server = MockServer::new()
+            .respond(
+                Method::GET,
+                "/r/polkadot/new.json",
+                StatusCode::SERVICE_UNAVAILABLE,
+                "",
+            )
+            .respond(
+                Method::GET,
+                "/r/polkadot/hot.json",
+                StatusCode::OK,
+                &listing(&[post("a", 100.0)]),
+            )
+            .start();
+        let bus = EventBus::new();
+        let mut state = RedditState::default();
+
+        poll_subreddit(
+            &reqwest::Client::new(),
+            &server.base,
+            &bus,
+            &mut state,
+            &subreddit(),
+        )
+        .await;
+
+        assert_eq!(state.posted["polkadot"], vec![String::from("a")]);
+    }
+
+    #[tokio::test]
+    async fn a_subreddit_isnt_recorded_until_a_listing_is_fetched() {
+        let server = MockServer::new()
+            .respond(
+                Method::GET,
+                "/r/polkadot/new.json",
+                StatusCode::SERVICE_UNAVAILABLE,
+                "",
+            )
+            .respond(
+                Method::GET,
+                "/r/polkadot/new.json",
+                StatusCode::OK,
+                &listing(&[post("b", 200.0), post("a", 100.0)]),
+            )
+            .start();
+        let subreddit = SubredditConfig {
+            listings: vec![Listing::New],
+            ..subreddit()
+        };
+        let client = reqwest::Client::new();
+        let bus = EventBus::new();
+        let mut rx = bus.subscribe("test");
+        let mut state = RedditState::default();
+
+        poll_subreddit(&client, &server.base, &bus, &mut state, &subreddit).await;
+        assert!(!state.posted.contains_key("polkadot"));
+
+        // The first poll that reaches reddit only records what's there
+        poll_subreddit(&client, &server.base, &bus, &mut state, &subreddit).await;
+        assert!(found(&mut rx).is_empty());
+        assert_eq!(state.posted["polkadot"].len(), 2);
+    }
+
+    #[test]
+    fn subreddits_added_from_chat_use_the_default_filters() {
+        let subreddit = SubredditConfig::named("r/Polkadot");
+
+        assert_eq!(subreddit.name, "Polkadot");
+        assert_eq!(subreddit.listings.len(), 2);
+        assert_eq!(subreddit.min_score, 0);
+        assert!(subreddit.flairs.is_empty());
+        assert!(watches(&[subreddit], "polkadot"));
+    }
+}
@@ -0,0 +1,338 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::Arc,
+};
+
+use serde::{Deserialize, Serialize};
+use tokio::sync::mpsc::{Receiver, Sender};
+
+use crate::{
+    audit::Actor,
+    command::{Command, Manager, RssCommand},
+    events::{self, Event, EventBus},
+    lifecycle::{ManagerHandle, Shutdown},
+    storage, Config,
+};
+
+const STATE_PATH: &str = "rss_state.json";
+/// How many item ids to remember per feed, comfortably more than a feed lists at once
+const MAX_REMEMBERED: usize = 500;
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct RssConfig {
+    #[serde(default = "default_sleep_time_secs")]
+    pub sleep_time_secs: u64,
+    #[serde(default)]
+    pub feeds: Vec<String>,
+}
+
+fn default_sleep_time_secs() -> u64 {
+    300
+}
+
+impl Default for RssConfig {
+    fn default() -> Self {
+        RssConfig {
+            sleep_time_secs: default_sleep_time_secs(),
+            feeds: vec![],
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct FeedItem {
+    pub feed_title: String,
+    pub title: String,
+    pub link: Option<String>,
+    pub summary: Option<String>,
+}
+
+/// The GUIDs of the items already published from each feed, keyed by feed url, newest last.
+/// Some feeds drop items and list them again later, so these outlive the items in the feed.
+#[derive(Deserialize, Serialize, Debug, Default)]
+struct RssState {
+    seen: HashMap<String, VecDeque<String>>,
+}
+
+impl Manager<RssCommand> for RssConfig {
+    fn start_manager(
+        &self,
+        config: Arc<Config>,
+        mut rx: Receiver<RssCommand>,
+        _tx: Sender<Command>,
+        events: EventBus,
+        mut shutdown: Shutdown,
+    ) -> ManagerHandle {
+        tracing::info!("Starting rss manager");
+        tokio::spawn(async move {
+            let client = reqwest::Client::new();
+            let mut feeds = config.rss.feeds.clone();
+            let mut state: RssState = storage::read(STATE_PATH).unwrap_or_else(|e| {
+                tracing::error!("Failed to read rss state, starting fresh {}", e);
+                RssState::default()
+            });
+            let mut interval =
+                tokio::time::interval(tokio::time::Duration::from_secs(config.rss.sleep_time_secs));
+
+            loop {
+                tokio::select! {
+                    _ = interval.tick() => {
+                        for feed in &feeds {
+                            poll_feed(&client, &events, &mut state, feed).await;
+                        }
+                        persist_state(&state);
+                    }
+                    cmd = rx.recv() => match cmd {
+                        Some(RssCommand::AddFeed(url, actor)) => {
+                            if feeds.contains(&url) {
+                                continue;
+                            }
+                            feeds.push(url.clone());
+                            persist_feeds(&feeds, &actor, &format!("add_feed {}", url));
+                            poll_feed(&client, &events, &mut state, &url).await;
+                            persist_state(&state);
+                        }
+                        Some(RssCommand::RemoveFeed(url, actor)) => {
+                            feeds.retain(|feed| feed != &url);
+                            persist_feeds(&feeds, &actor, &format!("remove_feed {}", url));
+                            state.seen.remove(&url);
+                            persist_state(&state);
+                        }
+                        None => return Ok(()),
+                    },
+                    _ = shutdown.wait() => return Ok(()),
+                }
+            }
+        })
+    }
+}
+
+fn persist_feeds(feeds: &[String], actor: &Actor, action: &str) {
+    let persisted = Config::modify(actor, action, |config| config.rss.feeds = feeds.to_vec());
+    if let Err(e) = persisted {
+        tracing::error!("Failed to persist config {}", e);
+    }
+}
+
+fn persist_state(state: &RssState) {
+    if let Err(e) = storage::persist(STATE_PATH, state) {
+        tracing::error!("Failed to persist rss state {}", e);
+    }
+}
+
+async fn fetch_feed(
+    client: &reqwest::Client,
+    url: &str,
+) -> Result<feed_rs::model::Feed, anyhow::Error> {
+    let body = client
+        .get(url)
+        .send()
+        .await?
+        .error_for_status()?
+        .bytes()
+        .await?;
+    Ok(feed_rs::parser::parse(&body[..])?)
+}
+
+/// Publishes any items we haven't seen before, oldest first. The first time a
+/// feed is polled its current items are only recorded, rather than flooding the channel.
+async fn poll_feed(client: &reqwest::Client, events: &EventBus, state: &mut RssState, url: &str) {
+    let feed = match fetch_feed(client, url).await {
+        Ok(feed) => feed,
+        Err(e) => {
+            tracing::error!("Failed to fetch feed {} {}", url, e);
+            return;
+        }
+    };
+
+    let feed_title = feed
+        .title
+        .map(|t| t.content)
+        .unwrap_or_else(|| url.to_string());
+    let first_poll = !state.seen.contains_key(url);
+    let seen = state.seen.entry(url.to_string()).or_default();
+
+    let mut new_items: Vec<_> = feed
+        .entries
+        .into_iter()
+        .filter(|entry| !seen.contains(&entry.id))
+        .collect();
+    new_items.sort_by_key(|entry| entry.published.or(entry.updated));
+
+    for entry in new_items {
+        seen.push_back(entry.id.clone());
+        if seen.len() > MAX_REMEMBERED {
+            seen.pop_front();
+        }
+        if first_poll {
+            continue;
+        }
+        let item = FeedItem {
+            feed_title: feed_title.clone(),
+            title: entry
+                .title
+                .map(|t| t.content)
+                .unwrap_or_else(|| String::from("Untitled")),
+            link: entry.links.first().map(|l| l.href.clone()),
+            summary: entry.summary.map(|s| s.content),
+        };
+        events::publish(events, Event::FeedItemPublished(item));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::sync::mpsc;
+    use warp::http::{Method, StatusCode};
+
+    use super::*;
+    use crate::harness::MockServer;
+
+    /// An rss document with an item per `(guid, title, published)`
+    fn feed(items: &[(&str, &str, &str)]) -> String {
+        let items: String = items
+            .iter()
+            .map(|(guid, title, published)| {
+                format!(
+                    "<item><guid>{}</guid><title>{}</title>\
+                     <link>https://polkadot.network/blog/{}</link>\
+                     <pubDate>{}</pubDate></item>",
+                    guid, title, guid, published
+                )
+            })
+            .collect();
+        format!(
+            "<?xml version=\"1.0\"?><rss version=\"2.0\"><channel>\
+             <title>Polkadot Blog</title><link>https://polkadot.network/blog</link>\
+             {}</channel></rss>",
+            items
+        )
+    }
+
+    fn published(rx: &mut mpsc::Receiver<Event>) -> Vec<String> {
+        events::drain(rx)
+            .into_iter()
+            .filter_map(|event| match event {
+                Event::FeedItemPublished(item) => Some(item.title),
+                _ => None,
+            })
+            .collect()
+    }
+
+    const MONDAY: &str = "Mon, 03 May 2021 09:00:00 GMT";
+    const TUESDAY: &str = "Tue, 04 May 2021 09:00:00 GMT";
+    const WEDNESDAY: &str = "Wed, 05 May 2021 09:00:00 GMT";
+
+    #[tokio::test]
+    async fn new_items_are_published_oldest_first_after_the_first_poll() {
+        let server = MockServer::new()
+            .respond(
+                Method::GET,
+                "/feed.xml",
+                StatusCode::OK,
+                &feed(&[("a", "First", MONDAY)]),
+            )
+            .respond(
+                Method::GET,
+                "/feed.xml",
+                StatusCode::OK,
+                &feed(&[
+                    ("c", "Third", WEDNESDAY),
+                    ("b", "Second", TUESDAY),
+                    ("a", "First", MONDAY),
+                ]),
+            )
+            .start();
+        let url = format!("{}/feed.xml", server.base);
+        let client = reqwest::Client::new();
+        let bus = EventBus::new();
+        let mut rx = bus.subscribe("test");
+        let mut state = RssState::default();
+
+        poll_feed(&client, &bus, &mut state, &url).await;
+        assert!(published(&mut rx).is_empty());
+
+        poll_feed(&client, &bus, &mut state, &url).await;
+        assert_eq!(published(&mut rx), vec!["Second", "Third"]);
+
+        poll_feed(&client, &bus, &mut state, &url).await;
+        assert!(published(&mut rx).is_empty());
+    }
+
+    #[tokio::test]
+    async fn items_listed_again_are_not_republished() {
+        let server = MockServer::new()
+            .respond(
+                Method::GET,
+                "/feed.xml",
+                StatusCode::OK,
+                &feed(&[("a", "First", MONDAY)]),
+            )
+            .respond(
+                Method::GET,
+                "/feed.xml",
+                StatusCode::OK,
+                &feed(&[("b", "Second", TUESDAY)]),
+            )
+            .respond(
+                Method::GET,
+                "/feed.xml",
+                StatusCode::OK,
+                &feed(&[("b", "Second", TUESDAY), ("a", "First", MONDAY)]),
+            )
+            .start();
+        let url = format!("{}/feed.xml", server.base);
+        let client = reqwest::Client::new();
+        let bus = EventBus::new();
+        let mut rx = bus.subscribe("test");
+        let mut state = RssState::default();
+
+        for _ in 0..3 {
+            poll_feed(&client, &bus, &mut state, &url).await;
+        }
+
+        assert_eq!(published(&mut rx), vec!["Second"]);
+    }
+
+    #[tokio::test]
+    async fn the_oldest_ids_are_forgotten_past_the_limit() {
+        let server = MockServer::new()
+            .respond(
+                Method::GET,
+                "/feed.xml",
+                StatusCode::OK,
+                &feed(&[("new", "New", WEDNESDAY)]),
+            )
+            .start();
+        let url = format!("{}/feed.xml", server.base);
+        let bus = EventBus::new();
+        let mut rx = bus.subscribe("test");
+        let mut state = RssState::default();
+        state.seen.insert(
+            url.clone(),
+            (0..MAX_REMEMBERED).map(|i| i.to_string()).collect(),
+        );
+
+        poll_feed(&reqwest::Client::new(), &bus, &mut state, &url).await;
+
+        assert_eq!(published(&mut rx), vec!["New"]);
+        let seen = &state.seen[&url];
+        assert_eq!(seen.len(), MAX_REMEMBERED);
+        assert_eq!(seen.front().map(String::as_str), Some("1"));
+        assert_eq!(seen.back().map(String::as_str), Some("new"));
+    }
+
+    #[tokio::test]
+    async fn feeds_that_fail_to_fetch_are_left_unrecorded() {
+        let server = MockServer::new()
+            .respond(Method::GET, "/feed.xml", StatusCode::NOT_FOUND, "")
+            .start();
+        let url = format!("{}/feed.xml", server.base);
+        let bus = EventBus::new();
+        let mut state = RssState::default();
+
+        poll_feed(&reqwest::Client::new(), &bus, &mut state, &url).await;
+
+        assert!(state.seen.is_empty());
+    }
+}
@@ -0,0 +1,413 @@
+use std::{fmt, str::FromStr, sync::Arc};
+
+use chrono::{DateTime, Utc};
+use chrono_tz::Tz;
+use cron::Schedule;
+use serde::{Deserialize, Serialize};
+use tokio::{
+    sync::mpsc::{Receiver, Sender},
+    time::Duration,
+};
+
+#[cfg(feature = "coingecko")]
+use crate::command::CoingeckoCommand;
+use crate::{
+    command::{Command, Manager, SchedulerCommand},
+    events::{self, Event, EventBus},
+    lifecycle::{ManagerHandle, Shutdown},
+    storage, Config,
+};
+
+const STATE_PATH: &str = "schedules.json";
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct SchedulerConfig {
+    /// The timezone schedules are evaluated in when none is given, e.g. `Europe/London`
+    #[serde(default = "default_timezone")]
+    pub timezone: String,
+}
+
+fn default_timezone() -> String {
+    String::from("UTC")
+}
+
+impl Default for SchedulerConfig {
+    fn default() -> Self {
+        SchedulerConfig {
+            timezone: default_timezone(),
+        }
+    }
+}
+
+/// Something a job asks a manager to do when it runs
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub enum JobTask {
+    /// Posts the top coins by market cap
+    TopCoins(usize),
+    /// Posts the named market digest, covering the time since it last ran
+    Digest(String),
+}
+
+impl JobTask {
+    /// Parses a task as written in a chat command, e.g. `top 10` or `digest daily`
+    pub fn parse(words: &[&str]) -> Result<JobTask, anyhow::Error> {
+        match words {
+            ["top", n] => Ok(JobTask::TopCoins(n.parse()?)),
+            ["digest", name] => Ok(JobTask::Digest(name.to_string())),
+            _ => Err(anyhow::anyhow!(
+                "Unknown task, expected `top <count>` or `digest <name>`"
+            )),
+        }
+    }
+
+    /// The command that carries the task out, which there is none of when this build doesn't
+    /// have the manager it's for
+    #[cfg(feature = "coingecko")]
+    fn command(&self) -> Option<Command> {
+        Some(match self {
+            JobTask::TopCoins(n) => Command::Coingecko(CoingeckoCommand::Top(*n)),
+            JobTask::Digest(name) => Command::Coingecko(CoingeckoCommand::Digest(name.clone())),
+        })
+    }
+
+    #[cfg(not(feature = "coingecko"))]
+    fn command(&self) -> Option<Command> {
+        None
+    }
+}
+
+impl fmt::Display for JobTask {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            JobTask::TopCoins(n) => write!(f, "top {}", n),
+            JobTask::Digest(name) => write!(f, "digest {}", name),
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct Job {
+    pub id: u64,
+    pub schedule: String,
+    pub timezone: String,
+    pub task: JobTask,
+    /// Set for jobs registered by a manager, which registers them again each time it starts
+    /// rather than having them persisted
+    #[serde(skip)]
+    pub owner: Option<String>,
+}
+
+/// The jobs added through chat commands, which survive restarts
+#[derive(Deserialize, Serialize, Debug, Default)]
+pub struct SchedulerState {
+    next_id: u64,
+    jobs: Vec<Job>,
+}
+
+/// Where the scheduler gets the time from, so tests can move it along themselves
+pub trait Clock: Send + Sync {
+    fn now(&self) -> DateTime<Utc>;
+}
+
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> DateTime<Utc> {
+        Utc::now()
+    }
+}
+
+/// Parses a cron expression, accepting the usual five fields as well as the cron crate's
+/// six or seven, which start with seconds
+pub fn parse_schedule(expression: &str) -> Result<Schedule, anyhow::Error> {
+    let expression = if expression.split_whitespace().count() == 5 {
+        format!("0 {}", expression)
+    } else {
+        expression.to_string()
+    };
+    Schedule::from_str(&expression).map_err(|e| anyhow::anyhow!("Invalid schedule {}", e))
+}
+
+struct ScheduledJob {
+    job: Job,
+    schedule: Schedule,
+    timezone: Tz,
+    next: Option<DateTime<Utc>>,
+}
+
+pub struct Scheduler<C: Clock> {
+    clock: C,
+    next_id: u64,
+    jobs: Vec<ScheduledJob>,
+}
+
+impl<C: Clock> Scheduler<C> {
+    pub fn new(clock: C, state: SchedulerState) -> Scheduler<C> {
+        let mut scheduler = Scheduler {
+            clock,
+            next_id: state.next_id,
+            jobs: vec![],
+        };
+        for job in state.jobs {
+            let id = job.id;
+            if let Err(e) = scheduler.insert(job) {
+                tracing::error!("Failed to restore scheduled job {} {}", id, e);
+            }
+        }
+        scheduler
+    }
+
+    /// Adds a job, replacing any job the owner registered before with the same task
+    pub fn schedule(
+        &mut self,
+        schedule: &str,
+        timezone: &str,
+        task: JobTask,
+        owner: Option<String>,
+    ) -> Result<u64, anyhow::Error> {
+        if owner.is_some() {
+            self.jobs
+                .retain(|s| s.job.owner != owner || s.job.task != task);
+        }
+        let id = self.next_id;
+        self.insert(Job {
+            id,
+            schedule: schedule.to_string(),
+            timezone: timezone.to_string(),
+            task,
+            owner,
+        })?;
+        self.next_id += 1;
+        Ok(id)
+    }
+
+    fn insert(&mut self, job: Job) -> Result<(), anyhow::Error> {
+        let schedule = parse_schedule(&job.schedule)?;
+        let timezone =
+            Tz::from_str(&job.timezone).map_err(|e| anyhow::anyhow!("Invalid timezone {}", e))?;
+        let next = next_run(&schedule, timezone, self.clock.now());
+        self.jobs.push(ScheduledJob {
+            job,
+            schedule,
+            timezone,
+            next,
+        });
+        Ok(())
+    }
+
+    /// Removes a job, returning whether there was one with the id
+    pub fn remove(&mut self, id: u64) -> bool {
+        let before = self.jobs.len();
+        self.jobs.retain(|s| s.job.id != id);
+        self.jobs.len() != before
+    }
+
+    pub fn jobs(&self) -> Vec<Job> {
+        self.jobs.iter().map(|s| s.job.clone()).collect()
+    }
+
+    /// How long until the next job is due, if any job will ever run again
+    pub fn until_next(&self) -> Option<Duration> {
+        let now = self.clock.now();
+        self.jobs
+            .iter()
+            .filter_map(|s| s.next)
+            .min()
+            .map(|next| (next - now).to_std().unwrap_or_default())
+    }
+
+    /// Takes the jobs that are due, moving each on to its next run
+    pub fn take_due(&mut self) -> Vec<Job> {
+        let now = self.clock.now();
+        let mut due = vec![];
+        for scheduled in &mut self.jobs {
+            if scheduled.next.map_or(false, |next| next <= now) {
+                due.push(scheduled.job.clone());
+                scheduled.next = next_run(&scheduled.schedule, scheduled.timezone, now);
+            }
+        }
+        due
+    }
+
+    pub fn state(&self) -> SchedulerState {
+        SchedulerState {
+            next_id: self.next_id,
+            jobs: self
+                .jobs
+                .iter()
+                .filter(|s| s.job.owner.is_none())
+                .map(|s| s.job.clone())
+                .collect(),
+        }
+    }
+}
+
+fn next_run(schedule: &Schedule, timezone: Tz, after: DateTime<Utc>) -> Option<DateTime<Utc>> {
+    schedule
+        .after(&after.with_timezone(&timezone))
+        .next()
+        .map(|next| next.with_timezone(&Utc))
+}
+
+impl Manager<SchedulerCommand> for SchedulerConfig {
+    fn start_manager(
+        &self,
+        config: Arc<Config>,
+        mut rx: Receiver<SchedulerCommand>,
+        tx: Sender<Command>,
+        events: EventBus,
+        mut shutdown: Shutdown,
+    ) -> ManagerHandle {
+        tracing::info!("Starting scheduler manager");
+        tokio::spawn(async move {
+            let state: SchedulerState = storage::read(STATE_PATH).unwrap_or_else(|e| {
+                tracing::error!("Failed to read scheduler state, starting fresh {}", e);
+                SchedulerState::default()
+            });
+            let mut scheduler = Scheduler::new(SystemClock, state);
+            events::publish(&events, Event::SchedulerStarted);
+
+            loop {
+                let until_next = scheduler.until_next();
+                let next_job = tokio::time::sleep(until_next.unwrap_or_default());
+                tokio::select! {
+                    _ = next_job, if until_next.is_some() => {
+                        for job in scheduler.take_due() {
+                            let cmd = match job.task.command() {
+                                Some(cmd) => cmd,
+                                None => {
+                                    tracing::warn!(
+                                        "Skipping scheduled job {} ({}), this build doesn't have its manager",
+                                        job.id,
+                                        job.task
+                                    );
+                                    continue;
+                                }
+                            };
+                            if let Err(e) = tx.send(cmd).await {
+                                tracing::error!("Failed to send scheduled job {} {}", job.id, e);
+                            }
+                        }
+                    }
+                    cmd = rx.recv() => match cmd {
+                        Some(SchedulerCommand::Register(owner, schedule, task)) => {
+                            let timezone = &config.scheduler.timezone;
+                            let res = scheduler.schedule(&schedule, timezone, task, Some(owner));
+                            if let Err(e) = res {
+                                tracing::error!("Failed to register scheduled job {}", e);
+                            }
+                        }
+                        Some(SchedulerCommand::Add(schedule, timezone, task, reply)) => {
+                            let timezone =
+                                timezone.unwrap_or_else(|| config.scheduler.timezone.clone());
+                            let res = scheduler.schedule(&schedule, &timezone, task, None);
+                            if res.is_ok() {
+                                persist_state(&scheduler);
+                            }
+                            let _ = reply.send(res);
+                        }
+                        Some(SchedulerCommand::List(reply)) => {
+                            let _ = reply.send(scheduler.jobs());
+                        }
+                        Some(SchedulerCommand::Remove(id, reply)) => {
+                            let removed = scheduler.remove(id);
+                            if removed {
+                                persist_state(&scheduler);
+                            }
+                            let _ = reply.send(removed);
+                        }
+                        None => return Ok(()),
+                    },
+                    _ = shutdown.wait() => return Ok(()),
+                }
+            }
+        })
+    }
+}
+
+fn persist_state<C: Clock>(scheduler: &Scheduler<C>) {
+    if let Err(e) = storage::persist(STATE_PATH, &scheduler.state()) {
+        tracing::error!("Failed to persist scheduler state {}", e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use chrono::TimeZone;
+
+    use super::*;
+
+    struct ManualClock(Mutex<DateTime<Utc>>);
+
+    impl ManualClock {
+        fn set(&self, now: DateTime<Utc>) {
+            *self.0.lock().unwrap() = now;
+        }
+    }
+
+    impl Clock for &ManualClock {
+        fn now(&self) -> DateTime<Utc> {
+            *self.0.lock().unwrap()
+        }
+    }
+
+    #[test]
+    fn runs_jobs_when_due_in_their_timezone() {
+        let clock = ManualClock(Mutex::new(Utc.ymd(2021, 6, 1).and_hms(7, 0, 0)));
+        let mut scheduler = Scheduler::new(&clock, SchedulerState::default());
+        let id = scheduler
+            .schedule("0 9 * * *", "Europe/London", JobTask::TopCoins(10), None)
+            .unwrap();
+
+        // 9am in London is 8am UTC during British Summer Time
+        assert_eq!(scheduler.until_next(), Some(Duration::from_secs(3600)));
+        assert!(scheduler.take_due().is_empty());
+
+        clock.set(Utc.ymd(2021, 6, 1).and_hms(8, 0, 0));
+        let due = scheduler.take_due();
+        assert_eq!(due.len(), 1);
+        assert_eq!(due[0].id, id);
+        assert_eq!(scheduler.until_next(), Some(Duration::from_secs(24 * 3600)));
+    }
+
+    #[test]
+    fn only_persists_jobs_without_an_owner() {
+        let clock = ManualClock(Mutex::new(Utc.ymd(2021, 6, 1).and_hms(0, 0, 0)));
+        let mut scheduler = Scheduler::new(&clock, SchedulerState::default());
+        scheduler
+            .schedule("0 * * * *", "UTC", JobTask::TopCoins(5), None)
+            .unwrap();
+        for _ in 0..2 {
+            scheduler
+                .schedule(
+                    "0 0 * * * *",
+                    "UTC",
+                    JobTask::Digest(String::from("hourly")),
+                    Some(String::from("coingecko")),
+                )
+                .unwrap();
+        }
+
+        assert_eq!(scheduler.jobs().len(), 2);
+        let state = scheduler.state();
+        assert_eq!(state.jobs.len(), 1);
+        assert_eq!(state.jobs[0].task, JobTask::TopCoins(5));
+        assert!(scheduler.remove(state.jobs[0].id));
+        assert!(!scheduler.remove(state.jobs[0].id));
+    }
+
+    #[test]
+    fn rejects_invalid_schedules() {
+        let clock = ManualClock(Mutex::new(Utc::now()));
+        let mut scheduler = Scheduler::new(&clock, SchedulerState::default());
+        assert!(scheduler
+            .schedule("not a schedule", "UTC", JobTask::TopCoins(5), None)
+            .is_err());
+        assert!(scheduler
+            .schedule("0 9 * * *", "Mars/Olympus", JobTask::TopCoins(5), None)
+            .is_err());
+        assert!(JobTask::parse(&["top", "ten"]).is_err());
+    }
+}
@@ -0,0 +1,140 @@
+use std::fmt;
+
+use serde::Deserialize;
+
+/// A credential, which is never serialized and is redacted when debug printed. Fields holding
+/// one are marked `skip_serializing` so persisting the config can't write them back.
+#[derive(Deserialize, Clone, Default, PartialEq)]
+#[serde(transparent)]
+pub struct Secret(String);
+
+impl Secret {
+    pub fn expose(&self) -> &str {
+        &self.0
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// Replaces the value from the config file with the `env` variable when it is set, or with
+    /// the contents of `file` when that is given
+    pub fn resolve(&mut self, env: &str, file: &Option<String>) -> Result<(), anyhow::Error> {
+        if let Ok(value) = std::env::var(env) {
+            self.0 = value;
+        } else if let Some(file) = file {
+            let value = std::fs::read_to_string(file)
+                .map_err(|e| anyhow::anyhow!("Failed to read secret file {} {}", file, e))?;
+            self.0 = value.trim().to_string();
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Debug for Secret {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.0.is_empty() {
+            write!(f, "\"\"")
+        } else {
+            write!(f, "\"[redacted]\"")
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http::HttpConfig;
+
+    fn secret(value: &str) -> Secret {
+        Secret(value.to_string())
+    }
+
+    fn secret_file(name: &str, contents: &str) -> String {
+        let path =
+            std::env::temp_dir().join(format!("honorable_secret_{}_{}", name, std::process::id()));
+        std::fs::write(&path, contents).unwrap();
+        path.display().to_string()
+    }
+
+    #[test]
+    fn the_environment_wins_over_the_file_and_config() {
+        let file = secret_file("env", "from the file");
+        std::env::set_var("HONORABLE_TEST_SECRET_ENV", "from the env");
+        let mut token = secret("from the config");
+
+        token
+            .resolve("HONORABLE_TEST_SECRET_ENV", &Some(file.clone()))
+            .unwrap();
+
+        assert_eq!(token.expose(), "from the env");
+        std::env::remove_var("HONORABLE_TEST_SECRET_ENV");
+        let _ = std::fs::remove_file(file);
+    }
+
+    #[test]
+    fn files_are_read_trimmed_over_the_config() {
+        let file = secret_file("file", "from the file\n");
+        let mut token = secret("from the config");
+
+        token
+            .resolve("HONORABLE_TEST_SECRET_FILE", &Some(file.clone()))
+            .unwrap();
+
+        assert_eq!(token.expose(), "from the file");
+        let _ = std::fs::remove_file(file);
+    }
+
+    #[test]
+    fn the_config_value_is_kept_without_either() {
+        let mut token = secret("from the config");
+        token.resolve("HONORABLE_TEST_SECRET_NONE", &None).unwrap();
+        assert_eq!(token.expose(), "from the config");
+    }
+
+    #[test]
+    fn a_missing_file_is_an_error() {
+        let mut token = secret("from the config");
+        let missing = Some(String::from("/nonexistent/honorable_secret"));
+
+        let err = token
+            .resolve("HONORABLE_TEST_SECRET_MISSING", &missing)
+            .unwrap_err();
+
+        assert!(err
+            .to_string()
+            .starts_with("Failed to read secret file /nonexistent/honorable_secret"));
+    }
+
+    #[test]
+    fn debug_output_is_redacted() {
+        assert_eq!(format!("{:?}", secret("hunter2")), "\"[redacted]\"");
+        assert_eq!(format!("{:?}", secret("")), "\"\"");
+
+        let config = HttpConfig {
+            api_token: secret("hunter2"),
+            ..Default::default()
+        };
+        assert!(!format!("{:?}", config).contains("hunter2"));
+    }
+
+    #[test]
+    fn secrets_are_left_out_when_the_config_is_serialized() {
+        let config: HttpConfig = serde_json::from_value(serde_json::json!({
+            "api_token": "hunter2",
+            "api_token_file": "/run/secrets/api_token"
+        }))
+        .unwrap();
+        assert_eq!(config.api_token.expose(), "hunter2");
+
+        let serialized = serde_json::to_value(&config).unwrap();
+
+        assert_eq!(
+            serialized,
+            serde_json::json!({
+                "listen": null,
+                "api_token_file": "/run/secrets/api_token"
+            })
+        );
+    }
+}
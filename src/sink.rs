@@ -0,0 +1,73 @@
+use async_trait::async_trait;
+use serde::Serialize;
+
+use crate::events::Event;
+
+/// An event rendered down to the parts every sink can present, whatever its format
+#[derive(Serialize, Debug, Clone)]
+pub struct Notification {
+    pub kind: &'static str,
+    pub title: String,
+    pub description: String,
+    pub url: Option<String>,
+    pub image: Option<String>,
+}
+
+impl Notification {
+    /// The notification for an event, if it is one these sinks are interested in
+    pub fn from_event(event: &Event) -> Option<Notification> {
+        match event {
+            #[cfg(feature = "twitter")]
+            Event::TweetReceived(tweet) => Some(Notification {
+                kind: "tweet",
+                title: format!("{} (@{})", tweet.name, tweet.screen_name),
+                description: tweet.text.clone(),
+                url: Some(tweet.url()),
+                image: Some(tweet.profile_image_url.clone()),
+            }),
+            #[cfg(feature = "coingecko")]
+            Event::RuleTriggered(res) => Some(Notification {
+                kind: "rule",
+                title: res.market().id.clone(),
+                description: res.description(),
+                url: Some(format!(
+                    "https://www.coingecko.com/en/coins/{}",
+                    res.market().id
+                )),
+                image: Some(res.market().image.clone()),
+            }),
+            #[cfg(feature = "coingecko")]
+            Event::MarketDigest(digest) => Some(Notification {
+                kind: "digest",
+                title: format!("{} market digest", digest.name),
+                description: digest
+                    .sections()
+                    .into_iter()
+                    .map(|(name, value)| format!("{}\n{}", name, value))
+                    .collect::<Vec<_>>()
+                    .join("\n\n"),
+                url: None,
+                image: None,
+            }),
+            _ => None,
+        }
+    }
+
+    /// Plain text for sinks without any formatting
+    pub fn text(&self) -> String {
+        match &self.url {
+            Some(url) => format!("{}\n{}\n{}", self.title, self.description, url),
+            None => format!("{}\n{}", self.title, self.description),
+        }
+    }
+}
+
+/// Somewhere notifications can be delivered besides the discord channel
+#[async_trait]
+pub trait Sink: Send + Sync {
+    async fn deliver(
+        &self,
+        client: &reqwest::Client,
+        notification: &Notification,
+    ) -> Result<(), anyhow::Error>;
+}
@@ -0,0 +1,35 @@
+use std::{
+    fs::{File, OpenOptions},
+    io::{BufReader, Write},
+    path::Path,
+};
+
+use serde::{de::DeserializeOwned, Serialize};
+
+/// Reads a piece of persisted state, falling back to its default when nothing has been stored yet
+pub fn read<T: DeserializeOwned + Default>(path: impl AsRef<Path>) -> Result<T, anyhow::Error> {
+    if !path.as_ref().exists() {
+        return Ok(T::default());
+    }
+    let file = File::open(path)?;
+    Ok(serde_json::from_reader(BufReader::new(file))?)
+}
+
+pub fn persist<T: Serialize>(path: impl AsRef<Path>, value: &T) -> Result<(), anyhow::Error> {
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(path)?;
+    file.write_all(serde_json::to_string_pretty(value)?.as_bytes())?;
+
+    Ok(())
+}
+
+/// Adds a value to the end of a file of JSON lines, creating it if needed
+pub fn append<T: Serialize>(path: impl AsRef<Path>, value: &T) -> Result<(), anyhow::Error> {
+    let mut file = OpenOptions::new().append(true).create(true).open(path)?;
+    writeln!(file, "{}", serde_json::to_string(value)?)?;
+
+    Ok(())
+}
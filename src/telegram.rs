@@ -0,0 +1,547 @@
+use std::sync::Arc;
+
+use async_trait::async_trait;
+#[cfg(feature = "coingecko")]
+use num_format::{Locale, ToFormattedString};
+use serde::{Deserialize, Serialize};
+use tokio::sync::mpsc::Sender;
+#[cfg(any(feature = "twitter", feature = "coingecko"))]
+use tokio::sync::oneshot;
+use tracing::Instrument;
+
+#[cfg(feature = "coingecko")]
+use crate::command::CoingeckoCommand;
+#[cfg(feature = "twitter")]
+use crate::{
+    audit::{Actor, Source},
+    command::TwitterCommand,
+};
+use crate::{
+    command::{Command, Worker},
+    events::EventBus,
+    lifecycle::{ManagerHandle, Shutdown},
+    secret::Secret,
+    sink::{Notification, Sink},
+    Config,
+};
+
+/// How long each getUpdates call waits for new messages before returning empty
+const POLL_TIMEOUT_SECS: u64 = 30;
+const RETRY_DELAY_SECS: u64 = 5;
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct TelegramConfig {
+    #[serde(default, skip_serializing)]
+    pub token: Secret,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub token_file: Option<String>,
+    /// The chats tweets and alerts are posted to
+    #[serde(default)]
+    pub chat_ids: Vec<i64>,
+    /// The users allowed to change subscriptions, the telegram counterpart of discord's administrator role
+    #[serde(default)]
+    pub admin_user_ids: Vec<i64>,
+    #[serde(default = "default_api_base")]
+    pub api_base: String,
+}
+
+fn default_api_base() -> String {
+    String::from("https://api.telegram.org")
+}
+
+impl Default for TelegramConfig {
+    fn default() -> Self {
+        TelegramConfig {
+            token: Secret::default(),
+            token_file: None,
+            chat_ids: vec![],
+            admin_user_ids: vec![],
+            api_base: default_api_base(),
+        }
+    }
+}
+
+impl TelegramConfig {
+    /// Takes the token from `HONORABLE_TELEGRAM_TOKEN` or the token file when given
+    pub fn resolve_secrets(&mut self) -> Result<(), anyhow::Error> {
+        self.token
+            .resolve("HONORABLE_TELEGRAM_TOKEN", &self.token_file)
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        !self.token.is_empty()
+    }
+
+    fn method_url(&self, method: &str) -> String {
+        format!(
+            "{}/bot{}/{}",
+            self.api_base.trim_end_matches('/'),
+            self.token.expose(),
+            method
+        )
+    }
+}
+
+#[derive(Deserialize, Debug)]
+struct ApiResponse<T> {
+    ok: bool,
+    result: Option<T>,
+    description: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+struct Update {
+    update_id: i64,
+    message: Option<Message>,
+}
+
+#[derive(Deserialize, Debug)]
+struct Message {
+    chat: Chat,
+    from: Option<User>,
+    text: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+struct Chat {
+    id: i64,
+}
+
+#[derive(Deserialize, Debug)]
+struct User {
+    id: i64,
+    first_name: String,
+    #[serde(default)]
+    username: Option<String>,
+}
+
+async fn call<T: for<'de> Deserialize<'de>>(
+    client: &reqwest::Client,
+    config: &TelegramConfig,
+    method: &str,
+    body: serde_json::Value,
+) -> Result<T, anyhow::Error> {
+    // The url has the bot token in it, so it's left out of anything that gets logged
+    let res: ApiResponse<T> = request(client, config, method, &body)
+        .await
+        .map_err(|e| anyhow::anyhow!("Telegram {} failed: {}", method, e.without_url()))?;
+    match res.result {
+        Some(result) if res.ok => Ok(result),
+        _ => Err(anyhow::anyhow!(
+            "Telegram {} failed: {}",
+            method,
+            res.description.unwrap_or_default()
+        )),
+    }
+}
+
+async fn request<T: for<'de> Deserialize<'de>>(
+    client: &reqwest::Client,
+    config: &TelegramConfig,
+    method: &str,
+    body: &serde_json::Value,
+) -> Result<ApiResponse<T>, reqwest::Error> {
+    client
+        .post(config.method_url(method))
+        .json(body)
+        .send()
+        .await?
+        .json()
+        .await
+}
+
+async fn send_message(
+    client: &reqwest::Client,
+    config: &TelegramConfig,
+    chat_id: i64,
+    text: &str,
+) -> Result<(), anyhow::Error> {
+    call::<serde_json::Value>(
+        client,
+        config,
+        "sendMessage",
+        serde_json::json!({ "chat_id": chat_id, "text": text }),
+    )
+    .await?;
+    Ok(())
+}
+
+#[async_trait]
+impl Sink for TelegramConfig {
+    async fn deliver(
+        &self,
+        client: &reqwest::Client,
+        notification: &Notification,
+    ) -> Result<(), anyhow::Error> {
+        let mut failed = 0;
+        for chat_id in &self.chat_ids {
+            if let Err(e) = send_message(client, self, *chat_id, &notification.text()).await {
+                tracing::error!("Failed to send telegram message to chat {} {}", chat_id, e);
+                failed += 1;
+            }
+        }
+        if failed > 0 {
+            return Err(anyhow::anyhow!(
+                "{} of {} telegram chats weren't delivered to",
+                failed,
+                self.chat_ids.len()
+            ));
+        }
+        Ok(())
+    }
+}
+
+impl Worker for TelegramConfig {
+    fn start_worker(
+        &self,
+        config: Arc<Config>,
+        tx: Sender<Command>,
+        events: EventBus,
+        mut shutdown: Shutdown,
+    ) -> ManagerHandle {
+        tracing::info!("Starting telegram manager");
+        let client = reqwest::Client::new();
+        let mut events = events.subscribe("telegram");
+
+        tokio::spawn(async move {
+            let listener = listen(&client, &config.telegram, &tx);
+            tokio::pin!(listener);
+
+            loop {
+                tokio::select! {
+                    _ = &mut listener => return Ok(()),
+                    event = events.recv() => {
+                        let event = match event {
+                            Some(event) => event,
+                            None => return Ok(()),
+                        };
+                        if let Some(notification) = Notification::from_event(&event) {
+                            async {
+                                match config.telegram.deliver(&client, &notification).await {
+                                    Ok(()) => tracing::debug!("Delivered to telegram"),
+                                    Err(e) => {
+                                        tracing::error!("Error sending telegram message {}", e)
+                                    }
+                                }
+                            }
+                            .instrument(event.span())
+                            .await
+                        }
+                    }
+                    _ = shutdown.wait() => return Ok(()),
+                }
+            }
+        })
+    }
+}
+
+/// Long polls for messages sent to the bot, translating its commands onto the command bus
+async fn listen(client: &reqwest::Client, config: &TelegramConfig, tx: &Sender<Command>) {
+    let mut offset = 0;
+    loop {
+        let updates: Vec<Update> = match call(
+            client,
+            config,
+            "getUpdates",
+            serde_json::json!({ "offset": offset, "timeout": POLL_TIMEOUT_SECS }),
+        )
+        .await
+        {
+            Ok(updates) => updates,
+            Err(e) => {
+                tracing::error!("Failed to get telegram updates {}", e);
+                tokio::time::sleep(tokio::time::Duration::from_secs(RETRY_DELAY_SECS)).await;
+                continue;
+            }
+        };
+
+        for update in updates {
+            offset = update.update_id + 1;
+            if let Some(message) = update.message {
+                if let Some(reply) = handle_message(config, tx, &message).await {
+                    if let Err(e) = send_message(client, config, message.chat.id, &reply).await {
+                        tracing::error!("Error replying to telegram command {}", e)
+                    }
+                }
+            }
+        }
+    }
+}
+
+async fn handle_message(
+    config: &TelegramConfig,
+    tx: &Sender<Command>,
+    message: &Message,
+) -> Option<String> {
+    let mut words = message.text.as_ref()?.split_whitespace();
+    // Commands in groups can be addressed to a specific bot as /command@bot_name
+    let command = words.next()?.split('@').next()?;
+    let arg = words.next();
+
+    match command {
+        #[cfg(feature = "twitter")]
+        "/add_subscription" => {
+            let user = match &message.from {
+                Some(user) if config.admin_user_ids.contains(&user.id) => user,
+                _ => return Some(String::from("You aren't allowed to change subscriptions.")),
+            };
+            let handle = match arg {
+                Some(handle) => handle.trim_start_matches('@').to_string(),
+                None => return Some(String::from("You need to provide a twitter handle.")),
+            };
+            let (reply_tx, reply_rx) = oneshot::channel();
+            if let Err(e) = tx
+                .send(Command::Twitter(TwitterCommand::AddTwitterSubscription(
+                    handle.clone(),
+                    Actor {
+                        source: Source::Telegram,
+                        id: user.id.to_string(),
+                        name: user
+                            .username
+                            .clone()
+                            .unwrap_or_else(|| user.first_name.clone()),
+                    },
+                    reply_tx,
+                )))
+                .await
+            {
+                tracing::error!("Failed to send add twitter sub {}", e);
+                return None;
+            }
+            match reply_rx.await {
+                Ok(Ok(())) => Some(format!("Subscribed to @{}", handle)),
+                Ok(Err(e)) => Some(e.to_string()),
+                Err(_) => Some(String::from("Subscriptions can't be changed right now.")),
+            }
+        }
+        #[cfg(feature = "coingecko")]
+        "/price" => {
+            let coin = match arg {
+                Some(coin) => coin.to_string(),
+                None => return Some(String::from("You need to provide a coin.")),
+            };
+            let (reply_tx, reply_rx) = oneshot::channel();
+            if let Err(e) = tx
+                .send(Command::Coingecko(CoingeckoCommand::Price(
+                    coin.clone(),
+                    reply_tx,
+                )))
+                .await
+            {
+                tracing::error!("Failed to send price query {}", e);
+                return None;
+            }
+            match reply_rx.await {
+                Ok(Some(m)) => Some(format!(
+                    "{} (#{}) ${} [MARKET_CAP] ${}",
+                    m.id,
+                    m.market_cap_rank,
+                    m.current_price,
+                    m.market_cap.to_formatted_string(&Locale::en)
+                )),
+                Ok(None) => Some(format!("Couldn't find a coin called {}", coin)),
+                Err(_) => Some(String::from("Coingecko prices aren't available yet.")),
+            }
+        }
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::sync::mpsc;
+    use warp::http::{Method, StatusCode};
+
+    use super::*;
+    use crate::harness::MockServer;
+
+    const SEND_MESSAGE: &str = "/bot123:abc/sendMessage";
+
+    fn config(api_base: &str) -> TelegramConfig {
+        TelegramConfig {
+            token: serde_json::from_str("\"123:abc\"").unwrap(),
+            chat_ids: vec![-100, -200, -300],
+            admin_user_ids: vec![7],
+            api_base: api_base.to_string(),
+            ..Default::default()
+        }
+    }
+
+    fn notification() -> Notification {
+        Notification {
+            kind: "tweet",
+            title: String::from("Polkadot (@Polkadot)"),
+            description: String::from("gm"),
+            url: None,
+            image: None,
+        }
+    }
+
+    fn message(user_id: i64, text: &str) -> Message {
+        Message {
+            chat: Chat { id: -100 },
+            from: Some(User {
+                id: user_id,
+                first_name: String::from("Gavin"),
+                username: Some(String::from("gavofyork")),
+            }),
+            text: Some(text.to_string()),
+        }
+    }
+
+    #[tokio::test]
+    async fn a_failing_chat_doesnt_stop_delivery_to_the_rest() {
+        let server = MockServer::new()
+            .respond(
+                Method::POST,
+                SEND_MESSAGE,
+                StatusCode::BAD_REQUEST,
+                r#"{"ok": false, "description": "Bad Request: chat not found"}"#,
+            )
+            .respond(
+                Method::POST,
+                SEND_MESSAGE,
+                StatusCode::OK,
+                r#"{"ok": true, "result": {}}"#,
+            )
+            .start();
+        let config = config(&server.base);
+
+        let result = config
+            .deliver(&reqwest::Client::new(), &notification())
+            .await;
+
+        let err = result.unwrap_err().to_string();
+        assert_eq!(err, "1 of 3 telegram chats weren't delivered to");
+        let chats: Vec<_> = server
+            .requests_to(SEND_MESSAGE)
+            .iter()
+            .map(|r| r.json()["chat_id"].clone())
+            .collect();
+        assert_eq!(chats, vec![-100, -200, -300]);
+        assert_eq!(
+            server.requests_to(SEND_MESSAGE)[1].json()["text"],
+            "Polkadot (@Polkadot)\ngm"
+        );
+    }
+
+    #[tokio::test]
+    async fn failures_carry_the_bot_apis_description() {
+        let server = MockServer::new()
+            .respond(
+                Method::POST,
+                SEND_MESSAGE,
+                StatusCode::FORBIDDEN,
+                r#"{"ok": false, "description": "Forbidden: bot was kicked"}"#,
+            )
+            .start();
+
+        let err = send_message(&reqwest::Client::new(), &config(&server.base), -100, "gm")
+            .await
+            .unwrap_err();
+
+        assert_eq!(
+            err.to_string(),
+            "Telegram sendMessage failed: Forbidden: bot was kicked"
+        );
+    }
+
+    #[tokio::test]
+    async fn request_errors_leave_out_the_token() {
+        let server = MockServer::new()
+            .respond(
+                Method::POST,
+                SEND_MESSAGE,
+                StatusCode::BAD_GATEWAY,
+                "<html>",
+            )
+            .start();
+
+        let err = send_message(&reqwest::Client::new(), &config(&server.base), -100, "gm")
+            .await
+            .unwrap_err()
+            .to_string();
+
+        assert!(err.starts_with("Telegram sendMessage failed: "), "{}", err);
+        assert!(!err.contains("123:abc"), "{}", err);
+
+        // Nothing listening at all
+        let err = send_message(
+            &reqwest::Client::new(),
+            &config("http://127.0.0.1:1"),
+            -100,
+            "gm",
+        )
+        .await
+        .unwrap_err()
+        .to_string();
+        assert!(!err.contains("123:abc"), "{}", err);
+    }
+
+    #[cfg(feature = "twitter")]
+    #[tokio::test]
+    async fn only_admins_can_add_subscriptions() {
+        let (tx, mut rx) = mpsc::channel(1);
+
+        let reply =
+            handle_message(&config(""), &tx, &message(8, "/add_subscription Polkadot")).await;
+
+        assert_eq!(
+            reply.as_deref(),
+            Some("You aren't allowed to change subscriptions.")
+        );
+        assert!(rx.try_recv().is_err());
+    }
+
+    #[cfg(feature = "twitter")]
+    #[tokio::test]
+    async fn admins_add_subscriptions_over_the_command_bus() {
+        let (tx, mut rx) = mpsc::channel(1);
+        tokio::spawn(async move {
+            if let Some(Command::Twitter(TwitterCommand::AddTwitterSubscription(
+                handle,
+                actor,
+                reply,
+            ))) = rx.recv().await
+            {
+                assert_eq!(handle, "Polkadot");
+                assert_eq!(actor.name, "gavofyork");
+                let _ = reply.send(Ok(()));
+            }
+        });
+
+        let reply = handle_message(
+            &config(""),
+            &tx,
+            &message(7, "/add_subscription@honorable_bot @Polkadot"),
+        )
+        .await;
+
+        assert_eq!(reply.as_deref(), Some("Subscribed to @Polkadot"));
+    }
+
+    #[tokio::test]
+    async fn anything_but_a_command_is_ignored() {
+        let (tx, _rx) = mpsc::channel(1);
+
+        assert_eq!(
+            handle_message(&config(""), &tx, &message(7, "gm")).await,
+            None
+        );
+        assert_eq!(
+            handle_message(&config(""), &tx, &message(7, "/unknown arg")).await,
+            None
+        );
+    }
+
+    #[cfg(feature = "coingecko")]
+    #[tokio::test]
+    async fn price_needs_a_coin() {
+        let (tx, _rx) = mpsc::channel(1);
+
+        assert_eq!(
+            handle_message(&config(""), &tx, &message(7, "/price")).await,
+            Some(String::from("You need to provide a coin."))
+        );
+    }
+}
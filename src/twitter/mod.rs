@@ -0,0 +1,572 @@
+use std::{
+    collections::{BTreeSet, HashMap},
+    path::PathBuf,
+    sync::Arc,
+};
+
+use anyhow::Context;
+use async_trait::async_trait;
+use futures::{prelude::*, stream::BoxStream};
+use serde::{Deserialize, Serialize};
+
+use tokio::sync::{
+    mpsc::{Receiver, Sender},
+    watch,
+};
+use tracing::Instrument;
+
+use crate::{
+    command::{Command, Manager, TwitterCommand},
+    events::{self, Event, EventBus},
+    lifecycle::{ManagerHandle, Shutdown},
+    metrics,
+    secret::Secret,
+    storage, Config,
+};
+
+pub mod v1;
+pub mod v2;
+
+const STATE_PATH: &str = "twitter_state.json";
+const RECONNECT_DELAY_SECS: u64 = 30;
+/// How many forwarded tweet ids to remember per user, comfortably more than arrive out of order
+const MAX_RECENT: usize = 200;
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct TwitterConfig {
+    #[serde(default)]
+    pub api: TwitterApi,
+    #[serde(default, skip_serializing)]
+    pub consumer_key: Secret,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub consumer_key_file: Option<String>,
+    #[serde(default, skip_serializing)]
+    pub consumer_secret: Secret,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub consumer_secret_file: Option<String>,
+    #[serde(default, skip_serializing)]
+    pub user_access_key: Secret,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub user_access_key_file: Option<String>,
+    #[serde(default, skip_serializing)]
+    pub user_access_secret: Secret,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub user_access_secret_file: Option<String>,
+    #[serde(default, skip_serializing)]
+    pub bearer_token: Secret,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub bearer_token_file: Option<String>,
+    #[serde(default = "default_api_base")]
+    pub api_base: String,
+    #[serde(default)]
+    pub subscriptions: Vec<String>,
+    #[serde(default)]
+    pub keywords: Vec<String>,
+}
+
+fn default_api_base() -> String {
+    String::from("https://api.twitter.com")
+}
+
+impl Default for TwitterConfig {
+    fn default() -> Self {
+        TwitterConfig {
+            api: TwitterApi::default(),
+            consumer_key: Secret::default(),
+            consumer_key_file: None,
+            consumer_secret: Secret::default(),
+            consumer_secret_file: None,
+            user_access_key: Secret::default(),
+            user_access_key_file: None,
+            user_access_secret: Secret::default(),
+            user_access_secret_file: None,
+            bearer_token: Secret::default(),
+            bearer_token_file: None,
+            api_base: default_api_base(),
+            subscriptions: vec![],
+            keywords: vec![],
+        }
+    }
+}
+
+impl TwitterConfig {
+    /// Takes each credential from its `HONORABLE_TWITTER_*` variable or file when given
+    pub fn resolve_secrets(&mut self) -> Result<(), anyhow::Error> {
+        self.consumer_key
+            .resolve("HONORABLE_TWITTER_CONSUMER_KEY", &self.consumer_key_file)?;
+        self.consumer_secret.resolve(
+            "HONORABLE_TWITTER_CONSUMER_SECRET",
+            &self.consumer_secret_file,
+        )?;
+        self.user_access_key.resolve(
+            "HONORABLE_TWITTER_USER_ACCESS_KEY",
+            &self.user_access_key_file,
+        )?;
+        self.user_access_secret.resolve(
+            "HONORABLE_TWITTER_USER_ACCESS_SECRET",
+            &self.user_access_secret_file,
+        )?;
+        self.bearer_token
+            .resolve("HONORABLE_TWITTER_BEARER_TOKEN", &self.bearer_token_file)?;
+        Ok(())
+    }
+}
+
+/// Which streaming API to follow the subscriptions with. V1 authenticates as a user with the
+/// consumer and access keys, V2 as an app with the bearer token.
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq)]
+pub enum TwitterApi {
+    V1,
+    V2,
+}
+
+impl Default for TwitterApi {
+    fn default() -> Self {
+        TwitterApi::V1
+    }
+}
+
+/// A tweet along with the parts of its author we forward, whichever API it came from
+#[derive(Debug, Clone)]
+pub struct Tweet {
+    pub id: u64,
+    pub text: String,
+    pub user_id: u64,
+    pub screen_name: String,
+    pub name: String,
+    pub profile_image_url: String,
+}
+
+impl Tweet {
+    pub fn url(&self) -> String {
+        format!(
+            "https://twitter.com/{}/status/{}",
+            self.screen_name, self.id
+        )
+    }
+}
+
+/// A source of tweets from the followed accounts, implemented for each Twitter API version
+#[async_trait]
+pub trait TwitterStream: Send + Sync {
+    /// Points the stream at the given handles and keywords, returning the ids of the followed users
+    async fn sync_subscriptions(
+        &mut self,
+        subscriptions: &[String],
+        keywords: &[String],
+    ) -> Result<Vec<u64>, anyhow::Error>;
+
+    /// Every tweet the user posted after `since_id`, paging back as far as the api allows,
+    /// in no particular order
+    async fn timeline_since(
+        &self,
+        user_id: u64,
+        since_id: u64,
+    ) -> Result<Vec<Tweet>, anyhow::Error>;
+
+    /// Opens the stream, which yields tweets to forward and keep-alives until the connection
+    /// drops. Anything else the api sends is left out.
+    async fn connect(
+        &self,
+    ) -> Result<BoxStream<'static, Result<StreamItem, anyhow::Error>>, anyhow::Error>;
+}
+
+/// What a connected stream yields
+#[derive(Debug, Clone)]
+pub enum StreamItem {
+    Tweet(Tweet),
+    /// Sent by the api while there is nothing to stream, so a quiet stream isn't mistaken for
+    /// a dead one
+    KeepAlive,
+}
+
+/// What has been forwarded for each followed user, keyed by user id
+#[derive(Deserialize, Serialize, Debug, Default, Clone)]
+pub struct TwitterState {
+    /// The newest tweet forwarded, which backfills start from
+    pub last_seen: HashMap<u64, u64>,
+    /// The most recently forwarded tweet ids, so a tweet arriving after a newer one is
+    /// still forwarded, but only the once
+    #[serde(default)]
+    pub recent: HashMap<u64, BTreeSet<u64>>,
+    /// The newest id dropped from `recent`, at or below which every tweet counts as forwarded
+    #[serde(default)]
+    pub forwarded_up_to: HashMap<u64, u64>,
+}
+
+impl TwitterState {
+    /// Returns false if this tweet was already forwarded
+    fn mark_seen(&mut self, user_id: u64, tweet_id: u64) -> bool {
+        if !self.recent.contains_key(&user_id) {
+            // States written before the recent ids were kept only know the newest
+            if let Some(last) = self.last_seen.get(&user_id) {
+                self.forwarded_up_to.insert(user_id, *last);
+            }
+        }
+        let floor = self.forwarded_up_to.get(&user_id).copied();
+        let recent = self.recent.entry(user_id).or_default();
+        if floor.map_or(false, |floor| tweet_id <= floor) || !recent.insert(tweet_id) {
+            return false;
+        }
+
+        if recent.len() > MAX_RECENT {
+            let oldest = *recent.iter().next().unwrap();
+            recent.remove(&oldest);
+            self.forwarded_up_to.insert(user_id, oldest);
+        }
+        let last_seen = self.last_seen.entry(user_id).or_insert(tweet_id);
+        *last_seen = (*last_seen).max(tweet_id);
+        true
+    }
+}
+
+impl Manager<TwitterCommand> for TwitterConfig {
+    fn start_manager(
+        &self,
+        config: Arc<Config>,
+        mut rx: Receiver<TwitterCommand>,
+        _tx: Sender<Command>,
+        events: EventBus,
+        mut shutdown: Shutdown,
+    ) -> ManagerHandle {
+        // A restart hands over the config as read again, which has the latest subscriptions
+        let twitter = config.twitter.clone().unwrap_or_else(|| self.clone());
+        tokio::spawn(async move {
+            let mut stream: Box<dyn TwitterStream> = match twitter.api {
+                TwitterApi::V1 => Box::new(v1::V1Stream::new(&twitter)),
+                TwitterApi::V2 => Box::new(v2::V2Stream::new(&twitter, reqwest::Client::new())),
+            };
+
+            // Spawn a new task to handle the operations on the subscription list, which hands
+            // each changed list to the stream to resync with
+            let mut subscriptions = twitter.subscriptions.clone();
+            let (resync, mut changed) = watch::channel(subscriptions.clone());
+            tokio::spawn(async move {
+                while let Some(cmd) = rx.recv().await {
+                    match cmd {
+                        TwitterCommand::AddTwitterSubscription(handle, actor, reply) => {
+                            let action = format!("add_subscription {}", handle);
+                            let persisted = Config::modify(&actor, &action, |config| {
+                                let twitter = config.twitter.get_or_insert_with(Default::default);
+                                if !twitter.subscriptions.contains(&handle) {
+                                    twitter.subscriptions.push(handle);
+                                }
+                            });
+                            let updated = update_subscriptions(&mut subscriptions, persisted);
+                            if updated.is_ok() {
+                                let _ = resync.send(subscriptions.clone());
+                            }
+                            let _ = reply.send(updated);
+                        }
+                        TwitterCommand::RemoveTwitterSubscription(handle, actor, reply) => {
+                            if !subscriptions.contains(&handle) {
+                                let _ = reply
+                                    .send(Err(anyhow::anyhow!("@{} isn't subscribed to", handle)));
+                                continue;
+                            }
+                            let action = format!("remove_subscription {}", handle);
+                            let persisted = Config::modify(&actor, &action, |config| {
+                                if let Some(twitter) = &mut config.twitter {
+                                    twitter.subscriptions.retain(|s| *s != handle);
+                                }
+                            });
+                            let updated = update_subscriptions(&mut subscriptions, persisted);
+                            if updated.is_ok() {
+                                let _ = resync.send(subscriptions.clone());
+                            }
+                            let _ = reply.send(updated);
+                        }
+                        TwitterCommand::ListTwitterSubscriptions(reply) => {
+                            let _ = reply.send(subscriptions.clone());
+                        }
+                    }
+                }
+            });
+
+            let mut ids = stream
+                .sync_subscriptions(&twitter.subscriptions, &twitter.keywords)
+                .await
+                .context("Failed to sync twitter subscriptions")?;
+
+            let mut forwarder = Forwarder::new(events, PathBuf::from(STATE_PATH));
+            loop {
+                forwarder.backfill(&*stream, &ids).await;
+
+                tokio::select! {
+                    result = forwarder.follow(&*stream) => {
+                        if let Err(e) = result {
+                            tracing::error!("Twitter stream dropped {}, reconnecting", e);
+                        }
+                    }
+                    Ok(()) = changed.changed() => {
+                        let subscriptions = changed.borrow().clone();
+                        tracing::info!("Subscriptions changed, resyncing the twitter stream");
+                        match stream.sync_subscriptions(&subscriptions, &twitter.keywords).await {
+                            Ok(synced) => ids = synced,
+                            Err(e) => tracing::error!("Failed to sync twitter subscriptions {}", e),
+                        }
+                        continue;
+                    }
+                    _ = shutdown.wait() => return Ok(()),
+                }
+                tokio::select! {
+                    _ = tokio::time::sleep(tokio::time::Duration::from_secs(RECONNECT_DELAY_SECS)) => {}
+                    _ = shutdown.wait() => return Ok(()),
+                }
+            }
+        })
+    }
+}
+
+/// Keeps the subscriptions listed over the api in step with what was persisted
+fn update_subscriptions(
+    subscriptions: &mut Vec<String>,
+    persisted: Result<Config, anyhow::Error>,
+) -> Result<(), anyhow::Error> {
+    match persisted {
+        Ok(config) => {
+            *subscriptions = config.twitter.map(|t| t.subscriptions).unwrap_or_default();
+            Ok(())
+        }
+        Err(e) => {
+            tracing::error!("Failed to persist config {}", e);
+            Err(e)
+        }
+    }
+}
+
+/// Publishes each tweet at most once, whether it came from the stream or a backfill, keeping
+/// what was forwarded in a state file so that holds across restarts
+struct Forwarder {
+    events: EventBus,
+    state: TwitterState,
+    path: PathBuf,
+}
+
+impl Forwarder {
+    fn new(events: EventBus, path: PathBuf) -> Forwarder {
+        let state = storage::read(&path).unwrap_or_else(|e| {
+            tracing::error!("Failed to read twitter state, starting fresh {}", e);
+            TwitterState::default()
+        });
+        Forwarder {
+            events,
+            state,
+            path,
+        }
+    }
+
+    /// Publishes tweets from the stream until it drops
+    async fn follow(&mut self, stream: &dyn TwitterStream) -> Result<(), anyhow::Error> {
+        let mut tweets = stream.connect().await?;
+        while let Some(item) = tweets.try_next().await? {
+            match item {
+                StreamItem::Tweet(tweet) => self.forward(tweet).await,
+                StreamItem::KeepAlive => events::publish(&self.events, Event::TwitterKeepAlive),
+            }
+        }
+        Ok(())
+    }
+
+    /// Forwards anything the followed users posted since the last tweet we forwarded for them,
+    /// oldest first. Users we have never forwarded a tweet for are skipped as there is no gap
+    /// to fill.
+    async fn backfill(&mut self, stream: &dyn TwitterStream, ids: &[u64]) {
+        for id in ids {
+            let since_id = match self.state.last_seen.get(id) {
+                Some(since_id) => *since_id,
+                None => continue,
+            };
+            match stream.timeline_since(*id, since_id).await {
+                Ok(mut missed) => {
+                    missed.sort_by_key(|tweet| tweet.id);
+                    if !missed.is_empty() {
+                        tracing::info!("Backfilling {} missed tweets for {}", missed.len(), id);
+                    }
+                    for tweet in missed {
+                        self.forward(tweet).await;
+                    }
+                }
+                Err(e) => tracing::error!("Failed to backfill timeline for {} {}", id, e),
+            }
+        }
+    }
+
+    /// Publishes a tweet unless it has already been forwarded. The tweet is marked seen and
+    /// published without awaiting in between, so following being cancelled for a resync can't
+    /// lose it, at worst a restart before the state is persisted forwards it again.
+    async fn forward(&mut self, tweet: Tweet) {
+        let span = events::tweet_span(&tweet);
+        async {
+            metrics::TWEETS_RECEIVED
+                .with_label_values(&[&tweet.screen_name])
+                .inc();
+            if !self.state.mark_seen(tweet.user_id, tweet.id) {
+                tracing::debug!("Skipping a tweet that was already forwarded");
+                return;
+            }
+            metrics::TWEETS_FORWARDED
+                .with_label_values(&[&tweet.screen_name])
+                .inc();
+            events::publish(&self.events, Event::TweetReceived(tweet));
+
+            let (path, state) = (self.path.clone(), self.state.clone());
+            let persisted = tokio::task::spawn_blocking(move || storage::persist(path, &state));
+            if let Err(e) = persisted.await.map_err(anyhow::Error::from).and_then(|r| r) {
+                tracing::error!("Failed to persist twitter state {}", e);
+            }
+        }
+        .instrument(span)
+        .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tweet(user_id: u64, id: u64) -> Tweet {
+        Tweet {
+            id,
+            text: format!("tweet {}", id),
+            user_id,
+            screen_name: String::from("Polkadot"),
+            name: String::from("Polkadot"),
+            profile_image_url: String::new(),
+        }
+    }
+
+    /// A stream with a fixed timeline per user and nothing to stream
+    struct FakeStream {
+        timelines: HashMap<u64, Vec<Tweet>>,
+    }
+
+    #[async_trait]
+    impl TwitterStream for FakeStream {
+        async fn sync_subscriptions(
+            &mut self,
+            _subscriptions: &[String],
+            _keywords: &[String],
+        ) -> Result<Vec<u64>, anyhow::Error> {
+            Ok(self.timelines.keys().copied().collect())
+        }
+
+        async fn timeline_since(
+            &self,
+            user_id: u64,
+            since_id: u64,
+        ) -> Result<Vec<Tweet>, anyhow::Error> {
+            Ok(self.timelines[&user_id]
+                .iter()
+                .filter(|t| t.id > since_id)
+                .cloned()
+                .collect())
+        }
+
+        async fn connect(
+            &self,
+        ) -> Result<BoxStream<'static, Result<StreamItem, anyhow::Error>>, anyhow::Error> {
+            Ok(futures::stream::empty().boxed())
+        }
+    }
+
+    fn state_path(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!(
+            "honorable_twitter_{}_{}.json",
+            name,
+            std::process::id()
+        ));
+        let _ = std::fs::remove_file(&path);
+        path
+    }
+
+    #[test]
+    fn older_tweets_arriving_late_are_forwarded_once() {
+        let mut state = TwitterState::default();
+
+        assert!(state.mark_seen(1, 10));
+        assert!(state.mark_seen(1, 8));
+        assert!(!state.mark_seen(1, 8));
+        assert!(!state.mark_seen(1, 10));
+        assert!(state.mark_seen(2, 8));
+        assert_eq!(state.last_seen[&1], 10);
+    }
+
+    #[test]
+    fn tweets_older_than_everything_remembered_are_skipped() {
+        let mut state = TwitterState::default();
+        for id in 100..100 + MAX_RECENT as u64 {
+            assert!(state.mark_seen(1, id));
+        }
+
+        // Making room for a new id drops the oldest, and everything at or below it
+        assert!(state.mark_seen(1, 100 + MAX_RECENT as u64));
+        assert_eq!(state.recent[&1].len(), MAX_RECENT);
+        assert!(!state.mark_seen(1, 100));
+        assert!(!state.mark_seen(1, 50));
+        assert!(!state.mark_seen(1, 150));
+    }
+
+    #[test]
+    fn states_without_recent_ids_skip_up_to_the_newest() {
+        let mut state: TwitterState = serde_json::from_str(r#"{"last_seen":{"1":10}}"#).unwrap();
+
+        assert!(!state.mark_seen(1, 9));
+        assert!(!state.mark_seen(1, 10));
+        assert!(state.mark_seen(1, 11));
+        assert!(!state.mark_seen(1, 9));
+        assert_eq!(state.last_seen[&1], 11);
+    }
+
+    #[tokio::test]
+    async fn backfills_forward_missed_tweets_oldest_first() {
+        let path = state_path("backfill");
+        let mut state = TwitterState::default();
+        state.mark_seen(1, 10);
+        storage::persist(&path, &state).unwrap();
+        let stream = FakeStream {
+            timelines: vec![(
+                1,
+                vec![tweet(1, 13), tweet(1, 11), tweet(1, 10), tweet(1, 12)],
+            )]
+            .into_iter()
+            .collect(),
+        };
+
+        let bus = EventBus::new();
+        let mut rx = bus.subscribe("test");
+        let mut forwarder = Forwarder::new(bus, path.clone());
+        forwarder.backfill(&stream, &[1]).await;
+        // A second backfill, say after a reconnect, has nothing new to forward
+        forwarder.backfill(&stream, &[1]).await;
+
+        let forwarded: Vec<u64> = events::drain(&mut rx)
+            .into_iter()
+            .filter_map(|event| match event {
+                Event::TweetReceived(tweet) => Some(tweet.id),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(forwarded, vec![11, 12, 13]);
+        let persisted: TwitterState = storage::read(&path).unwrap();
+        assert_eq!(persisted.last_seen[&1], 13);
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[tokio::test]
+    async fn users_never_forwarded_are_not_backfilled() {
+        let path = state_path("unseen");
+        let stream = FakeStream {
+            timelines: vec![(1, vec![tweet(1, 11)])].into_iter().collect(),
+        };
+
+        let bus = EventBus::new();
+        let mut rx = bus.subscribe("test");
+        Forwarder::new(bus, path.clone())
+            .backfill(&stream, &[1])
+            .await;
+
+        assert!(events::drain(&mut rx).is_empty());
+        assert!(!path.exists());
+    }
+}
@@ -0,0 +1,173 @@
+use async_trait::async_trait;
+use egg_mode::{stream::StreamMessage, KeyPair, Token};
+use futures::{future, prelude::*, stream::BoxStream};
+
+use super::{StreamItem, Tweet, TwitterConfig, TwitterStream};
+
+/// How far back a backfill pages, which at 200 a page is all the api keeps anyway
+const MAX_TIMELINE_PAGES: usize = 16;
+
+/// The v1.1 statuses/filter stream, authenticated as the configured user
+pub struct V1Stream {
+    token: Token,
+    ids: Vec<u64>,
+    keywords: Vec<String>,
+}
+
+impl V1Stream {
+    pub fn new(config: &TwitterConfig) -> V1Stream {
+        let consumer = KeyPair::new(
+            config.consumer_key.expose().to_string(),
+            config.consumer_secret.expose().to_string(),
+        );
+        let access = KeyPair::new(
+            config.user_access_key.expose().to_string(),
+            config.user_access_secret.expose().to_string(),
+        );
+        V1Stream {
+            token: Token::Access { consumer, access },
+            ids: vec![],
+            keywords: vec![],
+        }
+    }
+}
+
+impl Tweet {
+    fn from_v1(tweet: egg_mode::tweet::Tweet) -> Option<Tweet> {
+        let user = tweet.user?;
+        Some(Tweet {
+            id: tweet.id,
+            text: tweet.text,
+            user_id: user.id,
+            screen_name: user.screen_name,
+            name: user.name,
+            profile_image_url: user.profile_image_url,
+        })
+    }
+}
+
+/// Whether a tweet from the filter stream matches what the v2 rules would, tweets by a followed
+/// user or mentioning every word of a keyword. The stream also delivers replies to and retweets
+/// of the followed users, which aren't wanted.
+fn wanted(tweet: &Tweet, ids: &[u64], keywords: &[String]) -> bool {
+    if ids.contains(&tweet.user_id) {
+        return true;
+    }
+    let text = tweet.text.to_lowercase();
+    keywords.iter().any(|keyword| {
+        keyword
+            .split_whitespace()
+            .all(|word| text.contains(&word.to_lowercase()))
+    })
+}
+
+#[async_trait]
+impl TwitterStream for V1Stream {
+    async fn sync_subscriptions(
+        &mut self,
+        subscriptions: &[String],
+        keywords: &[String],
+    ) -> Result<Vec<u64>, anyhow::Error> {
+        // curl 'https://tweeterid.com/ajax.php' -H 'User-Agent: Mozilla/5.0 (X11; Linux x86_64; rv:89.0) Gecko/20100101 Firefox/89.0' -H 'Accept: */*' -H 'Accept-Language: en-US,en;q=0.5' --compressed -H 'Content-Type: application/x-www-form-urlencoded; charset=UTF-8' -H 'X-Requested-With: XMLHttpRequest' -H 'Origin: https://tweeterid.com' -H 'Connection: keep-alive' -H 'Referer: https://tweeterid.com/' -H 'Sec-Fetch-Dest: empty' -H 'Sec-Fetch-Mode: cors' -H 'Sec-Fetch-Site: same-origin' -H 'Pragma: no-cache' -H 'Cache-Control: no-cache' --data-raw 'input=%40polkadot'
+        let mut ids = vec![];
+        for handle in subscriptions {
+            let mut search = egg_mode::user::search(handle.clone(), &self.token);
+            match search.try_next().await {
+                Ok(Some(u)) => ids.push(u.id),
+                Err(e) => tracing::error!("Failed to search {}", e),
+                _ => {}
+            }
+        }
+        self.ids = ids.clone();
+        self.keywords = keywords.to_vec();
+
+        Ok(ids)
+    }
+
+    async fn timeline_since(
+        &self,
+        user_id: u64,
+        since_id: u64,
+    ) -> Result<Vec<Tweet>, anyhow::Error> {
+        let timeline =
+            egg_mode::tweet::user_timeline(user_id, true, true, &self.token).with_page_size(200);
+        let mut tweets = vec![];
+        let mut max_id = None;
+        for _ in 0..MAX_TIMELINE_PAGES {
+            let page = timeline.call(Some(since_id), max_id).await?.response;
+            // Each page ends with the oldest tweet, so the next one starts just below it
+            match page.iter().map(|tweet| tweet.id).min() {
+                Some(oldest) => max_id = Some(oldest - 1),
+                None => return Ok(tweets),
+            }
+            tweets.extend(page.into_iter().filter_map(Tweet::from_v1));
+        }
+
+        tracing::warn!(
+            "Gave up backfilling {} after {} pages",
+            user_id,
+            MAX_TIMELINE_PAGES
+        );
+        Ok(tweets)
+    }
+
+    async fn connect(
+        &self,
+    ) -> Result<BoxStream<'static, Result<StreamItem, anyhow::Error>>, anyhow::Error> {
+        let ids = self.ids.clone();
+        let keywords = self.keywords.clone();
+
+        Ok(egg_mode::stream::filter()
+            .follow(&self.ids)
+            .track(&self.keywords)
+            .start(&self.token)
+            .map_err(anyhow::Error::from)
+            .try_filter_map(move |m| {
+                let item = match m {
+                    StreamMessage::Tweet(tweet) => Tweet::from_v1(tweet)
+                        .filter(|t| wanted(t, &ids, &keywords))
+                        .map(StreamItem::Tweet),
+                    StreamMessage::Ping => Some(StreamItem::KeepAlive),
+                    _ => None,
+                };
+                future::ready(Ok(item))
+            })
+            .boxed())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tweet(user_id: u64, text: &str) -> Tweet {
+        Tweet {
+            id: 1,
+            text: text.to_string(),
+            user_id,
+            screen_name: String::from("someone"),
+            name: String::from("Someone"),
+            profile_image_url: String::new(),
+        }
+    }
+
+    #[test]
+    fn tweets_by_followed_users_are_wanted() {
+        assert!(wanted(&tweet(2, "gm"), &[2], &[]));
+        assert!(!wanted(&tweet(3, "@Polkadot gm"), &[2], &[]));
+    }
+
+    #[test]
+    fn tweets_from_anyone_else_need_every_word_of_a_keyword() {
+        let keywords = vec![String::from("polkadot parachain"), String::from("kusama")];
+
+        assert!(wanted(
+            &tweet(3, "The next Polkadot Parachain auction"),
+            &[2],
+            &keywords
+        ));
+        assert!(wanted(&tweet(3, "KUSAMA"), &[2], &keywords));
+        assert!(!wanted(&tweet(3, "Polkadot is up"), &[2], &keywords));
+        assert!(!wanted(&tweet(3, "gm"), &[2], &keywords));
+    }
+}
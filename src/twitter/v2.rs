@@ -0,0 +1,519 @@
+use async_trait::async_trait;
+use futures::{
+    future,
+    prelude::*,
+    stream::{self, BoxStream},
+};
+use serde::{Deserialize, Serialize};
+
+use super::{StreamItem, Tweet, TwitterConfig, TwitterStream};
+
+const USER_FIELDS: &str = "name,username,profile_image_url";
+/// How far back a backfill pages, which at 100 a page covers far longer than any outage
+const MAX_TIMELINE_PAGES: usize = 32;
+/// Set on every rule the bot adds, so rules other tools keep on the same app are left alone
+const RULE_TAG: &str = "honorable-bot";
+
+/// The v2 filtered stream, authenticated with an app bearer token. Which tweets it delivers
+/// is decided by the stream rules stored against the app, which are kept in sync with the config.
+pub struct V2Stream {
+    client: reqwest::Client,
+    api_base: String,
+    bearer_token: String,
+}
+
+#[derive(Deserialize, Debug)]
+struct Page<T> {
+    data: Option<T>,
+    includes: Option<Includes>,
+    meta: Option<Meta>,
+}
+
+#[derive(Deserialize, Debug, Default)]
+struct Meta {
+    next_token: Option<String>,
+}
+
+#[derive(Deserialize, Debug, Default)]
+struct Includes {
+    #[serde(default)]
+    users: Vec<User>,
+}
+
+#[derive(Deserialize, Debug)]
+struct User {
+    id: String,
+    name: String,
+    username: String,
+    #[serde(default)]
+    profile_image_url: String,
+}
+
+#[derive(Deserialize, Debug)]
+struct V2Tweet {
+    id: String,
+    text: String,
+    author_id: String,
+}
+
+#[derive(Deserialize, Serialize, Debug)]
+struct StreamRule {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    id: Option<String>,
+    value: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    tag: Option<String>,
+}
+
+impl V2Stream {
+    pub fn new(config: &TwitterConfig, client: reqwest::Client) -> V2Stream {
+        V2Stream {
+            client,
+            api_base: config.api_base.trim_end_matches('/').to_string(),
+            bearer_token: config.bearer_token.expose().to_string(),
+        }
+    }
+
+    async fn get<T: for<'de> Deserialize<'de>>(
+        &self,
+        path: &str,
+        query: &[(&str, &str)],
+    ) -> Result<T, anyhow::Error> {
+        Ok(self
+            .client
+            .get(format!("{}{}", self.api_base, path))
+            .bearer_auth(&self.bearer_token)
+            .query(query)
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?)
+    }
+
+    async fn update_rules(&self, body: serde_json::Value) -> Result<(), anyhow::Error> {
+        self.client
+            .post(format!("{}/2/tweets/search/stream/rules", self.api_base))
+            .bearer_auth(&self.bearer_token)
+            .json(&body)
+            .send()
+            .await?
+            .error_for_status()?;
+        Ok(())
+    }
+}
+
+impl Tweet {
+    fn from_v2(tweet: V2Tweet, includes: &Includes) -> Option<Tweet> {
+        let user = includes.users.iter().find(|u| u.id == tweet.author_id);
+        Some(Tweet {
+            id: tweet.id.parse().ok()?,
+            text: tweet.text,
+            user_id: tweet.author_id.parse().ok()?,
+            screen_name: user
+                .map(|u| u.username.clone())
+                .unwrap_or_else(|| tweet.author_id.clone()),
+            name: user.map(|u| u.name.clone()).unwrap_or_default(),
+            profile_image_url: user
+                .map(|u| u.profile_image_url.clone())
+                .unwrap_or_default(),
+        })
+    }
+}
+
+/// The rule values the stream should be filtering on for the given subscriptions
+fn desired_rules(subscriptions: &[String], keywords: &[String]) -> Vec<String> {
+    subscriptions
+        .iter()
+        .map(|handle| format!("from:{}", handle.trim_start_matches('@')))
+        .chain(keywords.iter().cloned())
+        .collect()
+}
+
+#[async_trait]
+impl TwitterStream for V2Stream {
+    async fn sync_subscriptions(
+        &mut self,
+        subscriptions: &[String],
+        keywords: &[String],
+    ) -> Result<Vec<u64>, anyhow::Error> {
+        let desired = desired_rules(subscriptions, keywords);
+        let current: Page<Vec<StreamRule>> = self.get("/2/tweets/search/stream/rules", &[]).await?;
+        let current = current.data.unwrap_or_default();
+
+        let stale: Vec<&String> = current
+            .iter()
+            .filter(|rule| rule.tag.as_deref() == Some(RULE_TAG))
+            .filter(|rule| !desired.contains(&rule.value))
+            .filter_map(|rule| rule.id.as_ref())
+            .collect();
+        if !stale.is_empty() {
+            tracing::info!("Removing {} stale stream rules", stale.len());
+            self.update_rules(serde_json::json!({ "delete": { "ids": stale } }))
+                .await?;
+        }
+
+        let missing: Vec<StreamRule> = desired
+            .iter()
+            .filter(|value| !current.iter().any(|rule| &rule.value == *value))
+            .map(|value| StreamRule {
+                id: None,
+                value: value.clone(),
+                tag: Some(RULE_TAG.to_string()),
+            })
+            .collect();
+        if !missing.is_empty() {
+            tracing::info!("Adding {} stream rules", missing.len());
+            self.update_rules(serde_json::json!({ "add": missing }))
+                .await?;
+        }
+
+        if subscriptions.is_empty() {
+            return Ok(vec![]);
+        }
+        let usernames = subscriptions
+            .iter()
+            .map(|handle| handle.trim_start_matches('@'))
+            .collect::<Vec<_>>()
+            .join(",");
+        let users: Page<Vec<User>> = self
+            .get("/2/users/by", &[("usernames", usernames.as_str())])
+            .await?;
+
+        Ok(users
+            .data
+            .unwrap_or_default()
+            .iter()
+            .filter_map(|u| u.id.parse().ok())
+            .collect())
+    }
+
+    async fn timeline_since(
+        &self,
+        user_id: u64,
+        since_id: u64,
+    ) -> Result<Vec<Tweet>, anyhow::Error> {
+        let since_id = since_id.to_string();
+        let path = format!("/2/users/{}/tweets", user_id);
+        let mut tweets = vec![];
+        let mut next_token = None;
+        for _ in 0..MAX_TIMELINE_PAGES {
+            let mut query = vec![
+                ("since_id", since_id.as_str()),
+                ("max_results", "100"),
+                ("expansions", "author_id"),
+                ("user.fields", USER_FIELDS),
+            ];
+            if let Some(token) = &next_token {
+                query.push(("pagination_token", token.as_str()));
+            }
+            let page: Page<Vec<V2Tweet>> = self.get(&path, &query).await?;
+            let includes = page.includes.unwrap_or_default();
+            tweets.extend(
+                page.data
+                    .unwrap_or_default()
+                    .into_iter()
+                    .filter_map(|tweet| Tweet::from_v2(tweet, &includes)),
+            );
+
+            next_token = page.meta.and_then(|meta| meta.next_token);
+            if next_token.is_none() {
+                return Ok(tweets);
+            }
+        }
+
+        tracing::warn!(
+            "Gave up backfilling {} after {} pages",
+            user_id,
+            MAX_TIMELINE_PAGES
+        );
+        Ok(tweets)
+    }
+
+    async fn connect(
+        &self,
+    ) -> Result<BoxStream<'static, Result<StreamItem, anyhow::Error>>, anyhow::Error> {
+        let response = self
+            .client
+            .get(format!("{}/2/tweets/search/stream", self.api_base))
+            .bearer_auth(&self.bearer_token)
+            .query(&[("expansions", "author_id"), ("user.fields", USER_FIELDS)])
+            .send()
+            .await?
+            .error_for_status()?;
+
+        Ok(lines(Box::pin(response.bytes_stream()))
+            .try_filter_map(|line| {
+                // Blank lines are keep-alives
+                if line.is_empty() {
+                    return future::ready(Ok(Some(StreamItem::KeepAlive)));
+                }
+                // Lines without a tweet, such as errors, are skipped
+                future::ready(
+                    serde_json::from_str::<Page<V2Tweet>>(&line)
+                        .map(|page| {
+                            let includes = page.includes.unwrap_or_default();
+                            page.data
+                                .and_then(|tweet| Tweet::from_v2(tweet, &includes))
+                                .map(StreamItem::Tweet)
+                        })
+                        .map_err(anyhow::Error::from),
+                )
+            })
+            .boxed())
+    }
+}
+
+/// Splits a chunked response body into its newline delimited lines
+fn lines<S, B>(body: S) -> impl Stream<Item = Result<String, anyhow::Error>>
+where
+    S: Stream<Item = Result<B, reqwest::Error>> + Unpin,
+    B: AsRef<[u8]>,
+{
+    stream::unfold((body, Vec::new()), |(mut body, mut buf)| async move {
+        loop {
+            if let Some(pos) = buf.iter().position(|b| *b == b'\n') {
+                let line: Vec<u8> = buf.drain(..=pos).collect();
+                let line = String::from_utf8_lossy(&line).trim().to_string();
+                return Some((Ok(line), (body, buf)));
+            }
+            match body.next().await {
+                Some(Ok(chunk)) => buf.extend_from_slice(chunk.as_ref()),
+                Some(Err(e)) => return Some((Err(e.into()), (body, buf))),
+                None => return None,
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use warp::http::{Method, StatusCode};
+
+    use super::*;
+    use crate::harness::{self, MockServer};
+
+    #[tokio::test]
+    async fn keep_alives_are_yielded_between_tweets_and_errors_skipped() {
+        let body = format!(
+            "{}\r\n{}",
+            r#"{"errors":[{"title":"operational-disconnect"}]}"#,
+            harness::tweet_stream(&[("1", "gm", "2", "Polkadot")])
+        );
+        let server = MockServer::new()
+            .respond(
+                Method::GET,
+                "/2/tweets/search/stream",
+                StatusCode::OK,
+                &body,
+            )
+            .start();
+        let config = TwitterConfig {
+            api_base: server.base.clone(),
+            ..Default::default()
+        };
+
+        let items: Vec<Option<u64>> = V2Stream::new(&config, reqwest::Client::new())
+            .connect()
+            .await
+            .unwrap()
+            .map_ok(|item| match item {
+                StreamItem::Tweet(tweet) => Some(tweet.id),
+                StreamItem::KeepAlive => None,
+            })
+            .try_collect()
+            .await
+            .unwrap();
+
+        assert_eq!(items, vec![None, Some(1), None]);
+    }
+
+    #[tokio::test]
+    async fn tweets_are_read_with_their_authors_from_each_line() {
+        let server = MockServer::new()
+            .respond(
+                Method::GET,
+                "/2/tweets/search/stream",
+                StatusCode::OK,
+                &harness::tweet_stream(&[
+                    ("10", "gm", "2", "Polkadot"),
+                    ("11", "gn", "3", "kusamanetwork"),
+                ]),
+            )
+            .start();
+        let config = TwitterConfig {
+            api_base: server.base.clone(),
+            ..Default::default()
+        };
+
+        let tweets: Vec<Tweet> = V2Stream::new(&config, reqwest::Client::new())
+            .connect()
+            .await
+            .unwrap()
+            .try_filter_map(|item| {
+                future::ready(Ok(match item {
+                    StreamItem::Tweet(tweet) => Some(tweet),
+                    StreamItem::KeepAlive => None,
+                }))
+            })
+            .try_collect()
+            .await
+            .unwrap();
+
+        assert_eq!(tweets.len(), 2);
+        assert_eq!(tweets[0].id, 10);
+        assert_eq!(tweets[0].user_id, 2);
+        assert_eq!(tweets[0].screen_name, "Polkadot");
+        assert_eq!(tweets[1].text, "gn");
+        assert_eq!(tweets[1].screen_name, "kusamanetwork");
+        assert_eq!(
+            tweets[1].profile_image_url,
+            "https://pbs.twimg.com/kusamanetwork.jpg"
+        );
+        assert_eq!(
+            tweets[1].url(),
+            "https://twitter.com/kusamanetwork/status/11"
+        );
+    }
+
+    #[tokio::test]
+    async fn malformed_lines_end_the_stream_with_an_error() {
+        let server = MockServer::new()
+            .respond(
+                Method::GET,
+                "/2/tweets/search/stream",
+                StatusCode::OK,
+                "{\"data\": \r\n",
+            )
+            .start();
+        let config = TwitterConfig {
+            api_base: server.base.clone(),
+            ..Default::default()
+        };
+
+        let result: Result<Vec<StreamItem>, _> = V2Stream::new(&config, reqwest::Client::new())
+            .connect()
+            .await
+            .unwrap()
+            .try_collect()
+            .await;
+
+        assert!(result.is_err());
+    }
+
+    #[tokio::test]
+    async fn only_the_bots_own_stale_rules_are_deleted() {
+        let rules = serde_json::json!({
+            "data": [
+                { "id": "1", "value": "from:oldhandle", "tag": RULE_TAG },
+                { "id": "2", "value": "from:someoneelse" },
+                { "id": "3", "value": "from:Polkadot", "tag": RULE_TAG }
+            ]
+        });
+        let users = serde_json::json!({
+            "data": [
+                { "id": "2", "name": "Polkadot", "username": "Polkadot" },
+                { "id": "3", "name": "Kusama", "username": "kusamanetwork" }
+            ]
+        });
+        let server = MockServer::new()
+            .respond(
+                Method::GET,
+                "/2/tweets/search/stream/rules",
+                StatusCode::OK,
+                &rules.to_string(),
+            )
+            .respond(
+                Method::POST,
+                "/2/tweets/search/stream/rules",
+                StatusCode::OK,
+                "{}",
+            )
+            .respond(
+                Method::GET,
+                "/2/users/by",
+                StatusCode::OK,
+                &users.to_string(),
+            )
+            .start();
+        let config = TwitterConfig {
+            api_base: server.base.clone(),
+            ..Default::default()
+        };
+
+        let ids = V2Stream::new(&config, reqwest::Client::new())
+            .sync_subscriptions(
+                &[String::from("@Polkadot"), String::from("kusamanetwork")],
+                &[],
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(ids, vec![2, 3]);
+        let updates: Vec<serde_json::Value> = server
+            .requests_to("/2/tweets/search/stream/rules")
+            .iter()
+            .filter(|r| !r.body.is_empty())
+            .map(|r| r.json())
+            .collect();
+        assert_eq!(
+            updates,
+            vec![
+                serde_json::json!({ "delete": { "ids": ["1"] } }),
+                serde_json::json!({
+                    "add": [{ "value": "from:kusamanetwork", "tag": RULE_TAG }]
+                }),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn timelines_are_paged_back_to_since_id() {
+        let user = serde_json::json!([{ "id": "2", "name": "Polkadot", "username": "Polkadot" }]);
+        let first = serde_json::json!({
+            "data": [
+                { "id": "14", "text": "newest", "author_id": "2" },
+                { "id": "13", "text": "newer", "author_id": "2" }
+            ],
+            "includes": { "users": user },
+            "meta": { "next_token": "page2" }
+        });
+        let second = serde_json::json!({
+            "data": [{ "id": "12", "text": "oldest", "author_id": "2" }],
+            "includes": { "users": user },
+            "meta": {}
+        });
+        let server = MockServer::new()
+            .respond(
+                Method::GET,
+                "/2/users/2/tweets",
+                StatusCode::OK,
+                &first.to_string(),
+            )
+            .respond(
+                Method::GET,
+                "/2/users/2/tweets",
+                StatusCode::OK,
+                &second.to_string(),
+            )
+            .start();
+        let config = TwitterConfig {
+            api_base: server.base.clone(),
+            ..Default::default()
+        };
+
+        let tweets = V2Stream::new(&config, reqwest::Client::new())
+            .timeline_since(2, 11)
+            .await
+            .unwrap();
+
+        let ids: Vec<u64> = tweets.iter().map(|t| t.id).collect();
+        assert_eq!(ids, vec![14, 13, 12]);
+        assert_eq!(tweets[0].screen_name, "Polkadot");
+        let requests = server.requests_to("/2/users/2/tweets");
+        assert_eq!(requests.len(), 2);
+        assert!(requests[0].query.contains("since_id=11"));
+        assert!(!requests[0].query.contains("pagination_token"));
+        assert!(requests[1].query.contains("pagination_token=page2"));
+    }
+}
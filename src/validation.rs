@@ -0,0 +1,318 @@
+use std::str::FromStr;
+
+use chrono_tz::Tz;
+
+#[cfg(feature = "twitter")]
+use crate::twitter::{TwitterApi, TwitterConfig};
+use crate::Config;
+#[cfg(feature = "coingecko")]
+use crate::{
+    gecko::{CoingeckoConfig, Rule},
+    scheduler,
+};
+
+/// Checks the config for values that parse but can't work, listing every problem at once
+pub fn validate(config: &Config) -> Result<(), anyhow::Error> {
+    report(problems(config))
+}
+
+/// Checks a change to the config, only failing on problems the change brings in, so that one
+/// elsewhere in the file, or a secret that's only filled in when the bot starts, doesn't block it.
+/// Problems name what they're about rather than where it is in a list, so removing an entry
+/// doesn't make the problems of those after it look new.
+pub fn validate_change(before: &Config, after: &Config) -> Result<(), anyhow::Error> {
+    let mut existing = problems(before);
+    report(
+        problems(after)
+            .into_iter()
+            .filter(|problem| match existing.iter().position(|e| e == problem) {
+                // Each existing problem only excuses one, so a second copy of it is still new
+                Some(i) => {
+                    existing.remove(i);
+                    false
+                }
+                None => true,
+            })
+            .collect(),
+    )
+}
+
+fn problems(config: &Config) -> Vec<String> {
+    let mut problems = vec![];
+
+    #[cfg(feature = "discord")]
+    if let Some(discord) = &config.discord {
+        if discord.token.is_empty() {
+            problems.push(String::from(
+                "discord.token is empty, copy the bot token from the discord developer portal",
+            ));
+        }
+        if discord.channel_id == 0 {
+            problems.push(String::from(
+                "discord.channel_id is 0, set it to the id of the channel to post to",
+            ));
+        }
+    }
+
+    #[cfg(feature = "twitter")]
+    if let Some(twitter) = &config.twitter {
+        validate_twitter(twitter, &mut problems);
+    }
+    #[cfg(feature = "coingecko")]
+    if let Some(coingecko) = &config.coingecko {
+        validate_coingecko(coingecko, &mut problems);
+    }
+    if Tz::from_str(&config.scheduler.timezone).is_err() {
+        problems.push(format!(
+            "scheduler.timezone {} isn't a timezone, use a name like Europe/London",
+            config.scheduler.timezone
+        ));
+    }
+    if config.rss.sleep_time_secs == 0 {
+        problems.push(String::from("rss.sleep_time_secs must be above 0"));
+    }
+    if config.reddit.sleep_time_secs == 0 {
+        problems.push(String::from("reddit.sleep_time_secs must be above 0"));
+    }
+    for hook in &config.webhooks.hooks {
+        if reqwest::Url::parse(&hook.url).is_err() {
+            problems.push(format!("webhooks.hooks {} isn't a valid url", hook.url));
+        }
+        if let Err(e) = hook.check_template() {
+            problems.push(format!(
+                "webhooks.hooks {} has a bad template, {}",
+                hook.url, e
+            ));
+        }
+    }
+    if config.telegram.is_enabled() && config.telegram.chat_ids.is_empty() {
+        problems.push(String::from(
+            "telegram.chat_ids is empty, add the chats to post to or remove the token",
+        ));
+    }
+
+    if let Err(e) = config.logging.filter() {
+        problems.push(format!(
+            "logging has an invalid level {}, use one of trace, debug, info, warn or error",
+            e
+        ));
+    }
+
+    problems
+}
+
+fn report(problems: Vec<String>) -> Result<(), anyhow::Error> {
+    if problems.is_empty() {
+        Ok(())
+    } else {
+        Err(anyhow::anyhow!(
+            "The config has {} problem(s):\n{}",
+            problems.len(),
+            problems.join("\n")
+        ))
+    }
+}
+
+#[cfg(feature = "twitter")]
+fn validate_twitter(twitter: &TwitterConfig, problems: &mut Vec<String>) {
+    if twitter.subscriptions.is_empty() && twitter.keywords.is_empty() {
+        return;
+    }
+    match twitter.api {
+        TwitterApi::V1 => {
+            let keys = [
+                ("consumer_key", &twitter.consumer_key),
+                ("consumer_secret", &twitter.consumer_secret),
+                ("user_access_key", &twitter.user_access_key),
+                ("user_access_secret", &twitter.user_access_secret),
+            ];
+            for (name, key) in keys.iter() {
+                if key.is_empty() {
+                    problems.push(format!(
+                        "twitter.{} is empty, the V1 api needs it to follow subscriptions",
+                        name
+                    ));
+                }
+            }
+        }
+        TwitterApi::V2 => {
+            if twitter.bearer_token.is_empty() {
+                problems.push(String::from(
+                    "twitter.bearer_token is empty, the V2 api needs it to follow subscriptions",
+                ));
+            }
+        }
+    }
+}
+
+#[cfg(feature = "coingecko")]
+fn validate_coingecko(coingecko: &CoingeckoConfig, problems: &mut Vec<String>) {
+    if coingecko.sleep_time_secs == 0 {
+        problems.push(String::from("coingecko.sleep_time_secs must be above 0"));
+    }
+    for rule in &coingecko.rules {
+        let problem = match rule {
+            Rule::PositivePercent(v) if *v <= 0.0 => {
+                Some("a PositivePercent must be above 0, use NegativePercent for falls")
+            }
+            Rule::NegativePercent(v) if *v >= 0.0 => {
+                Some("a NegativePercent must be below 0, use PositivePercent for rises")
+            }
+            Rule::PositiveRank(v) if *v <= 0 => {
+                Some("a PositiveRank must be above 0, use NegativeRank for falls")
+            }
+            Rule::NegativeRank(v) if *v >= 0 => {
+                Some("a NegativeRank must be below 0, use PositiveRank for rises")
+            }
+            _ => None,
+        };
+        if let Some(problem) = problem {
+            problems.push(format!("coingecko.rules {} is invalid, {}", rule, problem));
+        }
+    }
+    for digest in &coingecko.digests {
+        if let Err(e) = scheduler::parse_schedule(&digest.schedule) {
+            problems.push(format!(
+                "coingecko.digests {} has an invalid schedule {}",
+                digest.name, e
+            ));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn config(value: serde_json::Value) -> Config {
+        serde_json::from_value(value).unwrap()
+    }
+
+    #[test]
+    fn empty_config_is_valid() {
+        assert!(validate(&config(json!({}))).is_ok());
+    }
+
+    #[cfg(all(feature = "twitter", feature = "coingecko"))]
+    #[test]
+    fn every_problem_is_listed() {
+        let config = config(json!({
+            "twitter": { "api": "V2", "subscriptions": ["Polkadot"] },
+            "coingecko": {
+                "sleep_time_secs": 0,
+                "rules": [{ "PositivePercent": -5.0 }, { "NegativeRank": 10 }],
+                "digests": [{ "name": "daily", "schedule": "whenever" }]
+            },
+            "scheduler": { "timezone": "Mars/Olympus" },
+            "rss": { "sleep_time_secs": 0 },
+            "reddit": { "sleep_time_secs": 0 },
+            "webhooks": { "hooks": [{ "url": "not a url" }] },
+            "logging": { "modules": { "honorable_bot::gecko": "loud" } }
+        }));
+
+        let problems = problems(&config);
+
+        assert_eq!(problems.len(), 10, "{:#?}", problems);
+        assert!(problems[0].starts_with("twitter.bearer_token is empty"));
+        assert_eq!(problems[1], "coingecko.sleep_time_secs must be above 0");
+        assert!(problems[2].starts_with("coingecko.rules positive_percent -5 is invalid"));
+        assert!(problems[3].starts_with("coingecko.rules negative_rank 10 is invalid"));
+        assert!(problems[4].starts_with("coingecko.digests daily"));
+        assert!(problems[5].starts_with("scheduler.timezone Mars/Olympus"));
+        assert_eq!(problems[6], "rss.sleep_time_secs must be above 0");
+        assert_eq!(problems[7], "reddit.sleep_time_secs must be above 0");
+        assert!(problems[8].starts_with("webhooks.hooks not a url"));
+        assert!(problems[9].starts_with("logging has an invalid level"));
+    }
+
+    #[cfg(feature = "twitter")]
+    #[test]
+    fn twitter_credentials_are_only_needed_to_follow_something() {
+        assert!(validate(&config(json!({ "twitter": {} }))).is_ok());
+        let problems = problems(&config(json!({ "twitter": { "keywords": ["dot"] } })));
+        assert_eq!(problems.len(), 4);
+        assert!(problems[0].starts_with("twitter.consumer_key is empty"));
+    }
+
+    #[test]
+    fn polling_intervals_must_be_above_zero() {
+        let config = config(json!({
+            "rss": { "sleep_time_secs": 0 },
+            "reddit": { "sleep_time_secs": 0 }
+        }));
+        assert_eq!(
+            problems(&config),
+            vec![
+                "rss.sleep_time_secs must be above 0",
+                "reddit.sleep_time_secs must be above 0"
+            ]
+        );
+        assert!(validate(&config(json!({ "rss": { "sleep_time_secs": 1 } }))).is_ok());
+    }
+
+    #[test]
+    fn telegram_needs_chats_when_enabled() {
+        let config = config(json!({ "telegram": { "token": "123:abc" } }));
+        assert_eq!(
+            problems(&config),
+            vec!["telegram.chat_ids is empty, add the chats to post to or remove the token"]
+        );
+    }
+
+    #[cfg(all(feature = "twitter", feature = "coingecko"))]
+    #[test]
+    fn changes_only_fail_on_problems_they_bring_in() {
+        // Unresolved credentials are a problem before and after, so don't block the change
+        let before = config(json!({
+            "twitter": { "subscriptions": ["Polkadot"] },
+            "coingecko": { "rules": [] }
+        }));
+        let mut after = before.clone();
+        after
+            .twitter
+            .as_mut()
+            .unwrap()
+            .subscriptions
+            .push(String::from("kusamanetwork"));
+        assert!(validate_change(&before, &after).is_ok());
+
+        let mut after = before.clone();
+        after
+            .coingecko
+            .as_mut()
+            .unwrap()
+            .rules
+            .push(Rule::PositivePercent(-1.0));
+        let error = validate_change(&before, &after).unwrap_err().to_string();
+        assert!(error.starts_with("The config has 1 problem(s):"));
+        assert!(error.contains("coingecko.rules positive_percent -1 is invalid"));
+    }
+
+    #[cfg(feature = "coingecko")]
+    #[test]
+    fn moving_an_invalid_rule_is_not_a_new_problem() {
+        let before = config(json!({
+            "coingecko": {
+                "rules": [{ "PositivePercent": 5.0 }, { "NegativeRank": 10 }]
+            }
+        }));
+
+        // The invalid rule moves up a place when the one before it is removed
+        let mut after = before.clone();
+        after.coingecko.as_mut().unwrap().rules.remove(0);
+        assert!(validate_change(&before, &after).is_ok());
+
+        // Adding the same invalid rule again is still new
+        let mut after = before.clone();
+        after
+            .coingecko
+            .as_mut()
+            .unwrap()
+            .rules
+            .push(Rule::NegativeRank(10));
+        let error = validate_change(&before, &after).unwrap_err().to_string();
+        assert!(error.starts_with("The config has 1 problem(s):"));
+    }
+}
@@ -0,0 +1,362 @@
+use std::sync::Arc;
+
+use anyhow::Context;
+use async_trait::async_trait;
+use hmac::{Hmac, Mac, NewMac};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use tokio::sync::mpsc::{self, Sender};
+use tracing::Instrument;
+
+use crate::{
+    command::{Command, Worker},
+    events::EventBus,
+    lifecycle::{ManagerHandle, Shutdown},
+    secret::Secret,
+    sink::{Notification, Sink},
+    Config,
+};
+
+const SIGNATURE_HEADER: &str = "X-Hub-Signature-256";
+/// How many notifications can wait for a hook that is retrying before new ones are dropped
+const QUEUE_SIZE: usize = 64;
+
+#[derive(Deserialize, Serialize, Debug, Clone, Default)]
+pub struct WebhookConfig {
+    #[serde(default)]
+    pub hooks: Vec<Webhook>,
+}
+
+impl WebhookConfig {
+    /// Takes each hook's secret from `HONORABLE_WEBHOOK_<index>_SECRET` or its secret file when
+    /// given, counting the hooks from 0
+    pub fn resolve_secrets(&mut self) -> Result<(), anyhow::Error> {
+        for (i, hook) in self.hooks.iter_mut().enumerate() {
+            hook.secret.resolve(
+                &format!("HONORABLE_WEBHOOK_{}_SECRET", i),
+                &hook.secret_file,
+            )?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct Webhook {
+    pub url: String,
+    #[serde(default)]
+    pub kind: WebhookKind,
+    /// When set the body is signed with HMAC-SHA256 and the hex digest sent in the `X-Hub-Signature-256` header
+    #[serde(default, skip_serializing)]
+    pub secret: Secret,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub secret_file: Option<String>,
+    /// A JSON body to send instead of the default for the kind, where `{{kind}}`, `{{title}}`,
+    /// `{{description}}`, `{{url}}` and `{{image}}` are replaced with the notification's values
+    #[serde(default)]
+    pub template: Option<String>,
+    #[serde(default = "default_retries")]
+    pub retries: u32,
+}
+
+fn default_retries() -> u32 {
+    3
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, Copy)]
+pub enum WebhookKind {
+    Json,
+    Slack,
+    Matrix,
+}
+
+impl Default for WebhookKind {
+    fn default() -> Self {
+        WebhookKind::Json
+    }
+}
+
+impl Webhook {
+    fn body(&self, notification: &Notification) -> Result<String, anyhow::Error> {
+        if let Some(template) = &self.template {
+            let body = render_template(template, notification);
+            serde_json::from_str::<serde_json::Value>(&body)
+                .context("The template doesn't render to JSON")?;
+            return Ok(body);
+        }
+        let body = match self.kind {
+            WebhookKind::Json => serde_json::to_value(notification)?,
+            WebhookKind::Slack => serde_json::json!({
+                "text": match &notification.url {
+                    Some(url) => format!("*<{}|{}>*\n{}", url, notification.title, notification.description),
+                    None => format!("*{}*\n{}", notification.title, notification.description),
+                }
+            }),
+            WebhookKind::Matrix => serde_json::json!({
+                "msgtype": "m.text",
+                "body": notification.text(),
+                "format": "org.matrix.custom.html",
+                "formatted_body": format!(
+                    "<strong>{}</strong><br>{}{}",
+                    notification.title,
+                    notification.description,
+                    notification
+                        .url
+                        .as_ref()
+                        .map(|url| format!("<br><a href=\"{0}\">{0}</a>", url))
+                        .unwrap_or_default()
+                ),
+            }),
+        };
+        Ok(serde_json::to_string(&body)?)
+    }
+
+    /// Renders the template for a notification full of characters needing escaping, failing if
+    /// the result isn't JSON
+    pub fn check_template(&self) -> Result<(), anyhow::Error> {
+        let notification = Notification {
+            kind: "tweet",
+            title: String::from("A \"quoted\" title"),
+            description: String::from("Over\ntwo lines with a \\"),
+            url: Some(String::from("https://twitter.com/Polkadot/status/1")),
+            image: None,
+        };
+        self.body(&notification).map(|_| ())
+    }
+
+    fn sign(&self, body: &str) -> Option<String> {
+        if self.secret.is_empty() {
+            return None;
+        }
+        let mut mac = Hmac::<Sha256>::new_from_slice(self.secret.expose().as_bytes())
+            .expect("HMAC accepts keys of any length");
+        mac.update(body.as_bytes());
+        Some(format!(
+            "sha256={}",
+            hex::encode(mac.finalize().into_bytes())
+        ))
+    }
+
+    async fn post(&self, client: &reqwest::Client, body: &str) -> Result<(), anyhow::Error> {
+        let mut req = client
+            .post(&self.url)
+            .header(reqwest::header::CONTENT_TYPE, "application/json")
+            .body(body.to_string());
+        if let Some(signature) = self.sign(body) {
+            req = req.header(SIGNATURE_HEADER, signature);
+        }
+        req.send().await?.error_for_status()?;
+        Ok(())
+    }
+}
+
+/// Replaces the placeholders in the template with the JSON escaped notification values,
+/// so they can sit inside string literals in the template
+fn render_template(template: &str, notification: &Notification) -> String {
+    let escape = |value: &str| {
+        let quoted = serde_json::to_string(value).unwrap_or_default();
+        quoted[1..quoted.len() - 1].to_string()
+    };
+    template
+        .replace("{{kind}}", &escape(notification.kind))
+        .replace("{{title}}", &escape(&notification.title))
+        .replace("{{description}}", &escape(&notification.description))
+        .replace(
+            "{{url}}",
+            &escape(notification.url.as_deref().unwrap_or_default()),
+        )
+        .replace(
+            "{{image}}",
+            &escape(notification.image.as_deref().unwrap_or_default()),
+        )
+}
+
+#[async_trait]
+impl Sink for Webhook {
+    async fn deliver(
+        &self,
+        client: &reqwest::Client,
+        notification: &Notification,
+    ) -> Result<(), anyhow::Error> {
+        let body = self.body(notification)?;
+        let mut attempt = 0;
+        loop {
+            match self.post(client, &body).await {
+                Ok(()) => return Ok(()),
+                Err(e) if attempt < self.retries => {
+                    let delay = tokio::time::Duration::from_secs(2_u64.pow(attempt));
+                    tracing::warn!(
+                        "Webhook delivery to {} failed, retrying in {:?} {}",
+                        self.url,
+                        delay,
+                        e
+                    );
+                    tokio::time::sleep(delay).await;
+                    attempt += 1;
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+}
+
+/// Delivers a hook's notifications in order on a task of its own, so one hook retrying doesn't
+/// hold up the others
+fn spawn_delivery(hook: Webhook, client: reqwest::Client) -> Sender<(Notification, tracing::Span)> {
+    let (tx, mut rx) = mpsc::channel::<(Notification, tracing::Span)>(QUEUE_SIZE);
+    tokio::spawn(async move {
+        while let Some((notification, span)) = rx.recv().await {
+            async {
+                match hook.deliver(&client, &notification).await {
+                    Ok(()) => tracing::debug!("Delivered to webhook {}", hook.url),
+                    Err(e) => tracing::error!("Failed to deliver to webhook {} {}", hook.url, e),
+                }
+            }
+            .instrument(span)
+            .await;
+        }
+    });
+    tx
+}
+
+impl Worker for WebhookConfig {
+    fn start_worker(
+        &self,
+        config: Arc<Config>,
+        _tx: Sender<Command>,
+        events: EventBus,
+        mut shutdown: Shutdown,
+    ) -> ManagerHandle {
+        tracing::info!("Starting webhook manager");
+        let mut events = events.subscribe("webhooks");
+        tokio::spawn(async move {
+            let client = reqwest::Client::new();
+            let queues: Vec<_> = config
+                .webhooks
+                .hooks
+                .iter()
+                .map(|hook| {
+                    (
+                        hook.url.clone(),
+                        spawn_delivery(hook.clone(), client.clone()),
+                    )
+                })
+                .collect();
+            loop {
+                let event = tokio::select! {
+                    event = events.recv() => match event {
+                        Some(event) => event,
+                        None => return Ok(()),
+                    },
+                    _ = shutdown.wait() => return Ok(()),
+                };
+                let notification = match Notification::from_event(&event) {
+                    Some(notification) => notification,
+                    None => continue,
+                };
+                for (url, queue) in &queues {
+                    if queue
+                        .try_send((notification.clone(), event.span()))
+                        .is_err()
+                    {
+                        tracing::error!(
+                            "Webhook {} is too far behind, dropping {}",
+                            url,
+                            event.summary()
+                        );
+                    }
+                }
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use warp::http::{Method, StatusCode};
+
+    use super::*;
+    use crate::harness::MockServer;
+
+    fn hook(url: &str) -> Webhook {
+        Webhook {
+            url: url.to_string(),
+            kind: WebhookKind::Json,
+            secret: Secret::default(),
+            secret_file: None,
+            template: None,
+            retries: 0,
+        }
+    }
+
+    fn notification() -> Notification {
+        Notification {
+            kind: "tweet",
+            title: String::from("Polkadot (@Polkadot)"),
+            description: String::from("Say \"gm\""),
+            url: Some(String::from("https://twitter.com/Polkadot/status/1")),
+            image: None,
+        }
+    }
+
+    #[test]
+    fn templates_are_filled_in_with_escaped_values() {
+        let hook = Webhook {
+            template: Some(String::from(
+                r#"{"content": "{{title}}: {{description}}", "link": "{{url}}"}"#,
+            )),
+            ..hook("https://example.com")
+        };
+
+        let body: serde_json::Value =
+            serde_json::from_str(&hook.body(&notification()).unwrap()).unwrap();
+
+        assert_eq!(body["content"], "Polkadot (@Polkadot): Say \"gm\"");
+        assert_eq!(body["link"], "https://twitter.com/Polkadot/status/1");
+        assert!(hook.check_template().is_ok());
+    }
+
+    #[test]
+    fn templates_that_dont_render_to_json_are_rejected() {
+        let hook = Webhook {
+            template: Some(String::from(r#"{"content": {{title}}}"#)),
+            ..hook("https://example.com")
+        };
+
+        assert!(hook.body(&notification()).is_err());
+        assert!(hook.check_template().is_err());
+    }
+
+    #[test]
+    fn bodies_are_signed_when_there_is_a_secret() {
+        let mut hook = hook("https://example.com");
+        assert_eq!(hook.sign(r#"{"text":"gm"}"#), None);
+
+        hook.secret = serde_json::from_str("\"It's a secret to everybody\"").unwrap();
+        assert_eq!(
+            hook.sign(r#"{"text":"gm"}"#).unwrap(),
+            "sha256=007d69df256d2c3bd04b07f5f614f8088882ea232304ede67114419dff160f9b"
+        );
+    }
+
+    #[tokio::test]
+    async fn failed_deliveries_are_retried() {
+        let server = MockServer::new()
+            .respond(Method::POST, "/hook", StatusCode::BAD_GATEWAY, "")
+            .respond(Method::POST, "/hook", StatusCode::OK, "")
+            .start();
+        let hook = Webhook {
+            retries: 1,
+            ..hook(&format!("{}/hook", server.base))
+        };
+
+        hook.deliver(&reqwest::Client::new(), &notification())
+            .await
+            .unwrap();
+
+        let requests = server.requests_to("/hook");
+        assert_eq!(requests.len(), 2);
+        assert_eq!(requests[1].json()["kind"], "tweet");
+        assert_eq!(requests[1].json()["description"], "Say \"gm\"");
+    }
+}